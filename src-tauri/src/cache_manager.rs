@@ -1,211 +1,1051 @@
 use serde::{Serialize, Deserialize};
 use directories::ProjectDirs;
 use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use super::indexing_service::{LineOffset, InvertedIndex, NGramIndex}; // Added NGramIndex
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::io::ErrorKind;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use memmap2::Mmap;
+use super::file_content_service::FileContent;
+use super::indexing_service::{IndexSettings, LineOffset};
+use super::postings_store::PostingsStore;
+use super::schema_inference::SchemaStats;
+use super::utils::token_utils::TOKENIZER_VERSION;
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
 use std::error::Error; // For Box<dyn Error>
 
-// Helper function to calculate a hash for a given value
-fn calculate_hash<T: std::hash::Hash>(t: &T) -> u64 {
-    let mut s = DefaultHasher::new();
-    t.hash(&mut s);
-    s.finish()
-}
+/// Blocks hashed for a file's fingerprint: the first and last, and evenly spaced ones in
+/// between. Files up to SAMPLE_BLOCKS blocks are hashed whole.
+const SAMPLE_BLOCK_SIZE: usize = 64 * 1024;
+const SAMPLE_BLOCKS: usize = 16;
 
-// Helper function to determine the path for a cache file
-fn get_cache_file_path(original_file_path: &str) -> Result<PathBuf, String> {
-    if let Some(proj_dirs) = ProjectDirs::from("com", "DolphinEdit", "DolphinEdit") {
-        let cache_dir = proj_dirs.cache_dir();
-        if !cache_dir.exists() {
-            fs::create_dir_all(cache_dir).map_err(|e| format!("Failed to create cache directory: {}", e))?;
-        }
-        // Use the hash of the original file path to name the cache file
-        let file_hash = calculate_hash(&original_file_path.to_string());
-        Ok(cache_dir.join(format!("{}.indexcache", file_hash)))
-    } else {
-        Err("Could not determine project cache directory".to_string())
-    }
+/// A cached index and the number of leading bytes of the file it covers. When that is less
+/// than the file's size, the file grew by appending and only the tail needs indexing.
+pub struct CachedIndex<T> {
+    pub index: T,
+    pub indexed_size: u64,
 }
 
+// Identifies the content an index was built from. Freshness is decided by size, mtime and
+// inode; the full hash lets a renamed or copied file, or a touched one, keep its caches once
+// its content is confirmed to be the same. It is only computed for the stamp with full-hash
+// verification on, or if opening the file already needed it.
 #[derive(Serialize, Deserialize)]
-struct LineOffsetCacheWrapper {
+struct SourceStamp {
     original_file_size: u64,
-    original_mod_time_secs: u64,
-    original_mod_time_nanos: u32,
-    index_data: Vec<LineOffset>, // Adjusted to use the imported LineOffset
+    modified: Option<u64>, // mtime in nanoseconds since the epoch, as of mapping the content
+    identity: Option<(u64, u64)>, // Device and inode, see `FileContent::identity`
+    sample_hash: u64, // See `sample_hash`
+    full_hash: Option<u64>, // Of all indexed bytes
 }
 
-pub fn save_line_offset_index(file_path: &str, index_data: &Vec<LineOffset>) -> Result<(), Box<dyn Error>> {
-    let cache_path = get_cache_file_path(file_path)?;
-    let metadata = fs::metadata(file_path)?;
-    let mod_time = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+// Hash of sampled blocks of `data`. The last block is always included, so a file that has
+// been appended to still matches the hash of its indexed prefix.
+fn sample_hash(data: &[u8]) -> u64 {
+    if data.len() <= SAMPLE_BLOCK_SIZE * SAMPLE_BLOCKS {
+        return xxh3_64(data);
+    }
+    let mut hasher = Xxh3::new();
+    let last_start = data.len() - SAMPLE_BLOCK_SIZE;
+    for i in 0..SAMPLE_BLOCKS {
+        let start = last_start / (SAMPLE_BLOCKS - 1) * i;
+        let start = if i == SAMPLE_BLOCKS - 1 { last_start } else { start };
+        hasher.update(&data[start..start + SAMPLE_BLOCK_SIZE]);
+    }
+    hasher.digest()
+}
 
-    let wrapper = LineOffsetCacheWrapper {
-        original_file_size: metadata.len(),
-        original_mod_time_secs: mod_time.as_secs(),
-        original_mod_time_nanos: mod_time.subsec_nanos(),
-        index_data: index_data.clone(),
+// Names the caches of a file's content: its size and sampled hash.
+fn content_fingerprint(data: &[u8]) -> String {
+    format!("{:016x}{:016x}", data.len(), sample_hash(data))
+}
+
+fn modified_nanos(file_content: &FileContent) -> Option<u64> {
+    let since_epoch = file_content.modified()?.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    u64::try_from(since_epoch.as_nanos()).ok()
+}
+
+// Describes the mapped content, which is what the index was built from even if the file
+// has grown since it was mapped.
+fn source_stamp(file_content: &FileContent, full_hash_verification: bool) -> SourceStamp {
+    SourceStamp {
+        original_file_size: file_content.len(),
+        modified: modified_nanos(file_content),
+        identity: file_content.identity(),
+        sample_hash: sample_hash(file_content.bytes()),
+        full_hash: if full_hash_verification { Some(file_content.full_hash()) } else { file_content.known_full_hash() },
+    }
+}
+
+// The indexed bytes must still be the start of the file: all of it if the size is unchanged,
+// or a prefix if the file has been appended to. Anything else is stale. A file of the same
+// size is the indexed one if neither its mtime nor its inode changed; otherwise, as after a
+// copy, a touch or an edit in place that kept the size, every byte must hash the same, which
+// needs a stamp with the full hash. A grown file must start with the indexed bytes, checked
+// on sampled blocks.
+fn stamp_matches(stamp: &SourceStamp, file_content: &FileContent, full_hash_verification: bool) -> bool {
+    let indexed = match file_content.bytes().get(..stamp.original_file_size as usize) {
+        Some(indexed) => indexed,
+        None => return false, // The file shrank
     };
+    if sample_hash(indexed) != stamp.sample_hash {
+        return false;
+    }
+    let hash_matches = || stamp.full_hash.is_some_and(|full_hash| full_hash == file_content.prefix_hash(stamp.original_file_size));
+    if indexed.len() as u64 == file_content.len() {
+        let untouched = stamp.modified.is_some()
+            && stamp.modified == modified_nanos(file_content)
+            && stamp.identity == file_content.identity();
+        return (untouched && !full_hash_verification) || hash_matches();
+    }
+    !full_hash_verification || hash_matches()
+}
+
+fn full_hash_verification() -> bool {
+    cache_dir().is_ok_and(|cache_dir| read_settings(&cache_dir).full_hash_verification)
+}
+
+// --- Path lookup ---
+// Remembers which content fingerprint each kind of cache was last saved under for each path.
+// Opening a file that grew since it was indexed gives a new fingerprint; the lookup finds the
+// caches of its previous content so only the appended tail is indexed. It also tells when a
+// superseded cache is no longer used by any path and can be deleted.
+
+type PathLookup = HashMap<String, HashMap<String, String>>; // Path -> kind -> fingerprint
+
+fn lookup_key(file_path: &str) -> String {
+    fs::canonicalize(file_path)
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| file_path.to_string())
+}
+
+fn read_path_lookup(cache_dir: &Path) -> PathLookup {
+    match fs::read(cache_dir.join(PATH_LOOKUP_FILE)) {
+        Ok(bytes) => bincode::deserialize(&bytes).unwrap_or_else(|e| {
+            eprintln!("Ignoring corrupt cache path lookup: {}", e);
+            PathLookup::new()
+        }),
+        Err(_) => PathLookup::new(),
+    }
+}
+
+fn write_path_lookup(cache_dir: &Path, lookup: &PathLookup, _lock: &CacheDirLock) -> Result<(), Box<dyn Error>> {
+    let bytes = bincode::serialize(lookup)?;
+    write_atomically(&cache_dir.join(PATH_LOOKUP_FILE), |writer| writer.write_all(&bytes))
+}
+
+// The lookup is replaced by rename, so it can be read without the lock.
+fn previous_fingerprint(file_path: &str, kind: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let lookup = read_path_lookup(&cache_dir()?);
+    Ok(lookup.get(&lookup_key(file_path)).and_then(|kinds| kinds.get(kind)).cloned())
+}
+
+// Records that the `kind` cache of `file_path` is now the one for `fingerprint`, and deletes
+// the one it replaces unless another path still uses it.
+fn record_fingerprint(file_path: &str, kind: &str, fingerprint: &str, lock: &CacheDirLock) -> Result<(), Box<dyn Error>> {
+    let cache_dir = cache_dir()?;
+    let mut lookup = read_path_lookup(&cache_dir);
+    let previous = lookup.entry(lookup_key(file_path)).or_default().insert(kind.to_string(), fingerprint.to_string());
+    if previous.as_deref() == Some(fingerprint) {
+        return Ok(());
+    }
+    write_path_lookup(&cache_dir, &lookup, lock)?;
 
-    let file = File::create(cache_path)?;
-    let writer = BufWriter::new(file);
-    bincode::serialize_into(writer, &wrapper)?;
+    if let Some(previous) = previous.filter(|previous| previous != fingerprint) {
+        let still_used = lookup.values().any(|kinds| kinds.get(kind) == Some(&previous));
+        if !still_used {
+            remove_cache_file(&cache_dir.join(cache_file_name(&previous, kind)));
+        }
+    }
     Ok(())
 }
 
-pub fn load_line_offset_index(file_path: &str) -> Result<Option<Vec<LineOffset>>, Box<dyn Error>> {
-    let current_metadata = fs::metadata(file_path)?;
-    let current_mod_time = current_metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
-    
-    let cache_path = get_cache_file_path(file_path)?;
+// --- Index settings per file ---
+// The settings a file was last opened with, so it is indexed the same way, and its caches
+// are found, when it is opened again without any.
+
+const INDEX_SETTINGS_FILE: &str = "index_settings.json";
+
+fn read_index_settings(cache_dir: &Path) -> HashMap<String, IndexSettings> {
+    fs::read(cache_dir.join(INDEX_SETTINGS_FILE)).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or_default()
+}
+
+/// The settings `file_path` was last opened with, if any.
+pub fn saved_index_settings(file_path: &str) -> Option<IndexSettings> {
+    let cache_dir = cache_dir().ok()?;
+    read_index_settings(&cache_dir).remove(&lookup_key(file_path))
+}
 
-    if !cache_path.exists() {
-        return Ok(None);
+pub fn save_index_settings(file_path: &str, settings: &IndexSettings) -> Result<(), String> {
+    let cache_dir = cache_dir()?;
+    let _lock = lock_cache_dir()?;
+    let mut all_settings = read_index_settings(&cache_dir);
+    if all_settings.get(&lookup_key(file_path)) == Some(settings) {
+        return Ok(());
     }
+    all_settings.insert(lookup_key(file_path), settings.clone());
+    let bytes = serde_json::to_vec(&all_settings).map_err(|e| format!("Failed to serialize index settings: {}", e))?;
+    write_atomically(&cache_dir.join(INDEX_SETTINGS_FILE), |writer| writer.write_all(&bytes))
+        .map_err(|e| format!("Failed to save index settings: {}", e))
+}
 
-    let file = File::open(cache_path)?;
-    let reader = BufReader::new(file);
-    let wrapper: LineOffsetCacheWrapper = bincode::deserialize_from(reader)?;
+// --- Locking and atomic writes ---
+// Cache files and the lookup are never written in place: each is written to a temp file of
+// its own, synced, and renamed over the old one, so a crash leaves either version but never
+// a mix. Writers, in this process or another instance of the app, take the directory lock
+// so their lookup updates and evictions do not interleave. Readers need no lock.
 
-    if wrapper.original_file_size == current_metadata.len() &&
-       wrapper.original_mod_time_secs == current_mod_time.as_secs() &&
-       wrapper.original_mod_time_nanos == current_mod_time.subsec_nanos() {
-        Ok(Some(wrapper.index_data))
-    } else {
-        Ok(None) // Cache is stale
+const LOCK_FILE: &str = "cache.lock";
+// Temp files older than this are left over from a crash and deleted by eviction.
+const STALE_TEMP_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+// File locks are per open file, so threads of this process would also exclude each other;
+// the mutex just saves them from queueing in the kernel.
+static CACHE_DIR_MUTEX: Mutex<()> = Mutex::new(());
+
+/// Exclusive hold on the cache directory for as long as it lives.
+struct CacheDirLock {
+    _file: File,
+    _guard: MutexGuard<'static, ()>,
+}
+
+fn lock_cache_dir() -> Result<CacheDirLock, String> {
+    let guard = CACHE_DIR_MUTEX.lock().map_err(|e| format!("Failed to lock cache directory: {}", e))?;
+    let lock_path = cache_dir()?.join(LOCK_FILE);
+    let file = File::options().create(true).truncate(false).write(true).open(&lock_path)
+        .map_err(|e| format!("Failed to open {}: {}", lock_path.display(), e))?;
+    file.lock().map_err(|e| format!("Failed to lock {}: {}", lock_path.display(), e))?;
+    Ok(CacheDirLock { _file: file, _guard: guard })
+}
+
+// Writes `path` through a uniquely named temp file next to it, so concurrent writers never
+// share one, and makes the rename durable before returning.
+fn write_atomically(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) -> Result<(), Box<dyn Error>> {
+    let mut temp_name = path.file_name().ok_or("Cache path has no file name")?.to_os_string();
+    temp_name.push(format!(".{}-{}.tmp", std::process::id(), NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)));
+    let temp_path = path.with_file_name(temp_name);
+
+    let written = (|| -> Result<(), Box<dyn Error>> {
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        write(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    })();
+    if written.is_err() {
+        remove_cache_file(&temp_path);
+    }
+    written?;
+    sync_parent_dir(path)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(dir) => File::open(dir)?.sync_all(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(()) // Directories cannot be opened for syncing; NTFS journals the rename
+}
+
+// --- Cache container ---
+// Every cache file has the same layout: CACHE_MAGIC, the container version (u32 LE), the
+// length of the header (u32 LE), a bincode `CacheHeader`, and the payload it describes.
+// A file is only used if every field of the header matches what this build would write
+// for the file as it is now and the checksum of the payload's head holds; anything else is
+// a miss and the index is rebuilt. The head is what loading reads right away: all of a
+// payload that is deserialized, but only the section table and keys of a postings store,
+// so a mapped store is not paged in whole just to be opened. Its posting lists are checked
+// for bounds and counts as they are decoded.
+
+const CACHE_MAGIC: &[u8; 8] = b"DLPHCACH";
+// Bump whenever the container or the layout of a payload changes.
+const CACHE_FORMAT_VERSION: u32 = 7;
+const PREAMBLE_LEN: usize = 16; // Magic, version and header length
+
+const PATH_LOOKUP_FILE: &str = "paths.lookup";
+
+// Settings that shape an index; a cache built with others describes different terms.
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct BuildParams {
+    tokenizer_version: u32,
+    ngram_size: u32,             // N-gram caches only, 0 for others
+    case_fold_ngrams: bool,      // N-gram caches only
+    fields: Option<Vec<String>>, // Inverted index caches only
+}
+
+// The parameters a `kind` index built with `settings` depends on.
+fn build_params(kind: &str, settings: &IndexSettings) -> BuildParams {
+    let mut params = BuildParams {
+        tokenizer_version: TOKENIZER_VERSION,
+        ngram_size: 0,
+        case_fold_ngrams: false,
+        fields: None,
+    };
+    match kind {
+        "ngram" | "ngram_over_limit" => {
+            params.ngram_size = settings.ngram_size as u32;
+            params.case_fold_ngrams = settings.case_fold_ngrams;
+        }
+        "inverted" | "inverted_over_limit" => params.fields = settings.fields.clone(),
+        _ => {}
     }
+    params
 }
 
-// --- NGram Index Cache ---
+// Names the caches of a `kind` index built with `params` in file names and the path lookup.
+// Indexes built with non-default settings get a hash of them appended, so switching settings
+// neither overwrites nor invalidates the caches built with the others.
+fn cache_key(kind: &str, params: &BuildParams) -> String {
+    if *params == build_params(kind, &IndexSettings::default()) {
+        return kind.to_string();
+    }
+    let params_hash = bincode::serialize(params).map_or(0, |bytes| xxh3_64(&bytes));
+    format!("{}-{:08x}", kind, params_hash as u32)
+}
 
 #[derive(Serialize, Deserialize)]
-pub struct NGramIndexCacheWrapper {
-    pub original_file_size: u64,
-    pub original_mod_time_secs: u64,
-    pub original_mod_time_nanos: u32,
-    // pub ngram_size: usize, // If NGRAM_SIZE becomes dynamic, cache it too
-    pub index_data: NGramIndex,
+struct CacheHeader {
+    kind: String, // "line_offsets", "schema", or a postings kind such as "ngram" or "ngram_over_limit", without the settings hash
+    params: BuildParams,
+    source: SourceStamp,
+    payload_len: u64,
+    head_len: u64,      // Leading bytes of the payload covered by the checksum
+    head_checksum: u64, // xxh3 of them
+}
+
+// A cache file that passed all checks, with the source stamp it was built for.
+struct CacheFile {
+    mmap: Mmap,
+    payload_start: usize,
+    source: SourceStamp,
 }
 
-fn get_ngram_index_cache_file_path(original_file_path: &str) -> Result<PathBuf, String> {
+fn cache_dir() -> Result<PathBuf, String> {
     if let Some(proj_dirs) = ProjectDirs::from("com", "DolphinEdit", "DolphinEdit") {
         let cache_dir = proj_dirs.cache_dir();
         if !cache_dir.exists() {
-            std::fs::create_dir_all(cache_dir).map_err(|e| format!("Failed to create cache directory: {}", e))?;
+            fs::create_dir_all(cache_dir).map_err(|e| format!("Failed to create cache directory: {}", e))?;
         }
-        let file_hash = calculate_hash(&original_file_path.to_string());
-        Ok(cache_dir.join(format!("{}.ngram_index.cache", file_hash)))
+        Ok(cache_dir.to_path_buf())
     } else {
         Err("Could not determine project cache directory".to_string())
     }
 }
 
-pub fn save_ngram_index(file_path: &str, index_data: &NGramIndex) -> Result<(), Box<dyn Error>> {
-    let cache_path = get_ngram_index_cache_file_path(file_path)?;
-    let metadata = std::fs::metadata(file_path)?;
-    let mod_time = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+fn cache_file_name(fingerprint: &str, kind: &str) -> String {
+    format!("{}.{}.cache", fingerprint, kind)
+}
 
-    let wrapper = NGramIndexCacheWrapper {
-        original_file_size: metadata.len(),
-        original_mod_time_secs: mod_time.as_secs(),
-        original_mod_time_nanos: mod_time.subsec_nanos(),
-        // ngram_size: crate::indexing_service::NGRAM_SIZE, // Store NGRAM_SIZE if it might change
-        index_data: index_data.clone(),
-    };
+fn write_cache(file_content: &FileContent, kind: &str, params: BuildParams, payload: &[u8], head_len: usize) -> Result<(), Box<dyn Error>> {
+    let fingerprint = content_fingerprint(file_content.bytes());
+    let key = cache_key(kind, &params);
+    let cache_path = cache_dir()?.join(cache_file_name(&fingerprint, &key));
+    let header = bincode::serialize(&CacheHeader {
+        kind: kind.to_string(),
+        params,
+        source: source_stamp(file_content, full_hash_verification()),
+        payload_len: payload.len() as u64,
+        head_len: head_len as u64,
+        head_checksum: xxh3_64(&payload[..head_len]),
+    })?;
 
-    let file = File::create(cache_path)?;
-    let writer = BufWriter::new(file);
-    bincode::serialize_into(writer, &wrapper)?;
+    // Replacing by rename also keeps a previous version that is still mapped intact.
+    let lock = lock_cache_dir()?;
+    write_atomically(&cache_path, |writer| {
+        writer.write_all(CACHE_MAGIC)?;
+        writer.write_all(&CACHE_FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&(header.len() as u32).to_le_bytes())?;
+        writer.write_all(&header)?;
+        writer.write_all(payload)
+    })?;
+    record_fingerprint(file_content.path(), &key, &fingerprint, &lock)?;
+    evict_caches(Some(&fingerprint), &lock);
     Ok(())
 }
 
-pub fn load_ngram_index(file_path: &str) -> Result<Option<NGramIndex>, Box<dyn Error>> {
-    let cache_path = get_ngram_index_cache_file_path(file_path)?;
-    if !cache_path.exists() {
-        return Ok(None);
+// Finds the `kind` cache of the file's current content, or else of the content it had when
+// last indexed under this path, which is still usable if the file has only grown since.
+fn read_cache(file_content: &FileContent, kind: &str, params: BuildParams) -> Result<Option<CacheFile>, Box<dyn Error>> {
+    let cache_dir = cache_dir()?;
+    let fingerprint = content_fingerprint(file_content.bytes());
+    let key = cache_key(kind, &params);
+    let mut candidates = vec![fingerprint.clone()];
+    if let Some(previous) = previous_fingerprint(file_content.path(), &key)? {
+        if previous != fingerprint {
+            candidates.push(previous);
+        }
     }
 
-    let current_metadata = std::fs::metadata(file_path)?;
-    let current_mod_time = current_metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+    for candidate in candidates {
+        let cache_path = cache_dir.join(cache_file_name(&candidate, &key));
+        if !cache_path.exists() {
+            continue;
+        }
+        let file = File::open(&cache_path)?;
+        // Safety: cache files are only ever replaced by rename, never modified in place.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let (header, payload_start) = match check_container(&mmap, kind, &params) {
+            Ok(checked) => checked,
+            Err(ContainerError::Damaged(reason)) => {
+                // Torn by a crash in an older version, or damaged: of no use to anyone, so
+                // it goes rather than being checked again next time.
+                drop(mmap);
+                drop(file);
+                remove_damaged_cache(&cache_path, kind, &params, &reason)?;
+                continue;
+            }
+            Err(ContainerError::Foreign(reason)) => {
+                // Intact but written by another version or for other settings, which may
+                // still want it; eviction deletes it once it goes unused.
+                eprintln!("Skipping cache {}: {}", cache_path.display(), reason);
+                continue;
+            }
+        };
+        if stamp_matches(&header.source, file_content, full_hash_verification()) {
+            // A cache found by content may have been saved under another path; remember it
+            // under this one too, so it is still found once this file grows.
+            if candidate == fingerprint && previous_fingerprint(file_content.path(), &key)?.as_deref() != Some(&candidate) {
+                record_fingerprint(file_content.path(), &key, &candidate, &lock_cache_dir()?)?;
+            }
+            mark_used(&cache_path);
+            return Ok(Some(CacheFile { mmap, payload_start, source: header.source }));
+        }
+    }
+    Ok(None) // No cache, or only stale ones
+}
 
-    let file = File::open(cache_path)?;
-    let reader = BufReader::new(file);
-    let wrapper: NGramIndexCacheWrapper = bincode::deserialize_from(reader)?;
+// Deletes a cache that failed its checks, unless it has been replaced since: writers rename
+// into place under the cache directory lock, so the file is checked again while holding it.
+fn remove_damaged_cache(cache_path: &Path, kind: &str, params: &BuildParams, reason: &str) -> Result<(), Box<dyn Error>> {
+    let _lock = lock_cache_dir()?;
+    let file = match File::open(cache_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // Safety: as in `read_cache`.
+    let mmap = unsafe { Mmap::map(&file)? };
+    if let Err(ContainerError::Damaged(_)) = check_container(&mmap, kind, params) {
+        eprintln!("Deleting cache {}: {}", cache_path.display(), reason);
+        drop(mmap);
+        drop(file);
+        remove_cache_file(cache_path);
+    }
+    Ok(())
+}
 
-    // Add check for ngram_size if it's stored in the cache wrapper
-    // if wrapper.ngram_size != crate::indexing_service::NGRAM_SIZE { return Ok(None); }
+enum ContainerError {
+    Damaged(String), // Torn, truncated or failing its checksum
+    Foreign(String), // Intact, but of another format version, kind or build parameters
+}
 
-    if wrapper.original_file_size == current_metadata.len() &&
-       wrapper.original_mod_time_secs == current_mod_time.as_secs() &&
-       wrapper.original_mod_time_nanos == current_mod_time.subsec_nanos() {
-        Ok(Some(wrapper.index_data))
-    } else {
-        Ok(None) // Cache is stale
+fn check_container(data: &[u8], kind: &str, params: &BuildParams) -> Result<(CacheHeader, usize), ContainerError> {
+    let damaged = |reason: &str| ContainerError::Damaged(reason.to_string());
+    if data.len() < PREAMBLE_LEN || &data[..8] != CACHE_MAGIC {
+        return Err(damaged("not a cache file"));
     }
+    let version = u32::from_le_bytes(data[8..12].try_into().map_err(|_| damaged("truncated"))?);
+    if version != CACHE_FORMAT_VERSION {
+        return Err(ContainerError::Foreign(format!("format version {} instead of {}", version, CACHE_FORMAT_VERSION)));
+    }
+    let header_len = u32::from_le_bytes(data[12..16].try_into().map_err(|_| damaged("truncated"))?) as usize;
+    let header_bytes = data.get(PREAMBLE_LEN..PREAMBLE_LEN + header_len).ok_or_else(|| damaged("truncated header"))?;
+    let header: CacheHeader = bincode::deserialize(header_bytes)
+        .map_err(|e| ContainerError::Damaged(format!("corrupt header: {}", e)))?;
+    let payload_start = PREAMBLE_LEN + header_len;
+    let payload = &data[payload_start..];
+    if payload.len() as u64 != header.payload_len {
+        return Err(ContainerError::Damaged(format!("payload is {} bytes instead of {}", payload.len(), header.payload_len)));
+    }
+    let head = usize::try_from(header.head_len).ok()
+        .and_then(|head_len| payload.get(..head_len))
+        .ok_or_else(|| damaged("checksummed head is longer than the payload"))?;
+    if xxh3_64(head) != header.head_checksum {
+        return Err(damaged("checksum mismatch"));
+    }
+    if header.kind != kind {
+        return Err(ContainerError::Foreign(format!("holds a {} index, not {}", header.kind, kind)));
+    }
+    if header.params != *params {
+        return Err(ContainerError::Foreign(format!("built with {:?}, not {:?}", header.params, params)));
+    }
+    Ok((header, payload_start))
 }
 
-// --- Inverted Index Cache ---
+// --- Line offset cache ---
+
+pub fn save_line_offset_index(file_content: &FileContent, index_data: &Vec<LineOffset>) -> Result<(), Box<dyn Error>> {
+    let params = build_params("line_offsets", &IndexSettings::default());
+    let payload = bincode::serialize(index_data)?;
+    write_cache(file_content, "line_offsets", params, &payload, payload.len())
+}
+
+pub fn load_line_offset_index(file_content: &FileContent) -> Result<Option<CachedIndex<Vec<LineOffset>>>, Box<dyn Error>> {
+    match read_cache(file_content, "line_offsets", build_params("line_offsets", &IndexSettings::default()))? {
+        Some(cache) => {
+            let index: Vec<LineOffset> = bincode::deserialize(&cache.mmap[cache.payload_start..])?;
+            Ok(Some(CachedIndex { index, indexed_size: cache.source.original_file_size }))
+        }
+        None => Ok(None),
+    }
+}
+
+// --- Schema statistics cache ---
+// Statistics of the complete lines only, so that they can be extended from where the file's
+// unterminated last line began. Stored as JSON, since examples are arbitrary JSON values.
+
+pub fn save_schema_stats(file_content: &FileContent, stats: &SchemaStats) -> Result<(), Box<dyn Error>> {
+    let params = build_params("schema", &IndexSettings::default());
+    let payload = serde_json::to_vec(stats)?;
+    write_cache(file_content, "schema", params, &payload, payload.len())
+}
+
+pub fn load_schema_stats(file_content: &FileContent) -> Result<Option<CachedIndex<SchemaStats>>, Box<dyn Error>> {
+    match read_cache(file_content, "schema", build_params("schema", &IndexSettings::default()))? {
+        Some(cache) => {
+            let stats: SchemaStats = serde_json::from_slice(&cache.mmap[cache.payload_start..])?;
+            Ok(Some(CachedIndex { index: stats, indexed_size: cache.source.original_file_size }))
+        }
+        None => Ok(None),
+    }
+}
+
+// --- Postings caches (n-gram, inverted, positional and field value indexes) ---
+// The payload is the postings store itself. Loading maps the file; posting lists are decoded
+// only when a search asks for them. The container checksums the store's head, and each list
+// is checked against its own checksum when it is decoded. Each is saved and looked up for the
+// settings it was built with.
+
+fn save_postings(file_content: &FileContent, kind: &str, settings: &IndexSettings, store: &PostingsStore) -> Result<(), Box<dyn Error>> {
+    write_cache(file_content, kind, build_params(kind, settings), store.as_bytes(), store.head_len())
+}
+
+fn load_postings(file_content: &FileContent, kind: &str, settings: &IndexSettings) -> Result<Option<CachedIndex<PostingsStore>>, Box<dyn Error>> {
+    match read_cache(file_content, kind, build_params(kind, settings))? {
+        Some(cache) => {
+            let indexed_size = cache.source.original_file_size;
+            let store = PostingsStore::from_mmap(cache.mmap, cache.payload_start)?;
+            Ok(Some(CachedIndex { index: store, indexed_size }))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Deletes the `kind` postings cache of the file's content, or of the content it grew from, if
+/// one of its posting lists fails its checksum, as a lookup in the store loaded from it found.
+/// Checked again under the cache directory lock, as in `remove_damaged_cache`.
+pub fn remove_damaged_postings(file_content: &FileContent, kind: &str, settings: &IndexSettings) -> Result<(), Box<dyn Error>> {
+    let cache_dir = cache_dir()?;
+    let params = build_params(kind, settings);
+    let key = cache_key(kind, &params);
+    let mut candidates = vec![content_fingerprint(file_content.bytes())];
+    candidates.extend(previous_fingerprint(file_content.path(), &key)?);
+
+    let _lock = lock_cache_dir()?;
+    for candidate in candidates {
+        let cache_path = cache_dir.join(cache_file_name(&candidate, &key));
+        let file = match File::open(&cache_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // Safety: as in `read_cache`.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let payload_start = match check_container(&mmap, kind, &params) {
+            Ok((_, payload_start)) => payload_start,
+            Err(_) => continue, // Left to `read_cache` and eviction
+        };
+        if !PostingsStore::from_mmap(mmap, payload_start).is_ok_and(|store| store.is_intact()) {
+            eprintln!("Deleting cache {}: a posting list fails its checksum", cache_path.display());
+            drop(file);
+            remove_cache_file(&cache_path);
+        }
+    }
+    Ok(())
+}
+
+pub fn save_ngram_index(file_content: &FileContent, settings: &IndexSettings, store: &PostingsStore) -> Result<(), Box<dyn Error>> {
+    save_postings(file_content, "ngram", settings, store)
+}
+
+pub fn load_ngram_index(file_content: &FileContent, settings: &IndexSettings) -> Result<Option<CachedIndex<PostingsStore>>, Box<dyn Error>> {
+    load_postings(file_content, "ngram", settings)
+}
+
+pub fn save_inverted_index(file_content: &FileContent, settings: &IndexSettings, store: &PostingsStore) -> Result<(), Box<dyn Error>> {
+    save_postings(file_content, "inverted", settings, store)
+}
+
+pub fn load_inverted_index(file_content: &FileContent, settings: &IndexSettings) -> Result<Option<CachedIndex<PostingsStore>>, Box<dyn Error>> {
+    load_postings(file_content, "inverted", settings)
+}
+
+pub fn save_position_index(file_content: &FileContent, settings: &IndexSettings, store: &PostingsStore) -> Result<(), Box<dyn Error>> {
+    save_postings(file_content, "positions", settings, store)
+}
+
+pub fn load_position_index(file_content: &FileContent, settings: &IndexSettings) -> Result<Option<CachedIndex<PostingsStore>>, Box<dyn Error>> {
+    load_postings(file_content, "positions", settings)
+}
+
+pub fn save_value_index(file_content: &FileContent, settings: &IndexSettings, store: &PostingsStore) -> Result<(), Box<dyn Error>> {
+    save_postings(file_content, "values", settings, store)
+}
+
+pub fn load_value_index(file_content: &FileContent, settings: &IndexSettings) -> Result<Option<CachedIndex<PostingsStore>>, Box<dyn Error>> {
+    load_postings(file_content, "values", settings)
+}
+
+// --- Postings over the memory limit ---
+// An inverted or n-gram index dropped for outgrowing `max_postings_bytes` is recorded, with
+// the postings bytes it had reached, for the content it was dropped on. Postings only grow as
+// lines are appended, so it would outgrow the same limit on the file's grown content too.
+
+pub fn save_postings_over_limit(file_content: &FileContent, kind: &str, settings: &IndexSettings, postings_bytes: u64) -> Result<(), Box<dyn Error>> {
+    let payload = postings_bytes.to_le_bytes();
+    write_cache(file_content, &format!("{}_over_limit", kind), build_params(kind, settings), &payload, payload.len())
+}
+
+/// The postings bytes the `kind` index of this file reached before it was dropped over the
+/// limit, if it was.
+pub fn load_postings_over_limit(file_content: &FileContent, kind: &str, settings: &IndexSettings) -> Result<Option<u64>, Box<dyn Error>> {
+    match read_cache(file_content, &format!("{}_over_limit", kind), build_params(kind, settings))? {
+        Some(cache) => {
+            let payload = cache.mmap.get(cache.payload_start..).and_then(|payload| payload.try_into().ok())
+                .ok_or("Over-limit record is not 8 bytes")?;
+            Ok(Some(u64::from_le_bytes(payload)))
+        }
+        None => Ok(None),
+    }
+}
+
+// --- Size accounting and eviction ---
+// A cache file's mtime is its last use: set when it is written and when it is loaded. After
+// every save, the least recently used files are deleted until the directory is within the
+// configured limit.
+
+const SETTINGS_FILE: &str = "settings.json";
+const DEFAULT_MAX_TOTAL_BYTES: u64 = 20 * 1024 * 1024 * 1024;
 
 #[derive(Serialize, Deserialize)]
-pub struct InvertedIndexCacheWrapper {
-    pub original_file_size: u64,
-    pub original_mod_time_secs: u64,
-    pub original_mod_time_nanos: u32,
-    pub index_data: InvertedIndex,
+struct CacheSettings {
+    max_total_bytes: u64,
+    #[serde(default)] // Off by default: hashing every byte costs a full read of the file on each open
+    full_hash_verification: bool,
 }
 
-fn get_inverted_index_cache_file_path(original_file_path: &str) -> Result<PathBuf, String> {
-    if let Some(proj_dirs) = ProjectDirs::from("com", "DolphinEdit", "DolphinEdit") {
-        let cache_dir = proj_dirs.cache_dir();
-        if !cache_dir.exists() {
-            std::fs::create_dir_all(cache_dir).map_err(|e| format!("Failed to create cache directory: {}", e))?;
+fn read_settings(cache_dir: &Path) -> CacheSettings {
+    fs::read(cache_dir.join(SETTINGS_FILE)).ok()
+        .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+        .unwrap_or(CacheSettings { max_total_bytes: DEFAULT_MAX_TOTAL_BYTES, full_hash_verification: false })
+}
+
+fn update_settings(update: impl FnOnce(&mut CacheSettings)) -> Result<CacheDirLock, String> {
+    let cache_dir = cache_dir()?;
+    let lock = lock_cache_dir()?;
+    let mut settings = read_settings(&cache_dir);
+    update(&mut settings);
+    let settings = serde_json::to_vec(&settings)
+        .map_err(|e| format!("Failed to serialize cache settings: {}", e))?;
+    write_atomically(&cache_dir.join(SETTINGS_FILE), |writer| writer.write_all(&settings))
+        .map_err(|e| format!("Failed to save cache settings: {}", e))?;
+    Ok(lock)
+}
+
+/// Sets the total size the cache directory may grow to, and evicts down to it right away.
+pub fn set_max_total_bytes(max_total_bytes: u64) -> Result<(), String> {
+    let lock = update_settings(|settings| settings.max_total_bytes = max_total_bytes)?;
+    evict_caches(None, &lock);
+    Ok(())
+}
+
+/// With `enabled`, caches are always checked against a hash of the whole file, also when its
+/// size, mtime and inode are unchanged, and the indexed part of a grown file is hashed whole
+/// rather than on sampled blocks. Saved with the other cache settings.
+pub fn set_full_hash_verification(enabled: bool) -> Result<(), String> {
+    update_settings(|settings| settings.full_hash_verification = enabled).map(drop)
+}
+
+fn mark_used(cache_path: &Path) {
+    let touched = File::options().write(true).open(cache_path)
+        .and_then(|file| file.set_modified(SystemTime::now()));
+    if let Err(e) = touched {
+        eprintln!("Failed to mark cache {} as used: {}", cache_path.display(), e);
+    }
+}
+
+// A file in the cache directory holding an index.
+struct CacheFileInfo {
+    path: PathBuf,
+    fingerprint: String,
+    kind: String,
+    size_bytes: u64,
+    last_used: SystemTime,
+}
+
+fn cache_files(cache_dir: &Path) -> Result<Vec<CacheFileInfo>, String> {
+    let entries = fs::read_dir(cache_dir).map_err(|e| format!("Failed to read cache directory: {}", e))?;
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        // `<fingerprint>.<kind>.cache`; files of older versions are named differently but
+        // still count, so they are evicted first.
+        let (fingerprint, kind) = match name.strip_suffix(".cache").and_then(|stem| stem.split_once('.')) {
+            Some((fingerprint, kind)) => (fingerprint.to_string(), kind.to_string()),
+            None if name.ends_with(".indexcache") => (String::new(), "legacy".to_string()),
+            None => continue,
+        };
+        let metadata = match entry.metadata() {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => continue,
+        };
+        files.push(CacheFileInfo {
+            path: entry.path(),
+            fingerprint,
+            kind,
+            size_bytes: metadata.len(),
+            last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+        });
+    }
+    Ok(files)
+}
+
+fn remove_cache_file(path: &Path) -> bool {
+    match fs::remove_file(path) {
+        Ok(()) => true,
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        Err(e) => {
+            eprintln!("Failed to delete cache {}: {}", path.display(), e);
+            false
         }
-        let file_hash = calculate_hash(&original_file_path.to_string());
-        Ok(cache_dir.join(format!("{}.inverted_index.cache", file_hash)))
-    } else {
-        Err("Could not determine project cache directory".to_string())
     }
 }
 
-pub fn save_inverted_index(file_path: &str, index_data: &InvertedIndex) -> Result<(), Box<dyn Error>> {
-    let cache_path = get_inverted_index_cache_file_path(file_path)?;
-    let metadata = std::fs::metadata(file_path)?;
-    let mod_time = metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+// Deletes least recently used caches until the directory is within its limit. Those of
+// `keep_fingerprint`, the content a cache was just saved for, are never evicted: the file is
+// open and its other indexes are being saved or still to come.
+fn evict_caches(keep_fingerprint: Option<&str>, _lock: &CacheDirLock) {
+    let cache_dir = match cache_dir() {
+        Ok(cache_dir) => cache_dir,
+        Err(e) => {
+            eprintln!("Cache eviction skipped: {}", e);
+            return;
+        }
+    };
+    let max_total_bytes = read_settings(&cache_dir).max_total_bytes;
+    let mut files = match cache_files(&cache_dir) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Cache eviction skipped: {}", e);
+            return;
+        }
+    };
+    remove_stale_temp_files(&cache_dir);
+    let mut total_bytes: u64 = files.iter().map(|file| file.size_bytes).sum();
+    files.sort_by_key(|file| file.last_used);
+    for file in files {
+        if total_bytes <= max_total_bytes {
+            break;
+        }
+        if Some(file.fingerprint.as_str()) != keep_fingerprint && remove_cache_file(&file.path) {
+            total_bytes -= file.size_bytes;
+        }
+    }
+}
 
-    let wrapper = InvertedIndexCacheWrapper {
-        original_file_size: metadata.len(),
-        original_mod_time_secs: mod_time.as_secs(),
-        original_mod_time_nanos: mod_time.subsec_nanos(),
-        index_data: index_data.clone(),
+fn remove_stale_temp_files(cache_dir: &Path) {
+    let entries = match fs::read_dir(cache_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
     };
+    for entry in entries.flatten() {
+        let stale = entry.metadata().ok()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.elapsed().ok())
+            .is_some_and(|age| age > STALE_TEMP_AGE);
+        if stale && entry.file_name().to_string_lossy().ends_with(".tmp") {
+            remove_cache_file(&entry.path());
+        }
+    }
+}
+
+/// One file's caches, as listed by `list_caches`.
+#[derive(Clone, Serialize, Debug)]
+pub struct CacheEntry {
+    pub source_path: Option<String>, // None for caches no longer recorded under any path
+    pub kinds: Vec<String>,
+    pub size_bytes: u64,
+    pub last_used: u64, // Seconds since the Unix epoch
+}
 
-    let file = File::create(cache_path)?;
-    let writer = BufWriter::new(file);
-    bincode::serialize_into(writer, &wrapper)?;
+#[derive(Clone, Serialize, Debug)]
+pub struct CacheListing {
+    pub entries: Vec<CacheEntry>, // Most recently used first
+    pub total_bytes: u64,         // Of the cache directory; a cache shared by two paths counts once
+    pub max_total_bytes: u64,
+}
+
+pub fn list_caches() -> Result<CacheListing, String> {
+    let cache_dir = cache_dir()?;
+    let files = cache_files(&cache_dir)?;
+    let lookup = read_path_lookup(&cache_dir);
+
+    let mut entries = Vec::new();
+    let mut listed = HashSet::new();
+    for (source_path, kinds) in &lookup {
+        let source_files: Vec<&CacheFileInfo> = files.iter()
+            .filter(|file| kinds.get(&file.kind) == Some(&file.fingerprint))
+            .collect();
+        if source_files.is_empty() {
+            continue;
+        }
+        listed.extend(source_files.iter().map(|file| file.path.clone()));
+        entries.push(cache_entry(Some(source_path.clone()), &source_files));
+    }
+    let mut unlisted: HashMap<&str, Vec<&CacheFileInfo>> = HashMap::new();
+    for file in files.iter().filter(|file| !listed.contains(&file.path)) {
+        unlisted.entry(file.fingerprint.as_str()).or_default().push(file);
+    }
+    entries.extend(unlisted.values().map(|files| cache_entry(None, files)));
+    entries.sort_by_key(|entry| Reverse(entry.last_used));
+
+    Ok(CacheListing {
+        entries,
+        total_bytes: files.iter().map(|file| file.size_bytes).sum(),
+        max_total_bytes: read_settings(&cache_dir).max_total_bytes,
+    })
+}
+
+fn cache_entry(source_path: Option<String>, files: &[&CacheFileInfo]) -> CacheEntry {
+    let mut kinds: Vec<String> = files.iter().map(|file| file.kind.clone()).collect();
+    kinds.sort();
+    kinds.dedup();
+    CacheEntry {
+        source_path,
+        kinds,
+        size_bytes: files.iter().map(|file| file.size_bytes).sum(),
+        last_used: files.iter()
+            .filter_map(|file| file.last_used.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_secs())
+            .max()
+            .unwrap_or(0),
+    }
+}
+
+/// Forgets the caches recorded for `source_path` and deletes those that no other path uses
+/// (a copy of the file has the same content, so it shares them). Returns the bytes freed.
+pub fn clear_cache(source_path: &str) -> Result<u64, String> {
+    let lock = lock_cache_dir()?;
+    let cache_dir = cache_dir()?;
+    let mut lookup = read_path_lookup(&cache_dir);
+    // Listed paths are lookup keys already; others may still need resolving.
+    let kinds = match lookup.remove(source_path) {
+        Some(kinds) => kinds,
+        None => lookup.remove(&lookup_key(source_path)).unwrap_or_default(),
+    };
+    write_path_lookup(&cache_dir, &lookup, &lock).map_err(|e| format!("Failed to update cache path lookup: {}", e))?;
+    Ok(remove_unused_caches(&cache_dir, &lookup, kinds))
+}
+
+/// Forgets the caches of `file_path` after the app has rewritten it, deleting those that no
+/// other path uses. They describe content the file no longer has.
+pub fn invalidate_caches(file_path: &str) -> Result<(), String> {
+    let lock = lock_cache_dir()?;
+    let cache_dir = cache_dir()?;
+    let mut lookup = read_path_lookup(&cache_dir);
+    let kinds = match lookup.remove(&lookup_key(file_path)) {
+        Some(kinds) => kinds,
+        None => return Ok(()),
+    };
+    write_path_lookup(&cache_dir, &lookup, &lock).map_err(|e| format!("Failed to update cache path lookup: {}", e))?;
+    remove_unused_caches(&cache_dir, &lookup, kinds);
     Ok(())
 }
 
-pub fn load_inverted_index(file_path: &str) -> Result<Option<InvertedIndex>, Box<dyn Error>> {
-    let cache_path = get_inverted_index_cache_file_path(file_path)?;
-    if !cache_path.exists() {
-        return Ok(None);
+// Deletes the caches of a path just removed from the lookup (kind -> fingerprint) that no
+// path left in it uses. Returns the bytes freed.
+fn remove_unused_caches(cache_dir: &Path, lookup: &PathLookup, kinds: HashMap<String, String>) -> u64 {
+    let mut freed = 0;
+    for (kind, fingerprint) in kinds {
+        if lookup.values().any(|kinds| kinds.get(&kind) == Some(&fingerprint)) {
+            continue;
+        }
+        let path = cache_dir.join(cache_file_name(&fingerprint, &kind));
+        let size_bytes = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+        if remove_cache_file(&path) {
+            freed += size_bytes;
+        }
+    }
+    freed
+}
+
+/// Deletes every cache and the path lookup. Returns the bytes freed.
+pub fn clear_all_caches() -> Result<u64, String> {
+    let _lock = lock_cache_dir()?;
+    let cache_dir = cache_dir()?;
+    let mut freed = 0;
+    for file in cache_files(&cache_dir)? {
+        if remove_cache_file(&file.path) {
+            freed += file.size_bytes;
+        }
     }
+    remove_cache_file(&cache_dir.join(PATH_LOOKUP_FILE));
+    Ok(freed)
+}
 
-    let current_metadata = std::fs::metadata(file_path)?;
-    let current_mod_time = current_metadata.modified()?.duration_since(std::time::UNIX_EPOCH)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let file = File::open(cache_path)?;
-    let reader = BufReader::new(file);
-    let wrapper: InvertedIndexCacheWrapper = bincode::deserialize_from(reader)?;
+    fn stamp() -> SourceStamp {
+        SourceStamp { original_file_size: 3, modified: Some(1), identity: None, sample_hash: 2, full_hash: None }
+    }
 
-    if wrapper.original_file_size == current_metadata.len() &&
-       wrapper.original_mod_time_secs == current_mod_time.as_secs() &&
-       wrapper.original_mod_time_nanos == current_mod_time.subsec_nanos() {
-        Ok(Some(wrapper.index_data))
-    } else {
-        Ok(None) // Cache is stale
+    fn content_of(name: &str, bytes: &[u8]) -> FileContent {
+        let path = std::env::temp_dir().join(format!("dolphin-cache-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let content = FileContent::open(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        content
+    }
+
+    fn container(kind: &str, params: BuildParams, payload: &[u8], head_len: usize) -> Vec<u8> {
+        let header = bincode::serialize(&CacheHeader {
+            kind: kind.to_string(),
+            params,
+            source: stamp(),
+            payload_len: payload.len() as u64,
+            head_len: head_len as u64,
+            head_checksum: xxh3_64(&payload[..head_len]),
+        }).unwrap();
+        let mut data = CACHE_MAGIC.to_vec();
+        data.extend_from_slice(&CACHE_FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn check(data: &[u8]) -> &'static str {
+        match check_container(data, "ngram", &build_params("ngram", &IndexSettings::default())) {
+            Ok(_) => "ok",
+            Err(ContainerError::Damaged(_)) => "damaged",
+            Err(ContainerError::Foreign(_)) => "foreign",
+        }
+    }
+
+    #[test]
+    fn intact_containers_are_accepted() {
+        let params = build_params("ngram", &IndexSettings::default());
+        let data = container("ngram", params, b"headtail", 4);
+        let (header, payload_start) = check_container(&data, "ngram", &build_params("ngram", &IndexSettings::default()))
+            .unwrap_or_else(|_| panic!("rejected"));
+        assert_eq!(&data[payload_start..], b"headtail");
+        assert_eq!((header.kind.as_str(), header.source.original_file_size), ("ngram", 3));
+
+        // Only the head is checksummed; the rest is checked as it is decoded.
+        let mut data = data;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert_eq!(check(&data), "ok");
+    }
+
+    #[test]
+    fn torn_or_corrupt_containers_are_damaged() {
+        let data = container("ngram", build_params("ngram", &IndexSettings::default()), b"headtail", 4);
+        assert_eq!(check(b""), "damaged");
+        assert_eq!(check(&data[..PREAMBLE_LEN - 1]), "damaged");
+        assert_eq!(check(&data[..PREAMBLE_LEN + 4]), "damaged");
+        assert_eq!(check(&data[..data.len() - 1]), "damaged");
+
+        let mut not_a_cache = data.clone();
+        not_a_cache[0] = b'X';
+        assert_eq!(check(&not_a_cache), "damaged");
+
+        let mut flipped = data.clone();
+        flipped[data.len() - 8] ^= 1;
+        assert_eq!(check(&flipped), "damaged");
+
+        let mut longer = data.clone();
+        longer.push(0);
+        assert_eq!(check(&longer), "damaged");
+    }
+
+    #[test]
+    fn other_versions_kinds_and_settings_are_foreign() {
+        let mut data = container("ngram", build_params("ngram", &IndexSettings::default()), b"payload", 7);
+        data[8..12].copy_from_slice(&(CACHE_FORMAT_VERSION - 1).to_le_bytes());
+        assert_eq!(check(&data), "foreign");
+
+        let data = container("inverted", build_params("inverted", &IndexSettings::default()), b"payload", 7);
+        assert_eq!(check(&data), "foreign");
+
+        let settings = IndexSettings { ngram_size: 4, ..IndexSettings::default() };
+        let data = container("ngram", build_params("ngram", &settings), b"payload", 7);
+        assert_eq!(check(&data), "foreign");
+    }
+
+    // A stamp saved with or without full-hash verification; `source_stamp` would also take
+    // a full hash computed earlier in the test.
+    fn stamp_of(content: &FileContent, full_hash: bool) -> SourceStamp {
+        SourceStamp { full_hash: full_hash.then(|| content.full_hash()), ..source_stamp(content, false) }
+    }
+
+    #[test]
+    fn unchanged_files_match_their_stamp() {
+        let content = content_of("unchanged", b"{\"a\": 1}\n");
+        assert!(stamp_matches(&stamp_of(&content, false), &content, false));
+        assert!(stamp_matches(&stamp_of(&content, true), &content, true));
+        // Without a full hash, verification cannot confirm the content.
+        assert!(!stamp_matches(&stamp_of(&content, false), &content, true));
+    }
+
+    #[test]
+    fn copied_or_touched_files_need_the_full_hash() {
+        let original = content_of("original", b"same bytes\n");
+        let copy = content_of("copy", b"same bytes\n");
+        assert!(!stamp_matches(&stamp_of(&original, false), &copy, false));
+        assert!(stamp_matches(&stamp_of(&original, true), &copy, false));
+        assert!(stamp_matches(&stamp_of(&original, true), &copy, true));
+
+        let mut touched = stamp_of(&original, false);
+        touched.modified = touched.modified.map(|nanos| nanos + 1);
+        assert!(!stamp_matches(&touched, &original, false));
+        touched.full_hash = Some(original.full_hash());
+        assert!(stamp_matches(&touched, &original, false));
+
+        // Same size and sampled hash, other content: only the full hash tells.
+        let mut edited = stamp_of(&original, true);
+        edited.full_hash = edited.full_hash.map(|hash| hash ^ 1);
+        edited.modified = None;
+        assert!(!stamp_matches(&edited, &original, false));
+    }
+
+    #[test]
+    fn grown_files_match_if_they_start_with_the_indexed_bytes() {
+        let indexed = content_of("indexed", b"line 1\n");
+        let grown = content_of("grown", b"line 1\nline 2\n");
+        assert!(stamp_matches(&stamp_of(&indexed, false), &grown, false));
+        assert!(stamp_matches(&stamp_of(&indexed, true), &grown, true));
+        assert!(!stamp_matches(&stamp_of(&indexed, false), &grown, true));
+
+        let rewritten = content_of("rewritten", b"line X\nline 2\n");
+        assert!(!stamp_matches(&stamp_of(&indexed, false), &rewritten, false));
+        assert!(!stamp_matches(&stamp_of(&grown, false), &indexed, false));
+        let other = content_of("other", b"line 2\n");
+        assert!(!stamp_matches(&stamp_of(&indexed, false), &other, false));
     }
 }
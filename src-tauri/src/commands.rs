@@ -1,181 +1,957 @@
-use tauri::State;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
-use std::sync::Arc; // For sharing line_offset_index in closure
-use serde::Serialize; // For the IndexingStatus struct
-
-use super::state::AppState;
-use super::indexing_service::{self, LineOffset}; // Ensure LineOffset is in scope
+use serde::Serialize;
+use tauri::{AppHandle, Manager, State};
+use regex::bytes::Regex;
+use std::fs::{self, File};
+use std::sync::{Arc, Mutex}; // For sharing line_offset_index in closure
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::error::Error;
+
+use super::state::{AppState, DocId, Document, FollowJob, IndexingJob, IndexingStage, IndexingStatus, PendingReplace};
+use super::indexing_service::{self, BuiltIndexes, IndexSelection, IndexSettings, IndexingProgress, LineOffset, PostingsBytes, ResumePoint}; // Ensure LineOffset is in scope
 use super::cache_manager;
+use super::file_content_service::FileContent;
+use super::file_watcher::FileWatcher;
+use super::postings_store::{PostingList, PostingsStore};
+use super::schema_inference::{FileSchemaStats, SchemaReport, SchemaStats};
+use super::line_repair::{self, InvalidLine, InvalidLinesPage, InvalidLinesScan, RepairAction, RepairSummary, ScanState};
+use super::schema_validation::{self, ValidationPage, ValidationResults, ValidationState, Violation};
+use super::search_handler::{QueryEvaluator, QueryResults, Replacement, ReplaceSummary, SearchExecutor, SearchPlanner, SearchQuery, SearchResults};
+use super::utils::line_text::{self, LineDecoding, LineText};
+use super::utils::query_parser::{self, Query};
+
+
+pub const INDEXING_STATUS_EVENT: &str = "indexing_status_update";
+const STATUS_EMIT_INTERVAL: Duration = Duration::from_millis(100);
+
+pub const VALIDATION_STATUS_EVENT: &str = "validation_status_update";
+
+pub const INVALID_LINES_STATUS_EVENT: &str = "invalid_lines_status_update";
+
+pub const REPLACE_FINISHED_EVENT: &str = "replace_finished";
+
+pub const REPAIR_FINISHED_EVENT: &str = "repair_finished";
 
+pub const LINES_APPENDED_EVENT: &str = "lines_appended";
+// A followed file is re-checked this often even without a change notification.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Bytes before the previous end of a followed file that must be unchanged for growth to
+// count as an append.
+const FOLLOW_PREFIX_CHECK_BYTES: usize = 4096;
+// How far the search indexes may lag behind a followed file before they are extended.
+const FOLLOW_REINDEX_BYTES: u64 = 16 * 1024 * 1024;
 
-#[derive(Clone, Serialize)] // Serialize for sending to frontend
-pub struct IndexingStatus {
-    message: String,
-    progress: f32,
+/// Payload of the `replace_finished` event, sent when Replace All output is ready to save.
+#[derive(Clone, Serialize, Debug)]
+pub struct ReplaceFinished {
+    pub doc_id: DocId,
+    pub summary: ReplaceSummary,
 }
 
+/// Payload of the `repair_finished` event.
+#[derive(Clone, Serialize, Debug)]
+pub struct RepairFinished {
+    pub doc_id: DocId,
+    pub summary: RepairSummary,
+}
+
+/// Payload of the `lines_appended` event.
+#[derive(Clone, Serialize, Debug)]
+pub struct LinesAppended {
+    pub doc_id: DocId,
+    pub total_lines: usize,
+    pub first_changed_line: usize, // Lines from here on are new, or were incomplete and have grown
+    pub reset: bool, // The file was truncated or replaced and has been reindexed from scratch
+}
+
+// Stores the status for `get_indexing_status` and pushes it to the frontend.
+fn publish_status(document: &Document, status: IndexingStatus, app_handle: &AppHandle) {
+    match document.indexing_status.lock() {
+        Ok(mut lock) => *lock = status.clone(),
+        Err(e) => eprintln!("Failed to lock indexing_status: {}", e),
+    }
+    if let Err(e) = app_handle.emit_all(INDEXING_STATUS_EVENT, status) {
+        eprintln!("Failed to emit {}: {}", INDEXING_STATUS_EVENT, e);
+    }
+}
+
+fn set_status(document: &Document, msg: &str, progress: f32, stage: IndexingStage, app_handle: &AppHandle) {
+    publish_status(document, IndexingStatus::new(document.id, stage, msg, progress), app_handle);
+}
+
+// Turns the per-line progress callbacks of one stage into throttled status events.
+// The stage's own completion is mapped onto `progress_range` of the overall progress.
+struct StageProgress<'a> {
+    document: &'a Document,
+    app_handle: &'a AppHandle,
+    stage: IndexingStage,
+    message: &'a str,
+    progress_range: (f32, f32),
+    total_bytes: u64,
+    total_lines: usize,
+    resumed_fraction: f64, // Part of the work already done before this run, e.g. an indexed prefix
+    started: Instant,
+    last_emit: Cell<Option<Instant>>,
+}
+
+impl<'a> StageProgress<'a> {
+    fn new(document: &'a Document, app_handle: &'a AppHandle, stage: IndexingStage, message: &'a str, progress_range: (f32, f32), total_bytes: u64, total_lines: usize) -> Self {
+        set_status(document, message, progress_range.0, stage, app_handle);
+        StageProgress {
+            document,
+            app_handle,
+            stage,
+            message,
+            progress_range,
+            total_bytes,
+            total_lines,
+            resumed_fraction: 0.0,
+            started: Instant::now(),
+            last_emit: Cell::new(None),
+        }
+    }
+
+    // For passes that continue from byte `resumed_bytes`, so the ETA only reflects this run's rate.
+    fn resuming_from(mut self, resumed_bytes: u64) -> Self {
+        if self.total_bytes > 0 {
+            self.resumed_fraction = (resumed_bytes as f64 / self.total_bytes as f64).min(1.0);
+        }
+        self
+    }
+
+    fn report(&self, progress: IndexingProgress) {
+        let now = Instant::now();
+        if self.last_emit.get().is_some_and(|last| now.duration_since(last) < STATUS_EMIT_INTERVAL) {
+            return;
+        }
+        self.last_emit.set(Some(now));
+
+        let fraction = if self.total_bytes > 0 {
+            progress.bytes_processed as f64 / self.total_bytes as f64
+        } else if self.total_lines > 0 {
+            progress.lines_processed as f64 / self.total_lines as f64
+        } else {
+            0.0
+        }.min(1.0);
+        let done_this_run = fraction - self.resumed_fraction;
+        let eta_seconds = if done_this_run > 0.0 {
+            Some(now.duration_since(self.started).as_secs_f64() * (1.0 - fraction) / done_this_run)
+        } else {
+            None
+        };
+        let (start, end) = self.progress_range;
+
+        publish_status(self.document, IndexingStatus {
+            doc_id: self.document.id,
+            stage: self.stage,
+            message: self.message.to_string(),
+            progress: start + (end - start) * fraction as f32,
+            bytes_processed: progress.bytes_processed,
+            total_bytes: self.total_bytes,
+            lines_processed: progress.lines_processed,
+            total_lines: self.total_lines,
+            eta_seconds,
+        }, self.app_handle);
+    }
+}
+
+fn total_bytes(line_offsets: &[LineOffset]) -> u64 {
+    line_offsets.last().map(|l| l.offset + l.length as u64).unwrap_or(0)
+}
+
+/// Returned by `open_file`.
+#[derive(Clone, Serialize, Debug)]
+pub struct OpenedDocument {
+    pub doc_id: DocId,
+    pub total_lines: usize,
+}
+
+/// Opens `file_path` as a new document, alongside any already open. `settings` choose which
+/// search indexes are built and how, trading opening time and memory against search speed;
+/// they are remembered for the file and used when it is opened again without any.
 #[tauri::command]
-pub fn open_file(file_path: String, app_state: State<AppState>) -> Result<usize, String> {
-    // Helper to update status
-    let set_status = |msg: &str, progress: f32, state: &State<AppState>| {
-        match state.indexing_status_message.lock() {
-            Ok(mut lock) => *lock = msg.to_string(),
-            Err(e) => eprintln!("Failed to lock indexing_status_message: {}",e),
+pub fn open_file(file_path: String, settings: Option<IndexSettings>, app_handle: AppHandle) -> Result<OpenedDocument, String> {
+    let app_state = app_handle.state::<AppState>();
+    // Settings given explicitly are remembered, but only once the file has opened with them.
+    let settings_to_save = settings.map(IndexSettings::normalized).transpose()?;
+    let index_settings = match &settings_to_save {
+        Some(settings) => settings.clone(),
+        None => cache_manager::saved_index_settings(&file_path)
+            .and_then(|settings| settings.normalized().ok())
+            .unwrap_or_default(),
+    };
+    let document = app_state.create_document(&file_path, index_settings)?;
+    match index_file(&document, &file_path, &app_handle) {
+        Ok(total_lines) => {
+            if let Some(settings) = settings_to_save {
+                if let Err(e) = cache_manager::save_index_settings(&file_path, &settings) {
+                    eprintln!("Failed to remember index settings for {}: {}", file_path, e);
+                }
+            }
+            Ok(OpenedDocument { doc_id: document.id, total_lines })
         }
-        match state.indexing_progress.lock() {
-            Ok(mut lock) => *lock = progress,
-            Err(e) => eprintln!("Failed to lock indexing_progress: {}",e),
+        Err(e) => {
+            if let Err(remove_error) = app_state.remove_document(document.id) {
+                eprintln!("Failed to remove document {}: {}", document.id, remove_error);
+            }
+            Err(e)
         }
-        // Optionally, emit an event to the frontend immediately if desired
-        // For simplicity, we'll rely on the frontend polling get_indexing_status for now.
-        // state.window().emit("indexing_status_update", IndexingStatus { message: msg.to_string(), progress }).unwrap_or_else(|e| {
-        //     eprintln!("Failed to emit indexing_status_update: {}", e);
-        // });
+    }
+}
+
+/// Closes a document: stops its background threads, discards unsaved Replace All output
+/// and frees its indexes and file mapping. Closing a document that is not open is a no-op.
+#[tauri::command]
+pub fn close_document(doc_id: DocId, app_state: State<AppState>) -> Result<(), String> {
+    if let Some(document) = app_state.remove_document(doc_id)? {
+        stop_follow_job(&document);
+        stop_indexing_job(&document);
+        stop_validation_job(&document);
+        stop_invalid_lines_job(&document);
+        stop_replace_job(&document);
+        stop_repair_job(&document);
+        discard_pending_replace(&document);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn cancel_indexing(doc_id: DocId, app_handle: AppHandle) -> Result<(), String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    if stop_indexing_job(&document) {
+        set_status(&document, "Indexing cancelled.", 1.0, IndexingStage::Cancelled, &app_handle);
+    }
+    Ok(())
+}
+
+// Signals the document's background indexing job (if any) and waits for it to exit, so it
+// can no longer write into the document. Returns whether a job was running.
+fn stop_indexing_job(document: &Document) -> bool {
+    stop_job(&document.indexing_job, "Indexing", document.id)
+}
+
+fn stop_validation_job(document: &Document) -> bool {
+    stop_job(&document.validation_job, "Validation", document.id)
+}
+
+fn stop_invalid_lines_job(document: &Document) -> bool {
+    stop_job(&document.invalid_lines_job, "Invalid line scan", document.id)
+}
+
+fn stop_replace_job(document: &Document) -> bool {
+    stop_job(&document.replace_job, "Replace", document.id)
+}
+
+fn stop_repair_job(document: &Document) -> bool {
+    stop_job(&document.repair_job, "Repair", document.id)
+}
+
+fn stop_job(job_slot: &Mutex<Option<IndexingJob>>, name: &str, doc_id: DocId) -> bool {
+    let job = match job_slot.lock() {
+        Ok(mut lock) => lock.take(),
+        Err(e) => {
+            eprintln!("Failed to lock {} job: {}", name, e);
+            None
+        }
+    };
+    match job {
+        Some(job) => {
+            job.cancel_flag.store(true, Ordering::Relaxed);
+            if job.handle.join().is_err() {
+                eprintln!("{} thread of document {} panicked", name, doc_id);
+            }
+            true
+        }
+        None => false,
+    }
+}
+
+/// Starts or stops following appends to a document's file, like `tail -f`. New lines are
+/// indexed as they arrive and announced with `lines_appended` events; if the file is
+/// truncated or replaced (log rotation), it is reopened and the event has `reset` set.
+#[tauri::command]
+pub fn follow_file(doc_id: DocId, enabled: bool, app_handle: AppHandle) -> Result<(), String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    stop_follow_job(&document);
+    if !enabled {
+        return Ok(());
+    }
+
+    let file_path = mapped_content(&document)?.path().to_string();
+    let watcher = FileWatcher::new(&file_path)?;
+    let stop_flag = Arc::new(AtomicBool::new(false));
+    let worker_stop_flag = Arc::clone(&stop_flag);
+    let worker_file_path = file_path.clone();
+    let worker_document = Arc::clone(&document);
+    let worker_app_handle = app_handle.clone();
+    let handle = thread::Builder::new()
+        .name("follow".to_string())
+        .spawn(move || {
+            while !worker_stop_flag.load(Ordering::Relaxed) {
+                watcher.wait(FOLLOW_POLL_INTERVAL);
+                if worker_stop_flag.load(Ordering::Relaxed) {
+                    break;
+                }
+                if let Err(e) = check_followed_file(&worker_document, &worker_file_path, &worker_stop_flag, &worker_app_handle) {
+                    eprintln!("Following {} failed: {}", worker_file_path, e);
+                }
+            }
+        })
+        .map_err(|e| format!("Failed to start follow thread: {}", e))?;
+    *document.follow_job.lock().map_err(|e| format!("Failed to lock follow_job: {}", e))? = Some(FollowJob { file_path, stop_flag, handle });
+    Ok(())
+}
+
+fn stop_follow_job(document: &Document) {
+    let job = match document.follow_job.lock() {
+        Ok(mut lock) => lock.take(),
+        Err(e) => {
+            eprintln!("Failed to lock follow_job: {}", e);
+            None
+        }
+    };
+    if let Some(job) = job {
+        job.stop_flag.store(true, Ordering::Relaxed);
+        if job.handle.join().is_err() {
+            eprintln!("Follow thread for {} panicked", job.file_path);
+        }
+    }
+}
+
+// Brings the document up to date with the followed file: indexes appended lines, or
+// reopens the file if it shrank or was replaced by another one.
+fn check_followed_file(document: &Arc<Document>, file_path: &str, stop_flag: &AtomicBool, app_handle: &AppHandle) -> Result<(), String> {
+    let content = mapped_content(document)?;
+    let metadata = match fs::metadata(file_path) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok(()), // Rotated away and not recreated yet
+    };
+    if !content.is_same_file(&metadata) || metadata.len() < content.len() {
+        return reset_followed_file(document, file_path, app_handle);
+    }
+    if metadata.len() == content.len() {
+        return Ok(());
+    }
+
+    let grown = Arc::new(FileContent::open(file_path)?);
+    // Without inode numbers a replacement is only caught by its content: the bytes that
+    // were indexed must still be there.
+    let indexed_size = content.len() as usize;
+    let check_start = indexed_size.saturating_sub(FOLLOW_PREFIX_CHECK_BYTES);
+    if grown.bytes().get(check_start..indexed_size) != content.bytes().get(check_start..indexed_size) {
+        return reset_followed_file(document, file_path, app_handle);
+    }
+    drop(content);
+
+    // Both are swapped under the line offset index lock, in the order readers take them.
+    let mut index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))?;
+    *document.file_content.lock().map_err(|e| format!("Failed to lock file_content: {}", e))? = Some(Arc::clone(&grown));
+    let line_offsets = match index_lock.as_mut() {
+        Some(line_offsets) => line_offsets,
+        None => return Ok(()),
     };
+    let from = indexing_service::resume_point(line_offsets, indexed_size as u64, &grown);
+    let tail = indexing_service::build_line_offset_index(&grown, from, &|_| {})?;
+    // Only copies the offsets if the indexing thread still holds them.
+    let line_offsets = Arc::make_mut(line_offsets);
+    line_offsets.truncate(from.line);
+    line_offsets.extend(tail);
+    let total_lines = line_offsets.len();
+    drop(index_lock);
 
-    set_status("Opening file...", 0.0, &app_state);
+    if let Err(e) = app_handle.emit_all(LINES_APPENDED_EVENT, LinesAppended { doc_id: document.id, total_lines, first_changed_line: from.line, reset: false }) {
+        eprintln!("Failed to emit {}: {}", LINES_APPENDED_EVENT, e);
+    }
+    let extended = extend_search_indexes(document, &grown, stop_flag);
+    if extended.is_err() {
+        discard_damaged_indexes(document, app_handle);
+    }
+    extended
+}
 
-    // 1. Reset state for new file
-    *app_state.current_file_path.lock().map_err(|e| format!("Failed to lock current_file_path: {}", e))? = Some(file_path.clone());
-    *app_state.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))? = None;
-    *app_state.inverted_index.lock().map_err(|e| format!("Failed to lock inverted_index: {}", e))? = None;
-    *app_state.ngram_index.lock().map_err(|e| format!("Failed to lock ngram_index: {}", e))? = None;
-    
-    let total_lines_count: usize;
+fn reset_followed_file(document: &Arc<Document>, file_path: &str, app_handle: &AppHandle) -> Result<(), String> {
+    let total_lines = index_file(document, file_path, app_handle)?;
+    if let Err(e) = app_handle.emit_all(LINES_APPENDED_EVENT, LinesAppended { doc_id: document.id, total_lines, first_changed_line: 0, reset: true }) {
+        eprintln!("Failed to emit {}: {}", LINES_APPENDED_EVENT, e);
+    }
+    Ok(())
+}
+
+// Catches the search indexes up with lines appended while following, once enough have
+// accumulated; until then searches scan them. Also refreshes the caches, so reopening the
+// file later only has to index what was appended after this.
+fn extend_search_indexes(document: &Document, file_content: &FileContent, stop_flag: &AtomicBool) -> Result<(), String> {
+    // The indexing thread owns the search indexes until it is done with them.
+    if document.indexing_job.lock()
+        .map_err(|e| format!("Failed to lock indexing_job: {}", e))?
+        .as_ref()
+        .is_some_and(|job| !job.handle.is_finished()) {
+        return Ok(());
+    }
 
-    // 2. Line Offset Index
-    set_status("Indexing line offsets...", 0.1, &app_state);
-    let line_offset_index_arc = match cache_manager::load_line_offset_index(&file_path) {
-        Ok(Some(cached_index)) => {
-            set_status("Loaded line offsets from cache.", 0.25, &app_state);
-            Arc::new(cached_index)
+    let indexed_lines = *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))?;
+    let line_offsets = match &*document.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))? {
+        Some(line_offsets) => Arc::clone(line_offsets),
+        None => return Ok(()),
+    };
+    let from = match line_offsets.get(indexed_lines) {
+        Some(first_unindexed) => ResumePoint { line: indexed_lines, offset: first_unindexed.offset },
+        None => return Ok(()),
+    };
+    if file_content.len() - from.offset < FOLLOW_REINDEX_BYTES {
+        return Ok(());
+    }
+    let mut kinds = Vec::new();
+    let mut postings_base = PostingsBytes::default();
+    for kind in SearchIndex::ALL {
+        if let Some(store) = &*kind.slot(document).lock().map_err(|e| format!("Failed to lock {}: {}", kind.name(), e))? {
+            kinds.push(kind);
+            if let Some(bytes) = kind.limited_bytes(&mut postings_base) {
+                *bytes = store.as_bytes().len() as u64;
+            }
         }
-        _ => { // Cache miss or error
-            match indexing_service::build_line_offset_index(&file_path) {
-                Ok(built_index) => {
-                    if let Err(e) = cache_manager::save_line_offset_index(&file_path, &built_index) {
+    }
+    let extend_schema = document.schema_stats.lock()
+        .map_err(|e| format!("Failed to lock schema_stats: {}", e))?
+        .as_ref()
+        .is_some_and(|schema| schema.covered == from.offset);
+    if kinds.is_empty() && !extend_schema {
+        return Ok(());
+    }
+
+    let selection = IndexSelection { schema: extend_schema, ..SearchIndex::selection(&kinds) };
+    let mut tail = indexing_service::build_indexes(file_content, selection, &document.index_settings, from, postings_base, &|| stop_flag.load(Ordering::Relaxed), &|_| {})?;
+    for kind in kinds {
+        let extended = kind.slot(document).lock()
+            .map_err(|e| format!("Failed to lock {}: {}", kind.name(), e))?
+            .as_ref()
+            .and_then(|store| kind.encode(&tail, Some(store)))
+            .transpose()?;
+        match extended {
+            Some(store) => install_search_index(document, kind, store, file_content)?,
+            // Over the postings memory limit: without the tail it would miss lines.
+            None => {
+                *kind.slot(document).lock().map_err(|e| format!("Failed to lock {}: {}", kind.name(), e))? = None;
+                record_over_limit(document, kind, file_content, &mut tail);
+            }
+        }
+    }
+    if extend_schema {
+        let previous = document.schema_stats.lock()
+            .map_err(|e| format!("Failed to lock schema_stats: {}", e))?
+            .take()
+            .map(|schema| schema.complete);
+        install_schema_stats(document, file_content, previous, &mut tail)?;
+    }
+    *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))? =
+        indexing_service::resume_point(&line_offsets, file_content.len(), file_content).line;
+    if let Err(e) = cache_manager::save_line_offset_index(file_content, &line_offsets) {
+        eprintln!("Failed to save line offset index to cache: {}", e);
+    }
+    Ok(())
+}
+
+// (Re)indexes the document from `file_path`. Returns once the line offset index is available;
+// the search indexes are loaded or built on a background thread.
+fn index_file(document: &Arc<Document>, file_path: &str, app_handle: &AppHandle) -> Result<usize, String> {
+    let file_path = file_path.to_string();
+
+    stop_indexing_job(document);
+    stop_validation_job(document);
+    stop_invalid_lines_job(document);
+    stop_replace_job(document);
+    set_status(document, "Opening file...", 0.0, IndexingStage::LineOffsets, app_handle);
+
+    // 1. Reset the document's state
+    *document.file_path.lock().map_err(|e| format!("Failed to lock file_path: {}", e))? = file_path.clone();
+    *document.file_content.lock().map_err(|e| format!("Failed to lock file_content: {}", e))? = None;
+    *document.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))? = None;
+    for kind in SearchIndex::ALL {
+        *kind.slot(document).lock().map_err(|e| format!("Failed to lock {}: {}", kind.name(), e))? = None;
+    }
+    *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))? = 0;
+    *document.schema_stats.lock().map_err(|e| format!("Failed to lock schema_stats: {}", e))? = None;
+    *document.validation.lock().map_err(|e| format!("Failed to lock validation: {}", e))? = None;
+    *document.invalid_lines.lock().map_err(|e| format!("Failed to lock invalid_lines: {}", e))? = None;
+
+    let file_content = match FileContent::open(&file_path) {
+        Ok(content) => Arc::new(content),
+        Err(e) => {
+            set_status(document, &format!("Error: {}", e), 0.0, IndexingStage::Failed, app_handle);
+            return Err(e);
+        }
+    };
+    *document.file_content.lock().map_err(|e| format!("Failed to lock file_content: {}", e))? = Some(Arc::clone(&file_content));
+
+    // 2. Line Offset Index. It is in place before the search indexes are started, so the
+    // lines can be viewed while they build, or if they are cancelled or fail.
+    let line_offset_index_arc = match cache_manager::load_line_offset_index(&file_content) {
+        Ok(Some(cached)) if cached.indexed_size == file_content.len() => {
+            set_status(document, "Loaded line offsets from cache.", 0.25, IndexingStage::LineOffsets, app_handle);
+            Arc::new(cached.index)
+        }
+        cached => { // Cache miss or error, or the file grew and only its tail needs indexing
+            let (mut line_offsets, from) = match cached {
+                Ok(Some(cached)) => {
+                    let from = indexing_service::resume_point(&cached.index, cached.indexed_size, &file_content);
+                    (cached.index, from)
+                }
+                Ok(None) => (Vec::new(), ResumePoint::default()),
+                Err(e) => {
+                    eprintln!("Failed to load line offset index from cache: {}", e);
+                    (Vec::new(), ResumePoint::default())
+                }
+            };
+            let message = if from.offset > 0 { "Indexing appended lines..." } else { "Indexing line offsets..." };
+            let stage_progress = StageProgress::new(document, app_handle, IndexingStage::LineOffsets, message, (0.0, 0.25), file_content.len(), 0)
+                .resuming_from(from.offset);
+            match indexing_service::build_line_offset_index(&file_content, from, &|p| stage_progress.report(p)) {
+                Ok(tail) => {
+                    line_offsets.truncate(from.line);
+                    line_offsets.extend(tail);
+                    if let Err(e) = cache_manager::save_line_offset_index(&file_content, &line_offsets) {
                         eprintln!("Failed to save line offset index to cache: {}", e);
                     }
-                    set_status("Built line offsets.", 0.25, &app_state);
-                    Arc::new(built_index)
+                    set_status(document, "Built line offsets.", 0.25, IndexingStage::LineOffsets, app_handle);
+                    Arc::new(line_offsets)
                 }
                 Err(e) => {
-                    set_status(&format!("Error: {}", e), 0.0, &app_state);
+                    set_status(document, &format!("Error: {}", e), 0.0, IndexingStage::Failed, app_handle);
                     return Err(format!("Failed to build line offset index: {}", e));
                 }
             }
         }
     };
-    total_lines_count = line_offset_index_arc.len();
-    *app_state.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))? = Some(line_offset_index_arc.as_ref().clone());
+    let total_lines_count = line_offset_index_arc.len();
+    *document.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))? = Some(Arc::clone(&line_offset_index_arc));
 
-    let loi_clone_for_closure = Arc::clone(&line_offset_index_arc);
-    let file_path_clone_for_closure = Arc::new(file_path.clone());
+    // 3. Search indexes, in the background
+    start_indexing_job(document, file_content, line_offset_index_arc, app_handle)?;
 
-    let get_line_content_closure = move |line_num_u32: u32| -> Option<String> {
-        if (line_num_u32 as usize) < loi_clone_for_closure.len() {
-            let line_info = &loi_clone_for_closure[line_num_u32 as usize]; 
-            
-            let mut file = match std::fs::File::open(file_path_clone_for_closure.as_ref()) {
-                Ok(f) => f,
-                Err(e) => {
-                    eprintln!("Closure: Failed to open file {:?}: {}", file_path_clone_for_closure, e);
-                    return None;
+    Ok(total_lines_count)
+}
+
+// Loads or builds the search indexes on a background thread. Any previous job must have been
+// stopped.
+fn start_indexing_job(document: &Arc<Document>, file_content: Arc<FileContent>, line_offsets: Arc<Vec<LineOffset>>, app_handle: &AppHandle) -> Result<(), String> {
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    let worker_cancel_flag = Arc::clone(&cancel_flag);
+    let worker_document = Arc::clone(document);
+    let worker_app_handle = app_handle.clone();
+    let handle = thread::Builder::new()
+        .name("indexing".to_string())
+        .spawn(move || {
+            if let Err(e) = build_search_indexes(&worker_document, &file_content, line_offsets, &worker_cancel_flag, &worker_app_handle) {
+                eprintln!("Indexing {} stopped: {}", file_content.path(), e);
+            }
+        })
+        .map_err(|e| format!("Failed to start indexing thread: {}", e))?;
+    *document.indexing_job.lock().map_err(|e| format!("Failed to lock indexing_job: {}", e))? = Some(IndexingJob { cancel_flag, handle });
+    Ok(())
+}
+
+// Drops the search indexes in which a lookup found a damaged posting list, deletes their
+// caches and starts building them again. Returns whether there were any, so the lookup can
+// be retried without them.
+fn discard_damaged_indexes(document: &Arc<Document>, app_handle: &AppHandle) -> bool {
+    let mut damaged = Vec::new();
+    for kind in SearchIndex::ALL {
+        match kind.slot(document).lock() {
+            Ok(mut slot) => {
+                if slot.take_if(|store| store.is_damaged()).is_some() {
+                    damaged.push(kind);
                 }
-            };
-            let mut buffer = vec![0; line_info.length];
-            
-            if let Err(e) = file.seek(SeekFrom::Start(line_info.offset)) {
-                eprintln!("Closure: Failed to seek in file: {}", e);
-                return None;
             }
-            if let Err(e) = file.read_exact(&mut buffer) {
-                eprintln!("Closure: Failed to read line from file: {}", e);
-                return None;
+            Err(e) => eprintln!("Failed to lock {}: {}", kind.name(), e),
+        }
+    }
+    if damaged.is_empty() {
+        return false;
+    }
+
+    let restarted = document_content(document).and_then(|file_content| {
+        for &kind in &damaged {
+            if let Err(e) = kind.remove_damaged(&file_content, &document.index_settings) {
+                eprintln!("Failed to delete the damaged {} cache: {}", kind.name(), e);
             }
-            String::from_utf8(buffer).ok()
-        } else {
-            eprintln!("Closure: Line number {} out of bounds (len: {})", line_num_u32, loi_clone_for_closure.len());
-            None
         }
-    };
-    
-    // 3. Inverted Index
-    set_status("Building inverted index...", 0.3, &app_state);
-    match cache_manager::load_inverted_index(&file_path) {
-        Ok(Some(cached_index)) => {
-            *app_state.inverted_index.lock().map_err(|e| format!("Failed to lock inverted_index: {}", e))? = Some(cached_index);
-            set_status("Loaded inverted index from cache.", 0.6, &app_state);
+        let line_offsets = match &*document.line_offset_index.lock().map_err(|e| format!("Failed to lock line_offset_index: {}", e))? {
+            Some(line_offsets) => Arc::clone(line_offsets),
+            None => return Err("Line offset index is not available.".to_string()),
+        };
+        stop_indexing_job(document);
+        set_status(document, &format!("Rebuilding the {}: its cache is damaged.", SearchIndex::describe(&damaged)), 0.3, IndexingStage::SearchIndexes, app_handle);
+        start_indexing_job(document, file_content, line_offsets, app_handle)
+    });
+    if let Err(e) = restarted {
+        eprintln!("Failed to rebuild damaged indexes of document {}: {}", document.id, e);
+    }
+    true
+}
+
+fn build_search_indexes(document: &Document, file_content: &FileContent, line_offsets: Arc<Vec<LineOffset>>, cancel_flag: &AtomicBool, app_handle: &AppHandle) -> Result<(), String> {
+    let total_lines_count = line_offsets.len();
+    let total_bytes_count = file_content.len();
+    let is_cancelled = || cancel_flag.load(Ordering::Relaxed);
+
+    // Every index installed below covers the lines complete in this content.
+    *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))? =
+        indexing_service::resume_point(&line_offsets, file_content.len(), file_content).line;
+
+    // Take whatever the cache has. Indexes of a file that has grown since are extended with
+    // the appended lines; the rest are built in a single pass over the file.
+    let mut missing = Vec::new();
+    let mut appended = Vec::new();
+    let mut known_over_limit = Vec::new();
+    let mut resume_from: Option<ResumePoint> = None;
+    for kind in SearchIndex::wanted(document) {
+        match kind.load(file_content, &document.index_settings) {
+            Ok(Some(cached)) if cached.indexed_size == file_content.len() => {
+                *kind.slot(document).lock().map_err(|e| format!("Failed to lock {}: {}", kind.name(), e))? = Some(cached.index);
+                set_status(document, &format!("Loaded {} from cache.", kind.name()), 0.3, IndexingStage::SearchIndexes, app_handle);
+            }
+            // Extending reads every list anyway, so they are all checked first.
+            Ok(Some(cached)) if !cached.index.is_intact() => {
+                if let Err(e) = kind.remove_damaged(file_content, &document.index_settings) {
+                    eprintln!("Failed to delete the damaged {} cache: {}", kind.name(), e);
+                }
+                missing.push(kind);
+            }
+            Ok(Some(cached)) => {
+                let from = indexing_service::resume_point(&line_offsets, cached.indexed_size, file_content);
+                resume_from = Some(resume_from.map_or(from, |other| other.min(from)));
+                appended.push((kind, cached.index));
+            }
+            _ if kind.known_over_limit(file_content, &document.index_settings) => known_over_limit.push(kind),
+            _ => missing.push(kind),
         }
-        _ => {
-            match indexing_service::build_inverted_index(total_lines_count, &get_line_content_closure) {
-                Ok(built_index) => {
-                    if let Err(e) = cache_manager::save_inverted_index(&file_path, &built_index) {
-                        eprintln!("Failed to save inverted index to cache: {}", e);
+    }
+    if !known_over_limit.is_empty() {
+        let message = format!("Skipping the {}: over the postings memory limit.", SearchIndex::describe(&known_over_limit));
+        set_status(document, &message, 0.3, IndexingStage::SearchIndexes, app_handle);
+    }
+
+    if is_cancelled() {
+        return Err(indexing_service::INDEXING_CANCELLED.to_string());
+    }
+
+    // Ok(None) when the pass failed for a reason other than cancellation; search then
+    // falls back to scanning.
+    let run_pass = |selection: IndexSelection, from: ResumePoint, postings_base: PostingsBytes, message: &str| -> Result<Option<BuiltIndexes>, String> {
+        let stage_progress = StageProgress::new(document, app_handle, IndexingStage::SearchIndexes, message, (0.3, 0.95), total_bytes_count, total_lines_count)
+            .resuming_from(from.offset);
+        match indexing_service::build_indexes(file_content, selection, &document.index_settings, from, postings_base, &is_cancelled, &|p| stage_progress.report(p)) {
+            Ok(built) => Ok(Some(built)),
+            Err(e) if is_cancelled() => Err(e),
+            Err(e) => {
+                eprintln!("Failed to build search indexes: {}. Proceeding without them.", e);
+                set_status(document, &format!("Failed to build search indexes: {}. Some search features may be unavailable.", e), 0.95, IndexingStage::SearchIndexes, app_handle);
+                Ok(None)
+            }
+        }
+    };
+
+    // Schema statistics of the complete lines are cached too. They are gathered by the full
+    // pass if there is one, or else extended from where the cached ones end.
+    let mut cached_schema = None;
+    if let Ok(Some(cached)) = cache_manager::load_schema_stats(file_content) {
+        cached_schema = Some((indexing_service::resume_point(&line_offsets, cached.indexed_size, file_content), cached.index));
+    }
+
+    if !missing.is_empty() || cached_schema.is_none() {
+        cached_schema = None;
+        let message = if missing.is_empty() {
+            "Gathering schema statistics...".to_string()
+        } else {
+            format!("Building {}...", SearchIndex::describe(&missing))
+        };
+        // A full pass parses every line anyway, so the schema statistics come along.
+        let selection = IndexSelection { schema: true, ..SearchIndex::selection(&missing) };
+        if let Some(mut built) = run_pass(selection, ResumePoint::default(), PostingsBytes::default(), &message)? {
+            let mut over_limit = Vec::new();
+            for kind in missing {
+                match kind.encode(&built, None).transpose()? {
+                    Some(store) => install_search_index(document, kind, store, file_content)?,
+                    None => {
+                        record_over_limit(document, kind, file_content, &mut built);
+                        over_limit.push(kind);
                     }
-                    *app_state.inverted_index.lock().map_err(|e| format!("Failed to lock inverted_index: {}", e))? = Some(built_index);
-                    set_status("Built inverted index.", 0.6, &app_state);
-                }
-                Err(e) => {
-                    eprintln!("Failed to build inverted index: {}. Proceeding without it.", e);
-                    set_status(&format!("Failed to build inverted index: {}. Some search features may be unavailable.", e), 0.6, &app_state);
                 }
             }
+            install_schema_stats(document, file_content, None, &mut built)?;
+            let message = if over_limit.is_empty() {
+                "Built search indexes.".to_string()
+            } else {
+                format!("Built search indexes without the {}: over the postings memory limit.", SearchIndex::describe(&over_limit))
+            };
+            set_status(document, &message, 0.95, IndexingStage::SearchIndexes, app_handle);
         }
     }
 
-    // 4. N-gram Index
-    set_status("Building N-gram index...", 0.65, &app_state);
-    match cache_manager::load_ngram_index(&file_path) {
-        Ok(Some(cached_index)) => {
-            *app_state.ngram_index.lock().map_err(|e| format!("Failed to lock ngram_index: {}", e))? = Some(cached_index);
-            set_status("Loaded N-gram index from cache.", 0.95, &app_state);
+    if let Some(from) = resume_from {
+        let kinds: Vec<SearchIndex> = appended.iter().map(|(kind, _)| *kind).collect();
+        // The schema statistics come along if they end where the indexes do.
+        let extend_schema = cached_schema.as_ref().is_some_and(|(schema_from, _)| *schema_from == from);
+        let selection = IndexSelection { schema: extend_schema, ..SearchIndex::selection(&kinds) };
+        // The cached postings count toward the memory limit along with the appended ones.
+        let mut postings_base = PostingsBytes::default();
+        for (kind, stale) in &appended {
+            if let Some(bytes) = kind.limited_bytes(&mut postings_base) {
+                *bytes = stale.as_bytes().len() as u64;
+            }
         }
-        _ => {
-            match indexing_service::build_ngram_index(total_lines_count, &get_line_content_closure) {
-                Ok(built_index) => {
-                    if let Err(e) = cache_manager::save_ngram_index(&file_path, &built_index) {
-                        eprintln!("Failed to save N-gram index to cache: {}", e);
+        if let Some(mut built) = run_pass(selection, from, postings_base, "Indexing appended lines...")? {
+            let mut over_limit = Vec::new();
+            for (kind, stale) in appended {
+                match kind.encode(&built, Some(&stale)) {
+                    Some(Ok(store)) => install_search_index(document, kind, store, file_content)?,
+                    Some(Err(e)) => eprintln!("Failed to extend {}: {}. Proceeding without it.", kind.name(), e),
+                    None => {
+                        record_over_limit(document, kind, file_content, &mut built);
+                        over_limit.push(kind);
                     }
-                    *app_state.ngram_index.lock().map_err(|e| format!("Failed to lock ngram_index: {}", e))? = Some(built_index);
-                    set_status("Built N-gram index.", 0.95, &app_state);
-                }
-                Err(e) => {
-                    eprintln!("Failed to build N-gram index: {}. Proceeding without it.", e);
-                    set_status(&format!("Failed to build N-gram index: {}. Some search features may be unavailable.", e), 0.95, &app_state);
                 }
             }
+            if let Some((_, previous)) = cached_schema.take_if(|_| extend_schema) {
+                install_schema_stats(document, file_content, Some(previous), &mut built)?;
+            }
+            let message = if over_limit.is_empty() {
+                "Indexed appended lines.".to_string()
+            } else {
+                format!("Indexed appended lines without the {}: over the postings memory limit.", SearchIndex::describe(&over_limit))
+            };
+            set_status(document, &message, 0.95, IndexingStage::SearchIndexes, app_handle);
         }
     }
 
-    set_status("Ready", 1.0, &app_state);
-    Ok(total_lines_count)
+    if let Some((from, previous)) = cached_schema {
+        // Nothing to add unless the file has grown or ends in an unterminated line.
+        let mut built = if from.offset == file_content.len() {
+            Some(BuiltIndexes::default())
+        } else {
+            run_pass(IndexSelection { schema: true, ..IndexSelection::default() }, from, PostingsBytes::default(), "Gathering schema statistics of appended lines...")?
+        };
+        if let Some(built) = built.as_mut() {
+            install_schema_stats(document, file_content, Some(previous), built)?;
+        }
+    }
+
+    set_status(document, "Ready", 1.0, IndexingStage::Ready, app_handle);
+    Ok(())
+}
+
+// Makes the schema statistics of a pass available, added to those of the lines before it if
+// given, and caches those of the complete lines if the pass gathered any.
+fn install_schema_stats(document: &Document, file_content: &FileContent, previous: Option<SchemaStats>, built: &mut BuiltIndexes) -> Result<(), String> {
+    let mut complete = previous.unwrap_or_default();
+    if let Some(schema) = built.schema.take() {
+        complete.merge(schema);
+        if let Err(e) = cache_manager::save_schema_stats(file_content, &complete) {
+            eprintln!("Failed to save schema statistics to cache: {}", e);
+        }
+    }
+    *document.schema_stats.lock().map_err(|e| format!("Failed to lock schema_stats: {}", e))? = Some(FileSchemaStats {
+        covered: indexing_service::complete_lines_size(file_content),
+        complete,
+        unterminated: built.unterminated_schema.take().unwrap_or_default(),
+    });
+    Ok(())
+}
+
+/// The postings-based search indexes of a document. They are cached, built in one pass and
+/// extended with appended lines alike.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SearchIndex {
+    Inverted,
+    NGram,
+    Positions,
+    FieldValues,
+}
+
+impl SearchIndex {
+    const ALL: [SearchIndex; 4] = [SearchIndex::Inverted, SearchIndex::NGram, SearchIndex::Positions, SearchIndex::FieldValues];
+
+    // The indexes built for `document`, as its settings choose.
+    fn wanted(document: &Document) -> Vec<SearchIndex> {
+        let settings = &document.index_settings;
+        SearchIndex::ALL.into_iter()
+            .filter(|&kind| match kind {
+                SearchIndex::Inverted => settings.inverted_index,
+                SearchIndex::NGram => settings.ngram_index,
+                SearchIndex::Positions => settings.position_index,
+                SearchIndex::FieldValues => true,
+            })
+            .collect()
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SearchIndex::Inverted => "inverted index",
+            SearchIndex::NGram => "N-gram index",
+            SearchIndex::Positions => "positional index",
+            SearchIndex::FieldValues => "field value index",
+        }
+    }
+
+    // E.g. "inverted index, N-gram index and positional index".
+    fn describe(kinds: &[SearchIndex]) -> String {
+        let names: Vec<&str> = kinds.iter().map(|kind| kind.name()).collect();
+        match names.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} and {}", rest.join(", "), last),
+            None => String::new(),
+        }
+    }
+
+    fn slot(self, document: &Document) -> &Mutex<Option<PostingsStore>> {
+        match self {
+            SearchIndex::Inverted => &document.inverted_index,
+            SearchIndex::NGram => &document.ngram_index,
+            SearchIndex::Positions => &document.position_index,
+            SearchIndex::FieldValues => &document.value_index,
+        }
+    }
+
+    fn selection(kinds: &[SearchIndex]) -> IndexSelection {
+        let mut selection = IndexSelection::default();
+        for kind in kinds {
+            match kind {
+                SearchIndex::Inverted => selection.inverted_index = true,
+                SearchIndex::NGram => selection.ngram_index = true,
+                SearchIndex::Positions => selection.position_index = true,
+                SearchIndex::FieldValues => selection.value_index = true,
+            }
+        }
+        selection
+    }
+
+    // Cache kind of the indexes the postings memory limit applies to.
+    fn limited_kind(self) -> Option<&'static str> {
+        match self {
+            SearchIndex::Inverted => Some("inverted"),
+            SearchIndex::NGram => Some("ngram"),
+            SearchIndex::Positions | SearchIndex::FieldValues => None,
+        }
+    }
+
+    fn limited_bytes(self, bytes: &mut PostingsBytes) -> Option<&mut u64> {
+        match self {
+            SearchIndex::Inverted => Some(&mut bytes.inverted),
+            SearchIndex::NGram => Some(&mut bytes.ngram),
+            SearchIndex::Positions | SearchIndex::FieldValues => None,
+        }
+    }
+
+    // Whether this index was dropped for outgrowing the postings memory limit on this content,
+    // or on the content it grew from, after reaching more than the current limit.
+    fn known_over_limit(self, file_content: &FileContent, settings: &IndexSettings) -> bool {
+        let (kind, limit) = match (self.limited_kind(), settings.max_postings_bytes) {
+            (Some(kind), Some(limit)) => (kind, limit),
+            _ => return false,
+        };
+        match cache_manager::load_postings_over_limit(file_content, kind, settings) {
+            Ok(reached) => reached.is_some_and(|reached| reached > limit),
+            Err(e) => {
+                eprintln!("Failed to load the {} over-limit record from cache: {}", self.name(), e);
+                false
+            }
+        }
+    }
+
+    fn load(self, file_content: &FileContent, settings: &IndexSettings) -> Result<Option<cache_manager::CachedIndex<PostingsStore>>, Box<dyn Error>> {
+        match self {
+            SearchIndex::Inverted => cache_manager::load_inverted_index(file_content, settings),
+            SearchIndex::NGram => cache_manager::load_ngram_index(file_content, settings),
+            SearchIndex::Positions => cache_manager::load_position_index(file_content, settings),
+            SearchIndex::FieldValues => cache_manager::load_value_index(file_content, settings),
+        }
+    }
+
+    // Deletes the cache this index was loaded from, found to hold a damaged posting list.
+    fn remove_damaged(self, file_content: &FileContent, settings: &IndexSettings) -> Result<(), Box<dyn Error>> {
+        let kind = match self {
+            SearchIndex::Inverted => "inverted",
+            SearchIndex::NGram => "ngram",
+            SearchIndex::Positions => "positions",
+            SearchIndex::FieldValues => "values",
+        };
+        cache_manager::remove_damaged_postings(file_content, kind, settings)
+    }
+
+    fn save(self, file_content: &FileContent, settings: &IndexSettings, store: &PostingsStore) -> Result<(), Box<dyn Error>> {
+        match self {
+            SearchIndex::Inverted => cache_manager::save_inverted_index(file_content, settings, store),
+            SearchIndex::NGram => cache_manager::save_ngram_index(file_content, settings, store),
+            SearchIndex::Positions => cache_manager::save_position_index(file_content, settings, store),
+            SearchIndex::FieldValues => cache_manager::save_value_index(file_content, settings, store),
+        }
+    }
+
+    // Encodes this index from a pass's output, replacing the lines it indexed in `stale` if
+    // given. None if the pass did not build it.
+    fn encode(self, built: &BuiltIndexes, stale: Option<&PostingsStore>) -> Option<Result<PostingsStore, String>> {
+        fn encode_index<K: AsRef<[u8]> + Eq + std::hash::Hash, L: PostingList>(index: Option<&HashMap<K, L>>, stale: Option<&PostingsStore>, from_line: u32) -> Option<Result<PostingsStore, String>> {
+            let index = index?;
+            Some(match stale {
+                Some(stale) => stale.extend(index, from_line),
+                None => Ok(PostingsStore::from_index(index)),
+            })
+        }
+        match self {
+            SearchIndex::Inverted => encode_index(built.inverted_index.as_ref(), stale, built.first_line),
+            SearchIndex::NGram => encode_index(built.ngram_index.as_ref(), stale, built.first_line),
+            SearchIndex::Positions => encode_index(built.position_index.as_ref(), stale, built.first_line),
+            SearchIndex::FieldValues => encode_index(built.value_index.as_ref(), stale, built.first_line),
+        }
+    }
 }
 
+// Remembers that `kind` was dropped by the pass that built `built` for outgrowing the postings
+// memory limit, so opening the file again skips it instead of building it only to drop it.
+fn record_over_limit(document: &Document, kind: SearchIndex, file_content: &FileContent, built: &mut BuiltIndexes) {
+    let (cache_kind, reached) = match (kind.limited_kind(), kind.limited_bytes(&mut built.postings_bytes)) {
+        (Some(cache_kind), Some(reached)) => (cache_kind, *reached),
+        _ => return,
+    };
+    if let Err(e) = cache_manager::save_postings_over_limit(file_content, cache_kind, &document.index_settings, reached) {
+        eprintln!("Failed to save the {} over-limit record to cache: {}", kind.name(), e);
+    }
+}
+
+// Makes a finished search index available to search and saves it to the cache.
+fn install_search_index(document: &Document, kind: SearchIndex, store: PostingsStore, file_content: &FileContent) -> Result<(), String> {
+    if let Err(e) = kind.save(file_content, &document.index_settings, &store) {
+        eprintln!("Failed to save {} to cache: {}", kind.name(), e);
+    }
+    *kind.slot(document).lock().map_err(|e| format!("Failed to lock {}: {}", kind.name(), e))? = Some(store);
+    Ok(())
+}
 
 #[tauri::command]
-pub fn get_total_lines(app_state: State<AppState>) -> Result<usize, String> {
-    let index_lock = app_state.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+pub fn get_total_lines(doc_id: DocId, app_state: State<AppState>) -> Result<usize, String> {
+    let document = app_state.document(doc_id)?;
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
     match &*index_lock {
         Some(index) => Ok(index.len()),
         None => Ok(0),
     }
 }
 
+/// Reads `count` lines from `start_line` on. Lines that are not valid UTF-8 are decoded per
+/// `decoding` and flagged rather than failing the batch; `hex` adds a view of the raw bytes.
 #[tauri::command]
-pub fn get_lines(start_line: usize, count: usize, app_state: State<AppState>) -> Result<Vec<String>, String> {
-    let file_path_lock = app_state.current_file_path.lock().map_err(|e| format!("Failed to lock file path state: {}", e))?;
-    let current_file_path = match &*file_path_lock {
-        Some(path) => path.clone(),
-        None => return Err("No file is currently open.".to_string()),
-    };
+pub fn get_lines(doc_id: DocId, start_line: usize, count: usize, decoding: Option<LineDecoding>, hex: Option<bool>, app_state: State<AppState>) -> Result<Vec<LineText>, String> {
+    let document = app_state.document(doc_id)?;
 
-    let index_lock = app_state.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
     let line_offsets = match &*index_lock {
         Some(index) => index,
         None => return Err("Line offset index is not available.".to_string()),
     };
+    let file_content = document_content(&document)?;
 
     if line_offsets.is_empty() {
         return Ok(Vec::new());
@@ -187,21 +963,12 @@ pub fn get_lines(start_line: usize, count: usize, app_state: State<AppState>) ->
     if start_line >= end_line {
          return Ok(Vec::new());
     }
-    
-    let mut file = File::open(&current_file_path)
-        .map_err(|e| format!("Failed to open file {}: {}", current_file_path, e))?;
 
     for i in start_line..end_line {
         if let Some(line_offset_info) = line_offsets.get(i) {
-            let mut buffer = vec![0; line_offset_info.length]; 
-            file.seek(SeekFrom::Start(line_offset_info.offset))
-                .map_err(|e| format!("Failed to seek in file: {}", e))?;
-            file.read_exact(&mut buffer)
-                .map_err(|e| format!("Failed to read line {} from file: {}", i, e))?;
-            
-            let line_content = String::from_utf8(buffer)
-                .map_err(|e| format!("Failed to decode line {} as UTF-8: {}", i, e))?;
-            lines.push(line_content);
+            let line_bytes = file_content.line(line_offset_info)
+                .ok_or_else(|| format!("Failed to read line {} from file: file is shorter than its index", i))?;
+            lines.push(line_text::decode_line(line_bytes, decoding.unwrap_or_default(), hex.unwrap_or(false)));
         } else {
             return Err(format!("Internal error: Attempted to access line index {} which is out of bounds after check.", i));
         }
@@ -209,15 +976,35 @@ pub fn get_lines(start_line: usize, count: usize, app_state: State<AppState>) ->
     Ok(lines)
 }
 
+/// Reads the given lines, e.g. the page of `query_file` results being displayed.
 #[tauri::command]
-pub fn get_line_content(line_number: usize, app_state: State<AppState>) -> Result<String, String> {
-    let file_path_lock = app_state.current_file_path.lock().map_err(|e| format!("Failed to lock file path state: {}", e))?;
-    let current_file_path = match &*file_path_lock {
-        Some(path) => path.clone(),
-        None => return Err("No file is currently open.".to_string()),
+pub fn get_lines_at(doc_id: DocId, line_numbers: Vec<usize>, decoding: Option<LineDecoding>, hex: Option<bool>, app_state: State<AppState>) -> Result<Vec<LineText>, String> {
+    let document = app_state.document(doc_id)?;
+
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let line_offsets = match &*index_lock {
+        Some(index) => index,
+        None => return Err("Line offset index is not available.".to_string()),
     };
+    let file_content = document_content(&document)?;
+
+    let mut lines = Vec::with_capacity(line_numbers.len());
+    for line_number in line_numbers {
+        let line_offset_info = line_offsets.get(line_number)
+            .ok_or_else(|| format!("Line number {} is out of bounds. Total lines: {}", line_number, line_offsets.len()))?;
+        let line_bytes = file_content.line(line_offset_info)
+            .ok_or_else(|| format!("Failed to read line {} from file: file is shorter than its index", line_number))?;
+        lines.push(line_text::decode_line(line_bytes, decoding.unwrap_or_default(), hex.unwrap_or(false)));
+    }
+    Ok(lines)
+}
+
+#[tauri::command]
+pub fn get_line_content(doc_id: DocId, line_number: usize, decoding: Option<LineDecoding>, hex: Option<bool>, app_state: State<AppState>) -> Result<LineText, String> {
+    let document = app_state.document(doc_id)?;
 
-    let index_lock = app_state.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let file_content = document_content(&document)?;
     let line_offset_info = match &*index_lock {
         Some(index) => {
             if line_number >= index.len() {
@@ -229,25 +1016,677 @@ pub fn get_line_content(line_number: usize, app_state: State<AppState>) -> Resul
     };
 
     if let Some(info) = line_offset_info {
-        let mut file = File::open(&current_file_path)
-            .map_err(|e| format!("Failed to open file {}: {}", current_file_path, e))?;
-        
-        let mut buffer = vec![0; info.length];
-        file.seek(SeekFrom::Start(info.offset))
-            .map_err(|e| format!("Failed to seek in file: {}", e))?;
-        file.read_exact(&mut buffer)
-            .map_err(|e| format!("Failed to read line {} from file: {}", line_number, e))?;
-        
-        String::from_utf8(buffer)
-            .map_err(|e| format!("Failed to decode line {} as UTF-8: {}", line_number, e))
+        let line_bytes = file_content.line(info)
+            .ok_or_else(|| format!("Failed to read line {} from file: file is shorter than its index", line_number))?;
+        Ok(line_text::decode_line(line_bytes, decoding.unwrap_or_default(), hex.unwrap_or(false)))
     } else {
         Err(format!("Could not retrieve line offset info for line {}.", line_number))
     }
 }
 
+// The mapped content of the document's file, shared with the indexer and search. Fails
+// rather than hand out a mapping whose file has been truncated in place, until following
+// reopens it. Taken while holding the line offset index lock where the two are used
+// together, which is also how following swaps them, so a followed file's appended lines
+// never come with a mapping that lacks them.
+fn document_content(document: &Document) -> Result<Arc<FileContent>, String> {
+    let content = mapped_content(document)?;
+    content.check_not_truncated()?;
+    Ok(content)
+}
+
+fn mapped_content(document: &Document) -> Result<Arc<FileContent>, String> {
+    match &*document.file_content.lock().map_err(|e| format!("Failed to lock file_content: {}", e))? {
+        Some(content) => Ok(Arc::clone(content)),
+        None => Err(format!("The file of document {} is not available.", document.id)),
+    }
+}
+
+#[tauri::command]
+pub fn get_indexing_status(doc_id: DocId, app_state: State<AppState>) -> Result<IndexingStatus, String> {
+    let document = app_state.document(doc_id)?;
+    let status = document.indexing_status.lock().map_err(|e| format!("Failed to lock indexing_status: {}", e))?;
+    Ok(status.clone())
+}
+
+/// Turns full-hash cache verification on or off for the files opened from now on, and saves
+/// the choice. Caches are fresh when the file's size, mtime and inode are unchanged, and
+/// otherwise only if all its bytes hash the same, which needs caches saved with verification
+/// on; with `full_hash`, every byte is hashed on each open and each save.
+#[tauri::command]
+pub fn set_cache_verification(full_hash: bool) -> Result<(), String> {
+    cache_manager::set_full_hash_verification(full_hash)
+}
+
+/// Lists the cached indexes by source file, with their kinds, sizes and when they were last
+/// used, and the total size of the cache directory against its limit.
+#[tauri::command]
+pub fn list_caches() -> Result<cache_manager::CacheListing, String> {
+    cache_manager::list_caches()
+}
+
+/// Deletes the cached indexes of `file_path` that no other path shares; an open document
+/// keeps the indexes it has loaded. Returns the bytes freed.
+#[tauri::command]
+pub fn clear_cache(file_path: String) -> Result<u64, String> {
+    cache_manager::clear_cache(&file_path)
+}
+
+#[tauri::command]
+pub fn clear_all_caches() -> Result<u64, String> {
+    cache_manager::clear_all_caches()
+}
+
+/// Sets the most the cache directory may hold; least recently used caches are deleted to
+/// stay within it.
 #[tauri::command]
-pub fn get_indexing_status(app_state: State<AppState>) -> Result<IndexingStatus, String> {
-    let message = app_state.indexing_status_message.lock().map_err(|e| format!("Failed to lock indexing_status_message: {}", e))?.clone();
-    let progress = *app_state.indexing_progress.lock().map_err(|e| format!("Failed to lock indexing_progress: {}", e))?;
-    Ok(IndexingStatus { message, progress })
+pub fn set_cache_limit(max_total_bytes: u64) -> Result<(), String> {
+    cache_manager::set_max_total_bytes(max_total_bytes)
+}
+
+/// Searches the document. A search index found damaged on the way is dropped, to be built
+/// again, and the search answered without it.
+#[tauri::command]
+pub fn search_file(doc_id: DocId, query: String, is_regex: bool, case_sensitive: bool, app_handle: AppHandle) -> Result<SearchResults, String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+
+    if query.is_empty() {
+        return Err("Search query is empty.".to_string());
+    }
+
+    let search_query = SearchQuery::new(&query, is_regex, case_sensitive);
+    let regex = search_query.compile()?;
+    match search_document(&document, &search_query, &regex) {
+        Err(_) if discard_damaged_indexes(&document, &app_handle) => search_document(&document, &search_query, &regex),
+        results => results,
+    }
+}
+
+fn search_document(document: &Document, search_query: &SearchQuery, regex: &Regex) -> Result<SearchResults, String> {
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let line_offsets = match &*index_lock {
+        Some(index) => index,
+        None => return Err("Line offset index is not available.".to_string()),
+    };
+    let file_content = document_content(document)?;
+
+    let inverted_lock = document.inverted_index.lock().map_err(|e| format!("Failed to lock inverted_index: {}", e))?;
+    let ngram_lock = document.ngram_index.lock().map_err(|e| format!("Failed to lock ngram_index: {}", e))?;
+
+    let planner = SearchPlanner {
+        inverted_index: inverted_lock.as_ref(),
+        ngram_index: ngram_lock.as_ref(),
+        settings: &document.index_settings,
+    };
+    let indexed_lines = *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))?;
+    let plan = planner.plan(search_query)?.including_unindexed(indexed_lines, line_offsets.len());
+
+    let executor = SearchExecutor {
+        content: &file_content,
+        line_offsets,
+    };
+    executor.execute(&plan, regex)
+}
+
+/// Finds the lines matching a structured query such as `role:user AND NOT (error OR warn)`,
+/// see `query_parser`. Only line numbers and counts come back; the lines themselves are
+/// read with `get_lines_at` for the page being displayed. Damaged search indexes are dropped
+/// as in `search_file`.
+#[tauri::command]
+pub fn query_file(doc_id: DocId, query: String, app_handle: AppHandle) -> Result<QueryResults, String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    let parsed_query = query_parser::parse(&query)?;
+    match query_document(&document, &parsed_query) {
+        Err(_) if discard_damaged_indexes(&document, &app_handle) => query_document(&document, &parsed_query),
+        results => results,
+    }
+}
+
+fn query_document(document: &Document, parsed_query: &Query) -> Result<QueryResults, String> {
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let line_offsets = match &*index_lock {
+        Some(index) => index,
+        None => return Err("Line offset index is not available.".to_string()),
+    };
+    let file_content = document_content(document)?;
+    let inverted_lock = document.inverted_index.lock().map_err(|e| format!("Failed to lock inverted_index: {}", e))?;
+    let position_lock = document.position_index.lock().map_err(|e| format!("Failed to lock position_index: {}", e))?;
+    let value_lock = document.value_index.lock().map_err(|e| format!("Failed to lock value_index: {}", e))?;
+    let indexed_lines = *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))?;
+
+    let evaluator = QueryEvaluator {
+        inverted_index: inverted_lock.as_ref(),
+        position_index: position_lock.as_ref(),
+        value_index: value_lock.as_ref(),
+        settings: &document.index_settings,
+        indexed_lines,
+        content: &file_content,
+        line_offsets,
+    };
+    evaluator.evaluate(parsed_query)
+}
+
+/// Reports the shape of the document's JSON lines: per JSON path the types seen, how often
+/// it is present, null counts, examples and lengths, plus a JSON Schema of it all. Uses the
+/// statistics gathered while indexing, which are cached and follow appended lines, so it
+/// fails until the first indexing pass is done.
+#[tauri::command]
+pub fn infer_schema(doc_id: DocId, app_state: State<AppState>) -> Result<SchemaReport, String> {
+    let document = app_state.document(doc_id)?;
+    schema_report(&document)
+}
+
+/// Writes the inferred JSON Schema of the document to `target_path`.
+#[tauri::command]
+pub fn export_schema(doc_id: DocId, target_path: String, app_state: State<AppState>) -> Result<(), String> {
+    let document = app_state.document(doc_id)?;
+    let report = schema_report(&document)?;
+    let schema = serde_json::to_string_pretty(&report.json_schema)
+        .map_err(|e| format!("Failed to serialize schema: {}", e))?;
+    fs::write(&target_path, schema).map_err(|e| format!("Failed to write {}: {}", target_path, e))
+}
+
+fn schema_report(document: &Document) -> Result<SchemaReport, String> {
+    let total_lines = document.line_offset_index.lock()
+        .map_err(|e| format!("Failed to lock index state: {}", e))?
+        .as_ref()
+        .map_or(0, |line_offsets| line_offsets.len());
+
+    match &*document.schema_stats.lock().map_err(|e| format!("Failed to lock schema_stats: {}", e))? {
+        Some(schema) => Ok(schema.report(total_lines)),
+        None => Err("Schema statistics are not available yet; they are gathered while the file is indexed.".to_string()),
+    }
+}
+
+/// Validates every line of the document against the JSON Schema at `schema_path` on a
+/// background thread, replacing the results of any earlier run. Progress and the final
+/// counts arrive as `validation_status_update` events; the violations are paged through
+/// with `get_validation_results`, also while the run is still going.
+#[tauri::command]
+pub fn validate_file(doc_id: DocId, schema_path: String, app_handle: AppHandle) -> Result<(), String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    stop_validation_job(&document);
+    let validator = schema_validation::compile_schema(&schema_path)?;
+    let file_content = document_content(&document)?;
+    let total_lines = document.line_offset_index.lock()
+        .map_err(|e| format!("Failed to lock index state: {}", e))?
+        .as_ref()
+        .map_or(0, |line_offsets| line_offsets.len());
+
+    let results = ValidationResults::new(document.id, &schema_path, total_lines);
+    publish_validation_status(&results, &app_handle);
+    *document.validation.lock().map_err(|e| format!("Failed to lock validation: {}", e))? = Some(results);
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    let worker_cancel_flag = Arc::clone(&cancel_flag);
+    let worker_document = Arc::clone(&document);
+    let handle = thread::Builder::new()
+        .name("validation".to_string())
+        .spawn(move || {
+            let is_cancelled = || worker_cancel_flag.load(Ordering::Relaxed);
+            let mut last_emit: Option<Instant> = None;
+            let outcome = indexing_service::scan_lines(
+                &file_content,
+                ResumePoint::default(),
+                &is_cancelled,
+                &|violations: &mut Vec<Violation>, line_num, content| schema_validation::check_line(&validator, line_num, content, violations),
+                &mut |violations, first_line, line_count, _| {
+                    with_validation(&worker_document, |results| {
+                        results.add_chunk(violations, first_line, line_count);
+                        let now = Instant::now();
+                        if last_emit.is_none_or(|last| now.duration_since(last) >= STATUS_EMIT_INTERVAL) {
+                            last_emit = Some(now);
+                            publish_validation_status(results, &app_handle);
+                        }
+                    });
+                },
+            );
+            with_validation(&worker_document, |results| {
+                match outcome {
+                    Ok(()) if is_cancelled() => results.finish(ValidationState::Cancelled, "Validation cancelled."),
+                    Ok(()) => {
+                        let message = format!("{} violations on {} lines.", results.status.total_violations, results.status.invalid_lines);
+                        results.finish(ValidationState::Done, &message);
+                    }
+                    Err(e) => {
+                        eprintln!("Validating document {} failed: {}", worker_document.id, e);
+                        results.finish(ValidationState::Failed, &format!("Error: {}", e));
+                    }
+                }
+                publish_validation_status(results, &app_handle);
+            });
+        })
+        .map_err(|e| format!("Failed to start validation thread: {}", e))?;
+    *document.validation_job.lock().map_err(|e| format!("Failed to lock validation_job: {}", e))? = Some(IndexingJob { cancel_flag, handle });
+    Ok(())
+}
+
+// Runs `f` on the document's validation results, if a run has not been cleared meanwhile.
+fn with_validation(document: &Document, f: impl FnOnce(&mut ValidationResults)) {
+    match document.validation.lock() {
+        Ok(mut lock) => {
+            if let Some(results) = lock.as_mut() {
+                f(results);
+            }
+        }
+        Err(e) => eprintln!("Failed to lock validation: {}", e),
+    }
+}
+
+fn publish_validation_status(results: &ValidationResults, app_handle: &AppHandle) {
+    if let Err(e) = app_handle.emit_all(VALIDATION_STATUS_EVENT, &results.status) {
+        eprintln!("Failed to emit {}: {}", VALIDATION_STATUS_EVENT, e);
+    }
+}
+
+/// Returns `count` violations of the latest validation run from index `offset` on, with the
+/// run's status and per-rule counts.
+#[tauri::command]
+pub fn get_validation_results(doc_id: DocId, offset: usize, count: usize, app_state: State<AppState>) -> Result<ValidationPage, String> {
+    let document = app_state.document(doc_id)?;
+    let lock = document.validation.lock().map_err(|e| format!("Failed to lock validation: {}", e))?;
+    let results = lock.as_ref().ok_or_else(|| format!("Document {} has not been validated.", doc_id))?;
+    let start = offset.min(results.violations.len());
+    let end = start.saturating_add(count).min(results.violations.len());
+    Ok(ValidationPage {
+        status: results.status.clone(),
+        offset: start,
+        violations: results.violations[start..end].to_vec(),
+    })
+}
+
+#[tauri::command]
+pub fn cancel_validation(doc_id: DocId, app_state: State<AppState>) -> Result<(), String> {
+    let document = app_state.document(doc_id)?;
+    stop_validation_job(&document);
+    Ok(())
+}
+
+/// Scans the document on a background thread for lines that are not well-formed JSON:
+/// invalid UTF-8, truncated values, trailing garbage after the value, or objects with a key
+/// twice, each with the offending byte range. Replaces the results of any earlier scan.
+/// Progress and counts arrive as `invalid_lines_status_update` events; the lines are paged
+/// through with `get_invalid_lines`, also while the scan is still going.
+#[tauri::command]
+pub fn scan_invalid_lines(doc_id: DocId, app_handle: AppHandle) -> Result<(), String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    stop_invalid_lines_job(&document);
+    let file_content = document_content(&document)?;
+    let total_lines = document.line_offset_index.lock()
+        .map_err(|e| format!("Failed to lock index state: {}", e))?
+        .as_ref()
+        .map_or(0, |line_offsets| line_offsets.len());
+
+    let scan = InvalidLinesScan::new(document.id, total_lines);
+    publish_invalid_lines_status(&scan, &app_handle);
+    *document.invalid_lines.lock().map_err(|e| format!("Failed to lock invalid_lines: {}", e))? = Some(scan);
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    let worker_cancel_flag = Arc::clone(&cancel_flag);
+    let worker_document = Arc::clone(&document);
+    let handle = thread::Builder::new()
+        .name("invalid-lines".to_string())
+        .spawn(move || {
+            let is_cancelled = || worker_cancel_flag.load(Ordering::Relaxed);
+            let mut last_emit: Option<Instant> = None;
+            let outcome = indexing_service::scan_lines(
+                &file_content,
+                ResumePoint::default(),
+                &is_cancelled,
+                &|lines: &mut Vec<InvalidLine>, line_num, content| lines.extend(line_repair::check_line(line_num, content)),
+                &mut |lines, first_line, line_count, _| {
+                    with_invalid_lines(&worker_document, |scan| {
+                        scan.add_chunk(lines, first_line, line_count);
+                        let now = Instant::now();
+                        if last_emit.is_none_or(|last| now.duration_since(last) >= STATUS_EMIT_INTERVAL) {
+                            last_emit = Some(now);
+                            publish_invalid_lines_status(scan, &app_handle);
+                        }
+                    });
+                },
+            );
+            with_invalid_lines(&worker_document, |scan| {
+                match outcome {
+                    Ok(()) if is_cancelled() => scan.finish(ScanState::Cancelled, "Scan cancelled."),
+                    Ok(()) => {
+                        let message = format!("{} invalid lines.", scan.status.invalid_count);
+                        scan.finish(ScanState::Done, &message);
+                    }
+                    Err(e) => {
+                        eprintln!("Scanning document {} for invalid lines failed: {}", worker_document.id, e);
+                        scan.finish(ScanState::Failed, &format!("Error: {}", e));
+                    }
+                }
+                publish_invalid_lines_status(scan, &app_handle);
+            });
+        })
+        .map_err(|e| format!("Failed to start invalid line scan thread: {}", e))?;
+    *document.invalid_lines_job.lock().map_err(|e| format!("Failed to lock invalid_lines_job: {}", e))? = Some(IndexingJob { cancel_flag, handle });
+    Ok(())
+}
+
+// Runs `f` on the document's invalid line scan, if it has not been cleared meanwhile.
+fn with_invalid_lines(document: &Document, f: impl FnOnce(&mut InvalidLinesScan)) {
+    match document.invalid_lines.lock() {
+        Ok(mut lock) => {
+            if let Some(scan) = lock.as_mut() {
+                f(scan);
+            }
+        }
+        Err(e) => eprintln!("Failed to lock invalid_lines: {}", e),
+    }
+}
+
+fn publish_invalid_lines_status(scan: &InvalidLinesScan, app_handle: &AppHandle) {
+    if let Err(e) = app_handle.emit_all(INVALID_LINES_STATUS_EVENT, &scan.status) {
+        eprintln!("Failed to emit {}: {}", INVALID_LINES_STATUS_EVENT, e);
+    }
+}
+
+/// Returns `count` invalid lines of the latest scan from index `offset` on, with the scan's
+/// status and per-problem counts.
+#[tauri::command]
+pub fn get_invalid_lines(doc_id: DocId, offset: usize, count: usize, app_state: State<AppState>) -> Result<InvalidLinesPage, String> {
+    let document = app_state.document(doc_id)?;
+    let lock = document.invalid_lines.lock().map_err(|e| format!("Failed to lock invalid_lines: {}", e))?;
+    let scan = lock.as_ref().ok_or_else(|| format!("Document {} has not been scanned for invalid lines.", doc_id))?;
+    let start = offset.min(scan.lines.len());
+    let end = start.saturating_add(count).min(scan.lines.len());
+    Ok(InvalidLinesPage {
+        status: scan.status.clone(),
+        offset: start,
+        lines: scan.lines[start..end].to_vec(),
+    })
+}
+
+#[tauri::command]
+pub fn cancel_invalid_lines_scan(doc_id: DocId, app_state: State<AppState>) -> Result<(), String> {
+    let document = app_state.document(doc_id)?;
+    stop_invalid_lines_job(&document);
+    Ok(())
+}
+
+/// Writes a copy of the document to `target_path` with `action` applied to the given lines,
+/// or to every line the latest finished `scan_invalid_lines` run found if none are given.
+/// Runs on a background thread that reports through `indexing_status_update` events and
+/// sends the counts in a `repair_finished` event; `cancel_repair` stops it. The document
+/// itself is left unchanged; open the copy to continue with it.
+#[tauri::command]
+pub fn repair_lines(doc_id: DocId, action: RepairAction, line_numbers: Option<Vec<u32>>, target_path: String, app_handle: AppHandle) -> Result<(), String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    let line_numbers = match line_numbers {
+        Some(mut line_numbers) => {
+            line_numbers.sort_unstable();
+            line_numbers.dedup();
+            line_numbers
+        }
+        None => match &*document.invalid_lines.lock().map_err(|e| format!("Failed to lock invalid_lines: {}", e))? {
+            Some(scan) if scan.status.state == ScanState::Done => scan.line_numbers.clone(),
+            Some(scan) if scan.status.state == ScanState::Running => return Err("The scan for invalid lines is still running.".to_string()),
+            _ => return Err("Scan the document for invalid lines first, or give the lines to repair.".to_string()),
+        },
+    };
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let line_offsets = match &*index_lock {
+        Some(line_offsets) => Arc::clone(line_offsets),
+        None => return Err("Line offset index is not available.".to_string()),
+    };
+    let file_content = document_content(&document)?;
+    drop(index_lock);
+    if fs::canonicalize(&target_path).ok() == fs::canonicalize(file_content.path()).ok() {
+        return Err("Repaired lines must be written to a new file.".to_string());
+    }
+
+    stop_repair_job(&document);
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    let worker_cancel_flag = Arc::clone(&cancel_flag);
+    let worker_document = Arc::clone(&document);
+    let handle = thread::Builder::new()
+        .name("repair".to_string())
+        .spawn(move || {
+            let is_cancelled = || worker_cancel_flag.load(Ordering::Relaxed);
+            let stage_progress = StageProgress::new(&worker_document, &app_handle, IndexingStage::Repairing, "Repairing lines...", (0.0, 1.0), file_content.len(), line_offsets.len());
+            match line_repair::write_repaired(&file_content, &line_offsets, &line_numbers, action, &target_path, &is_cancelled, &|p| stage_progress.report(p)) {
+                Ok(summary) => {
+                    if let Err(e) = cache_manager::invalidate_caches(&target_path) {
+                        eprintln!("Failed to invalidate caches of {}: {}", target_path, e);
+                    }
+                    set_status(&worker_document, &format!("Wrote repaired lines to {}.", target_path), 1.0, IndexingStage::Ready, &app_handle);
+                    let finished = RepairFinished { doc_id: worker_document.id, summary };
+                    if let Err(e) = app_handle.emit_all(REPAIR_FINISHED_EVENT, finished) {
+                        eprintln!("Failed to emit {}: {}", REPAIR_FINISHED_EVENT, e);
+                    }
+                }
+                Err(_) if is_cancelled() => set_status(&worker_document, "Repair cancelled.", 1.0, IndexingStage::Cancelled, &app_handle),
+                Err(e) => {
+                    eprintln!("Repairing lines of document {} failed: {}", worker_document.id, e);
+                    set_status(&worker_document, &format!("Error: {}", e), 0.0, IndexingStage::Failed, &app_handle);
+                }
+            }
+        })
+        .map_err(|e| format!("Failed to start repair thread: {}", e))?;
+    *document.repair_job.lock().map_err(|e| format!("Failed to lock repair_job: {}", e))? = Some(IndexingJob { cancel_flag, handle });
+    Ok(())
+}
+
+#[tauri::command]
+pub fn cancel_repair(doc_id: DocId, app_state: State<AppState>) -> Result<(), String> {
+    let document = app_state.document(doc_id)?;
+    stop_repair_job(&document);
+    Ok(())
+}
+
+// Replace All output is written next to the source so saving can be a same-filesystem rename.
+// The document ID keeps two documents of the same file apart.
+fn replace_temp_path(file_path: &str, doc_id: DocId) -> String {
+    format!("{}.dolphin-replace-{}.tmp", file_path, doc_id)
+}
+
+fn discard_pending_replace(document: &Document) {
+    let pending = match document.pending_replace.lock() {
+        Ok(mut lock) => lock.take(),
+        Err(e) => {
+            eprintln!("Failed to lock pending_replace: {}", e);
+            None
+        }
+    };
+    if let Some(pending) = pending {
+        if let Err(e) = fs::remove_file(&pending.temp_path) {
+            eprintln!("Failed to remove replace output {}: {}", pending.temp_path, e);
+        }
+    }
+}
+
+/// Replaces every match of `find_query` on a background thread, streaming the file with the
+/// replacements to a temp file next to it. Progress arrives as `indexing_status_update`
+/// events and the counts as a `replace_finished` event. The output is kept until
+/// `save_replaced`, `save_replaced_as` or `discard_replaced`; `cancel_replace` stops the run.
+#[tauri::command]
+pub fn replace_all_in_file(doc_id: DocId, find_query: String, replace_with: String, is_regex: bool, case_sensitive: bool, app_handle: AppHandle) -> Result<(), String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    if find_query.is_empty() {
+        return Err("Search query is empty.".to_string());
+    }
+    let search_query = SearchQuery::new(&find_query, is_regex, case_sensitive);
+    let regex = search_query.compile()?;
+
+    stop_replace_job(&document);
+    discard_pending_replace(&document);
+    let index_lock = document.line_offset_index.lock().map_err(|e| format!("Failed to lock index state: {}", e))?;
+    let line_offsets = match &*index_lock {
+        Some(line_offsets) => Arc::clone(line_offsets),
+        None => return Err("Line offset index is not available.".to_string()),
+    };
+    let file_content = document_content(&document)?;
+    drop(index_lock);
+
+    let cancel_flag = Arc::new(AtomicBool::new(false));
+    let worker_cancel_flag = Arc::clone(&cancel_flag);
+    let worker_document = Arc::clone(&document);
+    let handle = thread::Builder::new()
+        .name("replace".to_string())
+        .spawn(move || {
+            let is_cancelled = || worker_cancel_flag.load(Ordering::Relaxed);
+            let replacement = Replacement { regex: &regex, with: replace_with.as_bytes(), literal: !is_regex };
+            let replace = || replace_all(&worker_document, &file_content, &line_offsets, &search_query, &replacement, &is_cancelled, &app_handle);
+            let result = match replace() {
+                Err(_) if !is_cancelled() && discard_damaged_indexes(&worker_document, &app_handle) => replace(),
+                result => result,
+            };
+            match result {
+                Ok(summary) => {
+                    let finished = ReplaceFinished { doc_id: worker_document.id, summary };
+                    if let Err(e) = app_handle.emit_all(REPLACE_FINISHED_EVENT, finished) {
+                        eprintln!("Failed to emit {}: {}", REPLACE_FINISHED_EVENT, e);
+                    }
+                }
+                Err(_) if is_cancelled() => set_status(&worker_document, "Replace cancelled.", 1.0, IndexingStage::Cancelled, &app_handle),
+                Err(e) => {
+                    eprintln!("Replace All in document {} failed: {}", worker_document.id, e);
+                    set_status(&worker_document, &format!("Error: {}", e), 0.0, IndexingStage::Failed, &app_handle);
+                }
+            }
+        })
+        .map_err(|e| format!("Failed to start replace thread: {}", e))?;
+    *document.replace_job.lock().map_err(|e| format!("Failed to lock replace_job: {}", e))? = Some(IndexingJob { cancel_flag, handle });
+    Ok(())
+}
+
+#[tauri::command]
+pub fn cancel_replace(doc_id: DocId, app_state: State<AppState>) -> Result<(), String> {
+    let document = app_state.document(doc_id)?;
+    stop_replace_job(&document);
+    Ok(())
+}
+
+// Writes the Replace All output for `file_content` and keeps it as the document's pending
+// replace. The search indexes are only locked while the candidate lines are planned.
+fn replace_all(document: &Document, file_content: &FileContent, line_offsets: &[LineOffset], search_query: &SearchQuery, replacement: &Replacement, is_cancelled: &dyn Fn() -> bool, app_handle: &AppHandle) -> Result<ReplaceSummary, String> {
+    let plan = {
+        let inverted_lock = document.inverted_index.lock().map_err(|e| format!("Failed to lock inverted_index: {}", e))?;
+        let ngram_lock = document.ngram_index.lock().map_err(|e| format!("Failed to lock ngram_index: {}", e))?;
+        let planner = SearchPlanner {
+            inverted_index: inverted_lock.as_ref(),
+            ngram_index: ngram_lock.as_ref(),
+            settings: &document.index_settings,
+        };
+        let indexed_lines = *document.search_indexed_lines.lock().map_err(|e| format!("Failed to lock search_indexed_lines: {}", e))?;
+        planner.plan(search_query)?.including_unindexed(indexed_lines, line_offsets.len())
+    };
+
+    let executor = SearchExecutor {
+        content: file_content,
+        line_offsets,
+    };
+    let temp_path = replace_temp_path(file_content.path(), document.id);
+    let stage_progress = StageProgress::new(document, app_handle, IndexingStage::Replacing, "Replacing...", (0.0, 1.0), total_bytes(line_offsets), line_offsets.len());
+    let output = match executor.replace_into(&plan, replacement, &temp_path, is_cancelled, &|p| stage_progress.report(p)) {
+        Ok(output) => output,
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+    };
+
+    *document.pending_replace.lock().map_err(|e| format!("Failed to lock pending_replace: {}", e))? = Some(PendingReplace {
+        source_path: file_content.path().to_string(),
+        temp_path,
+        changed_line_lengths: output.changed_line_lengths,
+        line_count_preserved: output.line_count_preserved,
+    });
+    set_status(document, "Replace complete. Save to keep the changes.", 1.0, IndexingStage::Ready, app_handle);
+    Ok(output.summary)
+}
+
+#[tauri::command]
+pub fn save_replaced(doc_id: DocId, app_handle: AppHandle) -> Result<usize, String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    let pending = document.pending_replace.lock().map_err(|e| format!("Failed to lock pending_replace: {}", e))?
+        .take()
+        .ok_or_else(|| "There are no replaced changes to save.".to_string())?;
+    let target_path = pending.source_path.clone();
+    persist_replaced(&document, pending, &target_path, &app_handle)
+}
+
+#[tauri::command]
+pub fn save_replaced_as(doc_id: DocId, target_path: String, app_handle: AppHandle) -> Result<usize, String> {
+    let document = app_handle.state::<AppState>().document(doc_id)?;
+    let pending = document.pending_replace.lock().map_err(|e| format!("Failed to lock pending_replace: {}", e))?
+        .take()
+        .ok_or_else(|| "There are no replaced changes to save.".to_string())?;
+    persist_replaced(&document, pending, &target_path, &app_handle)
+}
+
+#[tauri::command]
+pub fn discard_replaced(doc_id: DocId, app_state: State<AppState>) -> Result<(), String> {
+    let document = app_state.document(doc_id)?;
+    discard_pending_replace(&document);
+    Ok(())
+}
+
+// Moves the Replace All output to `target_path` and reopens the document from it. When no line
+// breaks were added or removed, the line offset index is remapped instead of rescanned.
+fn persist_replaced(document: &Arc<Document>, pending: PendingReplace, target_path: &str, app_handle: &AppHandle) -> Result<usize, String> {
+    // The indexer may still be reading the file that is about to be replaced.
+    stop_indexing_job(document);
+    stop_follow_job(document);
+    // Unmap it as well; Windows refuses to replace a file that is still mapped.
+    *document.file_content.lock().map_err(|e| format!("Failed to lock file_content: {}", e))? = None;
+    set_status(document, "Saving...", 0.0, IndexingStage::Saving, app_handle);
+    if let Err(e) = move_into_place(&pending.temp_path, target_path, document.id) {
+        set_status(document, &format!("Error: {}", e), 0.0, IndexingStage::Failed, app_handle);
+        if let (Ok(content), Ok(mut lock)) = (FileContent::open(&pending.source_path), document.file_content.lock()) {
+            *lock = Some(Arc::new(content));
+        }
+        // Keep the output around so the user can retry, e.g. with Save As.
+        if let Ok(mut lock) = document.pending_replace.lock() {
+            *lock = Some(pending);
+        }
+        return Err(e);
+    }
+    // The target's caches describe its old content, which may have had the same size.
+    if let Err(e) = cache_manager::invalidate_caches(target_path) {
+        eprintln!("Failed to invalidate caches of {}: {}", target_path, e);
+    }
+
+    if pending.line_count_preserved {
+        let remapped = document.line_offset_index.lock()
+            .map_err(|e| format!("Failed to lock line_offset_index: {}", e))?
+            .take();
+        if let Some(offsets) = remapped {
+            let mut offsets = Arc::try_unwrap(offsets).unwrap_or_else(|shared| shared.as_ref().clone());
+            indexing_service::remap_line_offsets(&mut offsets, &pending.changed_line_lengths);
+            let saved = FileContent::open(target_path)
+                .map_err(|e| e.into())
+                .and_then(|content| cache_manager::save_line_offset_index(&content, &offsets));
+            if let Err(e) = saved {
+                eprintln!("Failed to save remapped line offset index to cache: {}", e);
+            }
+        }
+    }
+
+    index_file(document, target_path, app_handle)
+}
+
+fn move_into_place(temp_path: &str, target_path: &str, doc_id: DocId) -> Result<(), String> {
+    if fs::rename(temp_path, target_path).is_ok() {
+        return Ok(());
+    }
+    // Most likely a different filesystem: copy next to the target first so the
+    // final step is still an atomic rename.
+    let staging_path = replace_temp_path(target_path, doc_id);
+    fs::copy(temp_path, &staging_path)
+        .and_then(|_| File::open(&staging_path)?.sync_all())
+        .map_err(|e| {
+            let _ = fs::remove_file(&staging_path);
+            format!("Failed to copy replaced file to {}: {}", target_path, e)
+        })?;
+    fs::rename(&staging_path, target_path).map_err(|e| {
+        let _ = fs::remove_file(&staging_path);
+        format!("Failed to move replaced file to {}: {}", target_path, e)
+    })?;
+    if let Err(e) = fs::remove_file(temp_path) {
+        eprintln!("Failed to remove replace output {}: {}", temp_path, e);
+    }
+    Ok(())
 }
@@ -0,0 +1,136 @@
+// Read access to the open file through one memory map, shared by the commands, the
+// index builders and search. Lines are handed out as slices borrowed from the map, so
+// serving a page of lines or a search candidate costs no open, seek or copy.
+use memmap2::Mmap;
+use std::fs::{File, Metadata};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::indexing_service::LineOffset;
+
+pub struct FileContent {
+    path: String,
+    file: File, // Kept open to tell whether the mapped file has been truncated
+    mmap: Option<Mmap>, // None for empty files, which cannot be mapped
+    identity: Option<(u64, u64)>, // Device and inode, where the platform has them
+    modified: Option<SystemTime>, // When the file was last written as of opening it
+    full_hash: OnceLock<u64>,
+    prefix_hashes: Mutex<HashMap<u64, u64>>, // By prefix length, see `prefix_hash`
+}
+
+impl FileContent {
+    pub fn open(path: &str) -> Result<Self, String> {
+        let file = File::open(path)
+            .map_err(|e| format!("Failed to open file {}: {}", path, e))?;
+        let metadata = file.metadata()
+            .map_err(|e| format!("Failed to read metadata of {}: {}", path, e))?;
+        let file_len = metadata.len();
+        let mmap = if file_len == 0 {
+            None
+        } else {
+            // Safety: the map is read-only and never outlives the FileContent. The file is
+            // replaced by rename (see persist_replaced), which leaves the mapped inode intact.
+            // Another process may truncate it in place, as copytruncate log rotation does;
+            // reading the lost pages would crash, so the commands check `check_not_truncated`
+            // before reading.
+            let mmap = unsafe { Mmap::map(&file) }
+                .map_err(|e| format!("Failed to map file {}: {}", path, e))?;
+            Some(mmap)
+        };
+        Ok(FileContent {
+            path: path.to_string(),
+            file,
+            mmap,
+            identity: file_identity(&metadata),
+            modified: metadata.modified().ok(),
+            full_hash: OnceLock::new(),
+            prefix_hashes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The whole file.
+    pub fn bytes(&self) -> &[u8] {
+        self.mmap.as_deref().unwrap_or(&[])
+    }
+
+    pub fn len(&self) -> u64 {
+        self.bytes().len() as u64
+    }
+
+    /// xxh3 of the whole file, computed on first use.
+    pub fn full_hash(&self) -> u64 {
+        *self.full_hash.get_or_init(|| xxh3_64(self.bytes()))
+    }
+
+    /// The full hash if it has already been computed.
+    pub fn known_full_hash(&self) -> Option<u64> {
+        self.full_hash.get().copied()
+    }
+
+    /// xxh3 of the first `len` bytes, computed once per length: the caches of a file that
+    /// has grown are all checked against the same indexed prefix.
+    pub fn prefix_hash(&self, len: u64) -> u64 {
+        if len >= self.len() {
+            return self.full_hash();
+        }
+        let prefix = &self.bytes()[..len as usize];
+        match self.prefix_hashes.lock() {
+            Ok(mut hashes) => *hashes.entry(len).or_insert_with(|| xxh3_64(prefix)),
+            Err(_) => xxh3_64(prefix),
+        }
+    }
+
+    /// Device and inode of the mapped file, where the platform has them.
+    pub fn identity(&self) -> Option<(u64, u64)> {
+        self.identity
+    }
+
+    /// The file's mtime when it was opened, if the platform reports one.
+    pub fn modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Whether `metadata`, freshly read from the path, still describes the mapped file rather
+    /// than one that has replaced it. Assumed true where the platform cannot tell.
+    pub fn is_same_file(&self, metadata: &Metadata) -> bool {
+        match (self.identity, file_identity(metadata)) {
+            (Some(mapped), Some(current)) => mapped == current,
+            _ => true,
+        }
+    }
+
+    /// Fails if the file has been truncated in place to less than the mapped length. The
+    /// mapping must not be read then, as touching its lost pages raises SIGBUS.
+    pub fn check_not_truncated(&self) -> Result<(), String> {
+        let metadata = self.file.metadata()
+            .map_err(|e| format!("Failed to read metadata of {}: {}", self.path, e))?;
+        if metadata.len() < self.len() {
+            return Err(format!("{} was truncated while open; reopen it.", self.path));
+        }
+        Ok(())
+    }
+
+    /// Raw bytes of a line including its terminator, or None if the offset points past
+    /// the end of the file (an index built for a different revision of it).
+    pub fn line(&self, line_offset: &LineOffset) -> Option<&[u8]> {
+        let start = usize::try_from(line_offset.offset).ok()?;
+        self.bytes().get(start..start.checked_add(line_offset.length)?)
+    }
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
@@ -0,0 +1,52 @@
+// Change notifications for a single file (inotify on Linux, FSEvents/kqueue on macOS,
+// ReadDirectoryChangesW on Windows). The parent directory is watched rather than the file
+// itself, so rotation, i.e. renaming the file away and creating a new one, is seen too.
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+pub struct FileWatcher {
+    _watcher: RecommendedWatcher, // Stops watching when dropped
+    changes: Receiver<()>,
+}
+
+impl FileWatcher {
+    pub fn new(file_path: &str) -> Result<Self, String> {
+        let path = Path::new(file_path);
+        let file_name = path.file_name()
+            .ok_or_else(|| format!("Cannot watch {}: not a file path", file_path))?
+            .to_os_string();
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+
+        let (sender, changes) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let relevant = match &event {
+                Ok(event) => event.paths.iter().any(|changed| changed.file_name() == Some(file_name.as_os_str())),
+                Err(_) => true, // E.g. an overflowed event queue; let the caller re-check the file
+            };
+            if relevant {
+                let _ = sender.send(());
+            }
+        }).map_err(|e| format!("Failed to create file watcher: {}", e))?;
+        watcher.watch(&directory, RecursiveMode::NonRecursive)
+            .map_err(|e| format!("Failed to watch {}: {}", directory.display(), e))?;
+
+        Ok(FileWatcher { _watcher: watcher, changes })
+    }
+
+    /// Waits up to `timeout` for the file to change. Bursts of events are coalesced into one
+    /// wakeup; callers re-check the file either way, so a timeout doubles as a poll.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        match self.changes.recv_timeout(timeout) {
+            Ok(()) => {
+                while self.changes.try_recv().is_ok() {}
+                true
+            }
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => false,
+        }
+    }
+}
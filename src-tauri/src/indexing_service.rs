@@ -1,94 +1,674 @@
 use serde::{Serialize, Deserialize};
-use std::fs::File;
-use std::io::{self, BufRead, BufReader};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use serde_json::Value;
+
+use crate::file_content_service::FileContent;
+use crate::postings_store::{self, PositionList, ValueColumn};
+use crate::schema_inference::SchemaStats;
+use crate::utils::field_values;
+use crate::utils::token_utils::{self, UNPARSED_LINE_TERM};
 
 pub type InvertedIndex = HashMap<String, Vec<u32>>;
+/// Token positions within the string values of each line, for phrase and proximity queries.
+pub type PositionIndex = HashMap<String, PositionList>;
+/// Numeric and date values of each field, keyed by `field_values::column_key`, for range queries.
+pub type FieldValueIndex = HashMap<String, ValueColumn>;
 
-pub const NGRAM_SIZE: usize = 3; 
+pub const DEFAULT_NGRAM_SIZE: usize = 3;
+pub const MIN_NGRAM_SIZE: usize = 2;
+pub const MAX_NGRAM_SIZE: usize = 8;
 pub type NGram = Vec<u8>;
 pub type NGramIndex = HashMap<NGram, Vec<u32>>;
 
+/// Which search indexes to build for a document and how, as passed to `open_file`. Indexes
+/// built with different settings answer queries differently, so they are cached apart.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(default)]
+pub struct IndexSettings {
+    pub inverted_index: bool,
+    pub ngram_index: bool,
+    pub position_index: bool, // Token positions, for phrase and proximity queries without reading lines
+    pub ngram_size: usize,
+    pub case_fold_ngrams: bool, // Index ASCII-lowercased n-grams; case-insensitive searches narrow better
+    pub fields: Option<Vec<String>>, // JSON paths whose values the inverted index covers; all if None
+    pub max_postings_bytes: Option<u64>, // Drop the inverted or n-gram index if its postings grow past this
+}
+
+impl Default for IndexSettings {
+    fn default() -> Self {
+        IndexSettings {
+            inverted_index: true,
+            ngram_index: true,
+            position_index: false,
+            ngram_size: DEFAULT_NGRAM_SIZE,
+            case_fold_ngrams: false,
+            fields: None,
+            max_postings_bytes: None,
+        }
+    }
+}
+
+impl IndexSettings {
+    /// Checks the settings and brings field paths into the lowercased form used in terms.
+    pub fn normalized(mut self) -> Result<Self, String> {
+        if !(MIN_NGRAM_SIZE..=MAX_NGRAM_SIZE).contains(&self.ngram_size) {
+            return Err(format!("N-gram size must be between {} and {}, not {}.", MIN_NGRAM_SIZE, MAX_NGRAM_SIZE, self.ngram_size));
+        }
+        if let Some(fields) = self.fields.as_mut() {
+            for field in fields.iter_mut() {
+                *field = field.trim().to_lowercase();
+            }
+            fields.retain(|field| !field.is_empty());
+            fields.sort();
+            fields.dedup();
+            if fields.is_empty() {
+                return Err("No JSON fields given for the inverted index.".to_string());
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Error returned by the index builders when `is_cancelled` reports true.
+pub const INDEXING_CANCELLED: &str = "Indexing cancelled";
+
+/// Builders report progress every this many lines; consumers throttle further as needed.
+pub const PROGRESS_INTERVAL_LINES: usize = 4096;
+
+#[derive(Clone, Copy, Debug)]
+pub struct IndexingProgress {
+    pub lines_processed: usize,
+    pub bytes_processed: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)] 
 pub struct LineOffset {
     pub offset: u64,
     pub length: usize,
 }
 
-pub fn build_line_offset_index(file_path: &str) -> Result<Vec<LineOffset>, std::io::Error> {
-    let file = File::open(file_path)?;
-    let mut reader = BufReader::new(file);
-    let mut offsets = Vec::new();
-    let mut current_offset = 0;
-    let mut line_buffer = String::new();
+/// Target size of the newline-aligned byte ranges the builders scan in parallel.
+const CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Splits a raw line into its content and its `\n` / `\r\n` terminator (possibly empty).
+pub fn split_line_ending(line: &[u8]) -> (&[u8], &[u8]) {
+    let mut content_len = line.len();
+    if line.ends_with(b"\n") {
+        content_len -= 1;
+        if line[..content_len].ends_with(b"\r") {
+            content_len -= 1;
+        }
+    }
+    line.split_at(content_len)
+}
+
+// Iterates over the lines of a chunk, each including its terminator.
+fn chunk_lines(chunk: &[u8]) -> impl Iterator<Item = &[u8]> {
+    chunk.split_inclusive(|&b| b == b'\n')
+}
+
+// Splits the data into ranges of roughly `chunk_size` bytes that each end right after a
+// newline (or at the end), so no line straddles two chunks.
+fn chunk_boundaries(data: &[u8], mut start: usize, chunk_size: usize) -> Vec<(usize, usize)> {
+    let mut chunks = Vec::new();
+    while start < data.len() {
+        let end = match data.get(start + chunk_size..) {
+            // Move the end forward to just past the next newline.
+            Some(rest) => rest.iter()
+                .position(|&b| b == b'\n')
+                .map_or(data.len(), |pos| start + chunk_size + pos + 1),
+            None => data.len(),
+        };
+        chunks.push((start, end));
+        start = end;
+    }
+    chunks
+}
+
+// Runs `scan_chunk` on newline-aligned chunks of the file, from byte `start` (the
+// beginning of a line) to the end, across all cores. Results are
+// handed to `merge` strictly in file order, together with the chunk's byte range, so
+// callers can rebase chunk-local line numbers onto the lines seen so far. Stops with an
+// error if the file is truncated in place under the mapping, checked before each chunk.
+fn scan_chunks_parallel<T: Send>(
+    content: &FileContent,
+    start: u64,
+    is_cancelled: &(dyn Fn() -> bool + Sync),
+    scan_chunk: &(dyn Fn(&[u8], u64) -> T + Sync),
+    merge: &mut dyn FnMut(T, (u64, u64)),
+) -> Result<(), String> {
+    content.check_not_truncated()?;
+    let data = content.bytes();
+    let chunks = chunk_boundaries(data, start as usize, CHUNK_SIZE);
+    let worker_count = thread::available_parallelism().map(|n| n.get()).unwrap_or(1).min(chunks.len().max(1));
+    let next_chunk = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel::<(usize, T)>(worker_count * 2);
+        for _ in 0..worker_count {
+            let sender = sender.clone();
+            let (chunks, next_chunk) = (&chunks, &next_chunk);
+            scope.spawn(move || loop {
+                let chunk_index = next_chunk.fetch_add(1, Ordering::Relaxed);
+                if chunk_index >= chunks.len() || is_cancelled() || content.check_not_truncated().is_err() {
+                    return;
+                }
+                let (start, end) = chunks[chunk_index];
+                if sender.send((chunk_index, scan_chunk(&data[start..end], start as u64))).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        // Reorder results so they are merged in file order.
+        let mut pending = BTreeMap::new();
+        let mut next_to_merge = 0;
+        for (chunk_index, value) in receiver {
+            pending.insert(chunk_index, value);
+            while let Some(value) = pending.remove(&next_to_merge) {
+                let (start, end) = chunks[next_to_merge];
+                merge(value, (start as u64, end as u64));
+                next_to_merge += 1;
+            }
+        }
+        if next_to_merge < chunks.len() {
+            // Workers only stop early when cancelled or the file was truncated.
+            content.check_not_truncated()?;
+            return Err(INDEXING_CANCELLED.to_string());
+        }
+        Ok(())
+    })
+}
+
+/// Feeds every line from `from` on to `scan_line`, with its content (without line ending)
+/// and its number within the chunk, across all cores. Each chunk's state is handed to `merge`
+/// in file order, with the absolute number of the chunk's first line, its line count and the
+/// bytes processed so far.
+pub fn scan_lines<T: Default + Send>(
+    content: &FileContent,
+    from: ResumePoint,
+    is_cancelled: &(dyn Fn() -> bool + Sync),
+    scan_line: &(dyn Fn(&mut T, u32, &[u8]) + Sync),
+    merge: &mut dyn FnMut(T, u32, u32, u64),
+) -> Result<(), String> {
+    let mut line_base = from.line as u32;
+    scan_chunks_parallel(
+        content,
+        from.offset,
+        is_cancelled,
+        &|chunk, _| {
+            let mut state = T::default();
+            let mut line_count = 0;
+            for line in chunk_lines(chunk) {
+                scan_line(&mut state, line_count, split_line_ending(line).0);
+                line_count += 1;
+            }
+            (state, line_count)
+        },
+        &mut |(state, line_count), (_, chunk_end)| {
+            merge(state, line_base, line_count, chunk_end);
+            line_base += line_count;
+        },
+    )
+}
+
+pub fn build_line_offset_index(
+    content: &FileContent,
+    from: ResumePoint,
+    on_progress: &dyn Fn(IndexingProgress),
+) -> Result<Vec<LineOffset>, String> {
+    let selection = IndexSelection { line_offsets: true, ..IndexSelection::default() };
+    let built = build_indexes(content, selection, &IndexSettings::default(), from, PostingsBytes::default(), &|| false, on_progress)?;
+    Ok(built.line_offsets.unwrap_or_default())
+}
+
+/// First line (and its byte offset) that a pass over the file indexes. Later passes over
+/// a file that grew by appending start where the previous one left off.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ResumePoint {
+    pub line: usize,
+    pub offset: u64,
+}
+
+/// Where indexing resumes for an index built over the first `indexed_size` bytes of a file
+/// that has since grown. `line_offsets` may be the cached or the current offsets. Lines that
+/// were complete at that size keep their entries; an unterminated last line may have been
+/// extended, so it is indexed again.
+pub fn resume_point(line_offsets: &[LineOffset], indexed_size: u64, content: &FileContent) -> ResumePoint {
+    let mut line = line_offsets.partition_point(|lo| lo.offset + lo.length as u64 <= indexed_size);
+    if line > 0 && indexed_size > 0 && content.bytes().get(indexed_size as usize - 1) != Some(&b'\n') {
+        let last = &line_offsets[line - 1];
+        if last.offset + last.length as u64 == indexed_size {
+            line -= 1;
+        }
+    }
+    let offset = line_offsets.get(line).map_or(indexed_size, |lo| lo.offset);
+    ResumePoint { line, offset }
+}
+
+/// Bytes up to and including the file's last newline, i.e. without an unterminated last line.
+pub fn complete_lines_size(content: &FileContent) -> u64 {
+    content.bytes().iter().rposition(|&b| b == b'\n').map_or(0, |newline| newline as u64 + 1)
+}
+
+/// Shifts offsets after rewriting some lines in place, without rescanning the new file.
+/// `changed_line_lengths` holds (line number, new length) pairs in ascending line order.
+pub fn remap_line_offsets(offsets: &mut [LineOffset], changed_line_lengths: &[(u32, usize)]) {
+    let mut shift: i64 = 0;
+    let mut changes = changed_line_lengths.iter().peekable();
+    for (line_num, line_offset) in offsets.iter_mut().enumerate() {
+        line_offset.offset = (line_offset.offset as i64 + shift) as u64;
+        if let Some(&&(changed_line, new_length)) = changes.peek() {
+            if changed_line as usize == line_num {
+                shift += new_length as i64 - line_offset.length as i64;
+                line_offset.length = new_length;
+                changes.next();
+            }
+        }
+    }
+}
+
+/// Which indexes a single pass over the file should produce.
+#[derive(Clone, Copy, Default)]
+pub struct IndexSelection {
+    pub line_offsets: bool,
+    pub inverted_index: bool,
+    pub ngram_index: bool,
+    pub position_index: bool,
+    pub value_index: bool,
+    pub schema: bool,
+}
+
+impl IndexSelection {
+    pub fn is_empty(&self) -> bool {
+        !(self.line_offsets || self.inverted_index || self.ngram_index || self.position_index || self.value_index || self.schema)
+    }
+
+    fn parses_json(&self) -> bool {
+        self.inverted_index || self.position_index || self.value_index || self.schema
+    }
+}
+
+/// Postings memory of the inverted and n-gram indexes, as counted against
+/// `IndexSettings::max_postings_bytes`.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PostingsBytes {
+    pub inverted: u64,
+    pub ngram: u64,
+}
+
+/// Output of `build_indexes`; an index is `Some` exactly when it was selected and, for the
+/// inverted and n-gram indexes, stayed within the postings memory limit.
+#[derive(Default)]
+pub struct BuiltIndexes {
+    pub first_line: u32, // Line the pass started at; postings are absolute line numbers
+    pub postings_bytes: PostingsBytes, // Including the base, and as far as a dropped index got
+    pub line_offsets: Option<Vec<LineOffset>>,
+    pub inverted_index: Option<InvertedIndex>,
+    pub ngram_index: Option<NGramIndex>,
+    pub position_index: Option<PositionIndex>,
+    pub value_index: Option<FieldValueIndex>,
+    pub schema: Option<SchemaStats>, // Of the complete lines
+    pub unterminated_schema: Option<SchemaStats>, // Of an unterminated last line, which may still grow
+}
 
-    loop {
-        let bytes_read = reader.read_line(&mut line_buffer)?;
-        if bytes_read == 0 { // EOF
-            break;
+// Everything built from one chunk. Line offsets are absolute, postings use chunk-local line numbers.
+#[derive(Default)]
+struct ChunkIndexes {
+    line_count: u32,
+    line_offsets: Vec<LineOffset>,
+    inverted_index: InvertedIndex,
+    ngram_index: NGramIndex,
+    position_index: PositionIndex,
+    value_index: FieldValueIndex,
+    schema: SchemaStats,
+    unterminated_schema: SchemaStats,
+}
+
+// Rough per-key cost of a posting list in memory: the key and list headers plus hash table slack.
+const POSTINGS_ENTRY_OVERHEAD_BYTES: u64 = 64;
+
+// Appends chunk-local postings to the global index, rebasing them by `line_base`.
+// Chunks are merged in file order, so the global lists stay sorted. Returns roughly how much
+// memory the index grew by.
+fn merge_chunk_postings<K: Eq + std::hash::Hash + AsRef<[u8]>>(index: &mut HashMap<K, Vec<u32>>, chunk_postings: HashMap<K, Vec<u32>>, line_base: u32) -> u64 {
+    let mut added_bytes = 0;
+    for (key, local_lines) in chunk_postings {
+        added_bytes += (local_lines.len() * std::mem::size_of::<u32>()) as u64;
+        let lines = index.entry(key).or_insert_with_key(|key| {
+            added_bytes += POSTINGS_ENTRY_OVERHEAD_BYTES + key.as_ref().len() as u64;
+            Vec::new()
+        });
+        lines.extend(local_lines.into_iter().map(|line| line + line_base));
+    }
+    added_bytes
+}
+
+// Drops an index whose postings have grown past the limit; searches then do without it.
+fn enforce_postings_limit<K>(index: &mut Option<HashMap<K, Vec<u32>>>, bytes: u64, limit: Option<u64>, dropped: &AtomicBool, name: &str) {
+    if let Some(limit) = limit.filter(|&limit| bytes > limit) {
+        if index.take().is_some() {
+            eprintln!("The {} outgrew the postings memory limit of {} bytes; building without it.", name, limit);
+            dropped.store(true, Ordering::Relaxed);
         }
-        offsets.push(LineOffset { offset: current_offset, length: bytes_read });
-        current_offset += bytes_read as u64;
-        line_buffer.clear(); // Important to clear the buffer for the next read
     }
-    Ok(offsets)
 }
 
-pub fn build_inverted_index(
-    total_lines: usize,
-    get_line_content_closure: &dyn Fn(u32) -> Option<String>
-) -> Result<InvertedIndex, String> {
-    let mut inverted_index = InvertedIndex::new();
+// Like `merge_chunk_postings`, for value columns. Columns are sorted once all chunks are in.
+fn merge_chunk_values(index: &mut FieldValueIndex, chunk_values: FieldValueIndex, line_base: u32) {
+    for (key, local_values) in chunk_values {
+        index.entry(key).or_default()
+            .extend(local_values.into_iter().map(|(value, line)| (value, line + line_base)));
+    }
+}
+
+// Like `merge_chunk_postings`, for position lists.
+fn merge_chunk_positions(index: &mut PositionIndex, chunk_positions: PositionIndex, line_base: u32) {
+    for (token, local_lines) in chunk_positions {
+        index.entry(token).or_default()
+            .extend(local_lines.into_iter().map(|(line, positions)| (line + line_base, positions)));
+    }
+}
 
-    for line_num_u32 in 0..total_lines as u32 {
-        if let Some(line_content) = get_line_content_closure(line_num_u32) {
-            if line_content.trim().is_empty() { // Skip empty lines
-                continue;
+/// Reads the file once, from `from` to the end, and feeds every line to each selected
+/// index builder. Line numbers and offsets in the output are absolute. The inverted and
+/// n-gram indexes are built as `settings` says; one whose postings outgrow
+/// `settings.max_postings_bytes` is left out of the output. When extending indexes,
+/// `postings_base` is the size of the ones being extended, which counts toward the limit.
+pub fn build_indexes(
+    content: &FileContent,
+    selection: IndexSelection,
+    settings: &IndexSettings,
+    from: ResumePoint,
+    postings_base: PostingsBytes,
+    is_cancelled: &(dyn Fn() -> bool + Sync),
+    on_progress: &dyn Fn(IndexingProgress),
+) -> Result<BuiltIndexes, String> {
+    let mut built = BuiltIndexes {
+        first_line: from.line as u32,
+        postings_bytes: postings_base,
+        line_offsets: selection.line_offsets.then(Vec::new),
+        inverted_index: selection.inverted_index.then(HashMap::new),
+        ngram_index: selection.ngram_index.then(HashMap::new),
+        position_index: selection.position_index.then(HashMap::new),
+        value_index: selection.value_index.then(HashMap::new),
+        schema: selection.schema.then(SchemaStats::default),
+        unterminated_schema: selection.schema.then(SchemaStats::default),
+    };
+    if selection.is_empty() {
+        return Ok(built);
+    }
+    let mut lines_processed = from.line as u32;
+    let inverted_dropped = AtomicBool::new(false);
+    let ngram_dropped = AtomicBool::new(false);
+    let fields = settings.fields.as_deref();
+    scan_chunks_parallel(
+        content,
+        from.offset,
+        is_cancelled,
+        &|chunk, chunk_start| {
+            let build_inverted = selection.inverted_index && !inverted_dropped.load(Ordering::Relaxed);
+            let build_ngrams = selection.ngram_index && !ngram_dropped.load(Ordering::Relaxed);
+            let mut local = ChunkIndexes::default();
+            let mut offset = chunk_start;
+            for line in chunk_lines(chunk) {
+                if selection.line_offsets {
+                    local.line_offsets.push(LineOffset { offset, length: line.len() });
+                    offset += line.len() as u64;
+                }
+                let (content, _) = split_line_ending(line);
+                if !content.is_empty() {
+                    if selection.parses_json() {
+                        let schema = if line.ends_with(b"\n") { &mut local.schema } else { &mut local.unterminated_schema };
+                        index_json(
+                            content,
+                            local.line_count,
+                            fields,
+                            build_inverted.then_some(&mut local.inverted_index),
+                            selection.position_index.then_some(&mut local.position_index),
+                            selection.value_index.then_some(&mut local.value_index),
+                            selection.schema.then_some(schema),
+                        );
+                    }
+                    if build_ngrams {
+                        index_ngrams(content, local.line_count, settings, &mut local.ngram_index);
+                    }
+                }
+                local.line_count += 1;
+            }
+            local
+        },
+        &mut |local: ChunkIndexes, (_, chunk_end)| {
+            if let Some(line_offsets) = built.line_offsets.as_mut() {
+                line_offsets.extend(local.line_offsets);
+            }
+            if let Some(inverted_index) = built.inverted_index.as_mut() {
+                built.postings_bytes.inverted += merge_chunk_postings(inverted_index, local.inverted_index, lines_processed);
+                enforce_postings_limit(&mut built.inverted_index, built.postings_bytes.inverted, settings.max_postings_bytes, &inverted_dropped, "inverted index");
+            }
+            if let Some(ngram_index) = built.ngram_index.as_mut() {
+                built.postings_bytes.ngram += merge_chunk_postings(ngram_index, local.ngram_index, lines_processed);
+                enforce_postings_limit(&mut built.ngram_index, built.postings_bytes.ngram, settings.max_postings_bytes, &ngram_dropped, "N-gram index");
+            }
+            if let Some(position_index) = built.position_index.as_mut() {
+                merge_chunk_positions(position_index, local.position_index, lines_processed);
             }
-            // Assuming token_utils is correctly brought into scope
-            // e.g. use crate::utils::token_utils; or use super::utils::token_utils;
-            let terms = crate::utils::token_utils::tokenize_json_line(&line_content);
-            for term in terms {
-                inverted_index.entry(term).or_default().push(line_num_u32);
+            if let Some(value_index) = built.value_index.as_mut() {
+                merge_chunk_values(value_index, local.value_index, lines_processed);
             }
-        } else {
-            // Optionally log a warning or error if line content isn't available
-            eprintln!("Warning: Could not retrieve content for line {}", line_num_u32);
+            if let Some(schema) = built.schema.as_mut() {
+                schema.merge(local.schema);
+            }
+            if let Some(schema) = built.unterminated_schema.as_mut() {
+                schema.merge(local.unterminated_schema);
+            }
+            lines_processed += local.line_count;
+            on_progress(IndexingProgress { lines_processed: lines_processed as usize, bytes_processed: chunk_end });
+        },
+    )?;
+    if let Some(value_index) = built.value_index.as_mut() {
+        value_index.values_mut().for_each(postings_store::sort_column);
+    }
+    Ok(built)
+}
+
+// Adds the JSON terms of one line (content without line ending) to the inverted index, the
+// positions of its string value tokens to the positional index, its numbers and dates to
+// the field value index and its shape to the schema statistics, whichever are given. The
+// line is parsed once for all of them.
+fn index_json(
+    content: &[u8],
+    line_num: u32,
+    fields: Option<&[String]>,
+    postings: Option<&mut InvertedIndex>,
+    positions: Option<&mut PositionIndex>,
+    values: Option<&mut FieldValueIndex>,
+    schema: Option<&mut SchemaStats>,
+) {
+    // Lines that are not UTF-8 cannot be JSON either.
+    let json_value = std::str::from_utf8(content).ok()
+        .and_then(|line_content| serde_json::from_str::<Value>(line_content).ok());
+    if let Some(postings) = postings {
+        let terms = match &json_value {
+            Some(json_value) => token_utils::extract_terms(json_value, fields),
+            None => std::iter::once(UNPARSED_LINE_TERM.to_string()).collect(),
+        };
+        for term in terms {
+            postings.entry(term).or_default().push(line_num);
         }
     }
+    if let (Some(positions), Some(json_value)) = (positions, &json_value) {
+        for (token, position) in token_utils::token_positions(json_value) {
+            let lines = positions.entry(token).or_default();
+            match lines.last_mut() {
+                Some((line, line_positions)) if *line == line_num => line_positions.push(position),
+                _ => lines.push((line_num, vec![position])),
+            }
+        }
+    }
+    if let (Some(values), Some(json_value)) = (values, &json_value) {
+        for (key, value) in field_values::field_values(json_value) {
+            values.entry(key).or_default().push((value, line_num));
+        }
+    }
+    if let Some(schema) = schema {
+        schema.add_line(line_num, json_value.as_ref());
+    }
+}
 
-    // Sort line number lists for efficient intersection later
-    for postings_list in inverted_index.values_mut() { // Corrected Rpostings_list to postings_list
-        postings_list.sort_unstable();
+// Adds the n-grams of one line to the n-gram index.
+fn index_ngrams(content: &[u8], line_num: u32, settings: &IndexSettings, postings: &mut NGramIndex) {
+    let folded;
+    let content = if settings.case_fold_ngrams {
+        folded = content.to_ascii_lowercase();
+        &folded[..]
+    } else {
+        content
+    };
+    for ngram_bytes in crate::utils::ngram_utils::generate_ngrams_from_line(content, settings.ngram_size) {
+        // N-grams can appear multiple times on the same line; lines arrive in order.
+        match postings.get_mut(ngram_bytes) {
+            Some(list) => if list.last() != Some(&line_num) { list.push(line_num) },
+            None => { postings.insert(ngram_bytes.to_vec(), vec![line_num]); }
+        }
     }
-    Ok(inverted_index)
 }
 
-pub fn build_ngram_index(
-    total_lines: usize,
-    get_line_content_closure: &dyn Fn(u32) -> Option<String>
-) -> Result<NGramIndex, String> {
-    let mut ngram_index = NGramIndex::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offsets(lengths: &[usize]) -> Vec<LineOffset> {
+        let mut offset = 0;
+        lengths.iter().map(|&length| {
+            let line_offset = LineOffset { offset, length };
+            offset += length as u64;
+            line_offset
+        }).collect()
+    }
 
-    for line_num_u32 in 0..total_lines as u32 {
-        if let Some(line_content) = get_line_content_closure(line_num_u32) {
-            if line_content.trim().is_empty() { // Skip empty lines
-                continue;
+    fn spans(line_offsets: &[LineOffset]) -> Vec<(u64, usize)> {
+        line_offsets.iter().map(|lo| (lo.offset, lo.length)).collect()
+    }
+
+    fn content_of(name: &str, bytes: &[u8]) -> FileContent {
+        let path = std::env::temp_dir().join(format!("dolphin-indexing-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let content = FileContent::open(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        content
+    }
+
+    #[test]
+    fn resume_after_the_last_complete_line() {
+        let content = content_of("grown", b"a\nbb\nccc\n");
+        let current = offsets(&[2, 3, 4]);
+        assert_eq!(resume_point(&current, 5, &content), ResumePoint { line: 2, offset: 5 });
+        assert_eq!(resume_point(&current, 9, &content), ResumePoint { line: 3, offset: 9 });
+        assert_eq!(resume_point(&current, 0, &content), ResumePoint { line: 0, offset: 0 });
+        assert_eq!(resume_point(&offsets(&[2, 3]), 5, &content), ResumePoint { line: 2, offset: 5 });
+    }
+
+    #[test]
+    fn an_extended_last_line_is_indexed_again() {
+        // Indexed as "a\nbb" and since extended to "a\nbbx\n".
+        let content = content_of("extended", b"a\nbbx\ny\n");
+        let expected = ResumePoint { line: 1, offset: 2 };
+        assert_eq!(resume_point(&offsets(&[2, 2]), 4, &content), expected);
+        assert_eq!(resume_point(&offsets(&[2, 4, 2]), 4, &content), expected);
+    }
+
+    #[test]
+    fn scanning_from_a_resume_point_numbers_lines_absolutely() {
+        let content = content_of("scan", b"a\nbb\r\nccc\ndd");
+        let mut seen = Vec::new();
+        scan_lines::<Vec<(u32, Vec<u8>)>>(
+            &content,
+            ResumePoint { line: 1, offset: 2 },
+            &|| false,
+            &|lines, line, text| lines.push((line, text.to_vec())),
+            &mut |lines, line_base, line_count, bytes| {
+                assert_eq!(lines.len(), line_count as usize);
+                assert_eq!(bytes, content.len());
+                seen.extend(lines.into_iter().map(|(line, text)| (line + line_base, text)));
+            },
+        ).unwrap();
+        assert_eq!(seen, vec![(1, b"bb".to_vec()), (2, b"ccc".to_vec()), (3, b"dd".to_vec())]);
+    }
+
+    #[test]
+    fn chunks_end_after_a_newline() {
+        let data = b"ab\ncd\nef";
+        assert_eq!(chunk_boundaries(data, 0, 2), vec![(0, 3), (3, 6), (6, 8)]);
+        assert_eq!(chunk_boundaries(data, 3, 2), vec![(3, 6), (6, 8)]);
+        assert_eq!(chunk_boundaries(data, 0, 100), vec![(0, 8)]);
+        assert_eq!(chunk_boundaries(data, 8, 2), vec![]);
+        assert_eq!(chunk_boundaries(b"", 0, 2), vec![]);
+
+        let data: Vec<u8> = (0..200u32).flat_map(|i| {
+            let mut line = vec![b'x'; (i * 7 % 13) as usize];
+            line.push(b'\n');
+            line
+        }).collect();
+        for chunk_size in [1, 5, 16, 64] {
+            let chunks = chunk_boundaries(&data, 0, chunk_size);
+            assert_eq!(chunks.first().map(|c| c.0), Some(0));
+            assert_eq!(chunks.last().map(|c| c.1), Some(data.len()));
+            for window in chunks.windows(2) {
+                assert_eq!(window[0].1, window[1].0);
             }
-            let ngrams = crate::utils::ngram_utils::generate_ngrams_from_line(&line_content);
-            for ngram_bytes in ngrams {
-                let postings_list = ngram_index.entry(ngram_bytes).or_default();
-                postings_list.push(line_num_u32);
+            for &(start, end) in &chunks {
+                assert!(end - start >= chunk_size.min(data.len() - start));
+                assert_eq!(data[end - 1], b'\n');
             }
-        } else {
-            eprintln!("Warning: Could not retrieve content for N-gram indexing on line {}", line_num_u32);
         }
     }
 
-    // Sort and deduplicate line number lists
-    for postings_list in ngram_index.values_mut() {
-        postings_list.sort_unstable();
-        postings_list.dedup(); // N-grams can appear multiple times on the same line
+    #[test]
+    fn remapped_offsets_follow_changed_lengths() {
+        let mut line_offsets = offsets(&[3, 5, 2, 4]);
+        remap_line_offsets(&mut line_offsets, &[(1, 7), (3, 1)]);
+        assert_eq!(spans(&line_offsets), vec![(0, 3), (3, 7), (10, 2), (12, 1)]);
+
+        let mut line_offsets = offsets(&[4, 4, 4]);
+        remap_line_offsets(&mut line_offsets, &[(0, 1), (1, 2)]);
+        assert_eq!(spans(&line_offsets), vec![(0, 1), (1, 2), (3, 4)]);
+
+        let mut line_offsets = offsets(&[2, 2]);
+        remap_line_offsets(&mut line_offsets, &[]);
+        assert_eq!(spans(&line_offsets), vec![(0, 2), (2, 2)]);
+    }
+
+    #[test]
+    fn settings_are_checked_and_fields_normalized() {
+        let fields = [" User.Name ", "level", "user.name", ""].map(String::from).to_vec();
+        let settings = IndexSettings { fields: Some(fields), ..IndexSettings::default() };
+        assert_eq!(settings.normalized().unwrap().fields, Some(vec!["level".to_string(), "user.name".to_string()]));
+        assert_eq!(IndexSettings::default().normalized(), Ok(IndexSettings::default()));
+
+        assert!(IndexSettings { fields: Some(vec![" ".to_string()]), ..IndexSettings::default() }.normalized().is_err());
+        for ngram_size in [MIN_NGRAM_SIZE - 1, MAX_NGRAM_SIZE + 1] {
+            assert!(IndexSettings { ngram_size, ..IndexSettings::default() }.normalized().is_err());
+        }
+        for ngram_size in [MIN_NGRAM_SIZE, MAX_NGRAM_SIZE] {
+            assert!(IndexSettings { ngram_size, ..IndexSettings::default() }.normalized().is_ok());
+        }
+    }
+
+    #[test]
+    fn indexes_past_the_postings_limit_are_dropped() {
+        let dropped = AtomicBool::new(false);
+        let mut index = Some(InvertedIndex::from([("a".to_string(), vec![1])]));
+        enforce_postings_limit(&mut index, 100, None, &dropped, "inverted index");
+        enforce_postings_limit(&mut index, 100, Some(100), &dropped, "inverted index");
+        assert!(index.is_some() && !dropped.load(Ordering::Relaxed));
+
+        enforce_postings_limit(&mut index, 101, Some(100), &dropped, "inverted index");
+        assert!(index.is_none() && dropped.load(Ordering::Relaxed));
     }
-    Ok(ngram_index)
 }
@@ -0,0 +1,343 @@
+// Finds lines that are not well-formed JSON (bad UTF-8, truncated values, trailing garbage,
+// duplicate keys) and writes repaired copies of a file. The source is never modified; the
+// repaired output goes to a new file the user picks.
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::file_content_service::FileContent;
+use super::indexing_service::{split_line_ending, IndexingProgress, LineOffset, PROGRESS_INTERVAL_LINES};
+use super::state::DocId;
+
+/// Invalid lines listed in a report; later ones are only counted.
+pub const MAX_REPORTED_LINES: usize = 100_000;
+
+pub const REPAIR_CANCELLED: &str = "Repair cancelled";
+
+// Tells apart the temp files of repairs running at the same time.
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum LineProblem {
+    InvalidUtf8,
+    TruncatedJson,   // The line ends inside a value
+    TrailingGarbage, // More than one value, or junk after it
+    InvalidJson,     // Any other syntax error
+    DuplicateKey,    // Valid JSON, but an object has the same key twice; only the last one is kept when parsed
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct InvalidLine {
+    pub line_number: u32,
+    pub problem: LineProblem,
+    pub message: String,
+    pub start: usize, // Byte range of the offending part, relative to the start of the line
+    pub end: usize,
+}
+
+/// Checks a line (content without line ending). Empty lines are fine.
+pub fn check_line(line_number: u32, content: &[u8]) -> Option<InvalidLine> {
+    let invalid = |problem, message: String, start, end| Some(InvalidLine { line_number, problem, message, start, end });
+    if content.is_empty() {
+        return None;
+    }
+    if let Err(e) = std::str::from_utf8(content) {
+        let start = e.valid_up_to();
+        let end = e.error_len().map_or(content.len(), |len| start + len);
+        return invalid(LineProblem::InvalidUtf8, format!("Invalid UTF-8 at byte {}", start), start, end);
+    }
+
+    let mut values = serde_json::Deserializer::from_slice(content).into_iter::<Value>();
+    match values.next() {
+        None => None, // Only whitespace
+        Some(Err(e)) if e.is_eof() => invalid(LineProblem::TruncatedJson, "The line ends before the JSON value is complete".to_string(), 0, content.len()),
+        Some(Err(e)) => {
+            let message = e.to_string();
+            let message = message.rsplit_once(" at line ").map_or(message.as_str(), |(message, _)| message);
+            let start = e.column().saturating_sub(1).min(content.len());
+            invalid(LineProblem::InvalidJson, message.to_string(), start, content.len())
+        }
+        Some(Ok(_)) => {
+            let value_end = values.byte_offset();
+            match content[value_end..].iter().position(|b| !b.is_ascii_whitespace()) {
+                Some(garbage) => invalid(LineProblem::TrailingGarbage, "Unexpected content after the JSON value".to_string(), value_end + garbage, content.len()),
+                None => duplicate_key(content).and_then(|(key, start, end)| {
+                    invalid(LineProblem::DuplicateKey, format!("Duplicate key \"{}\"", key), start, end)
+                }),
+            }
+        }
+    }
+}
+
+// Finds the first key that repeats within its object, with the byte range of the repeat.
+// The content must be valid JSON, so strings, brackets and commas can be matched up without
+// a full parser.
+fn duplicate_key(content: &[u8]) -> Option<(String, usize, usize)> {
+    let mut objects: Vec<Option<HashSet<String>>> = Vec::new(); // None for arrays
+    let mut expect_key = false;
+    let mut i = 0;
+    while i < content.len() {
+        match content[i] {
+            b'{' => {
+                objects.push(Some(HashSet::new()));
+                expect_key = true;
+            }
+            b'[' => {
+                objects.push(None);
+                expect_key = false;
+            }
+            b'}' | b']' => {
+                objects.pop();
+                expect_key = false;
+            }
+            b',' => expect_key = matches!(objects.last(), Some(Some(_))),
+            b'"' => {
+                let start = i;
+                i += 1;
+                while i < content.len() && content[i] != b'"' {
+                    i += if content[i] == b'\\' { 2 } else { 1 };
+                }
+                if expect_key {
+                    // Decoded, so `"a"` and `"\u0061"` count as the same key.
+                    let key: String = serde_json::from_slice(&content[start..=i]).ok()?;
+                    if let Some(Some(keys)) = objects.last_mut() {
+                        if !keys.insert(key.clone()) {
+                            return Some((key, start, i + 1));
+                        }
+                    }
+                    expect_key = false;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ScanState {
+    Running,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+/// Payload of the `invalid_lines_status_update` event; also part of every page of invalid lines.
+#[derive(Clone, Serialize, Debug)]
+pub struct InvalidLinesStatus {
+    pub doc_id: DocId,
+    pub state: ScanState,
+    pub message: String,
+    pub lines_checked: usize,
+    pub total_lines: usize,
+    pub invalid_count: usize,
+    pub problem_counts: BTreeMap<LineProblem, usize>,
+    pub truncated: bool, // More than MAX_REPORTED_LINES; only the first are listed
+}
+
+/// The invalid lines of a document found so far by `scan_invalid_lines`.
+pub struct InvalidLinesScan {
+    pub status: InvalidLinesStatus,
+    pub lines: Vec<InvalidLine>, // Ascending by line, at most MAX_REPORTED_LINES
+    pub line_numbers: Vec<u32>,  // Every invalid line, for `repair_lines`
+}
+
+impl InvalidLinesScan {
+    pub fn new(doc_id: DocId, total_lines: usize) -> Self {
+        InvalidLinesScan {
+            status: InvalidLinesStatus {
+                doc_id,
+                state: ScanState::Running,
+                message: "Scanning for invalid lines...".to_string(),
+                lines_checked: 0,
+                total_lines,
+                invalid_count: 0,
+                problem_counts: BTreeMap::new(),
+                truncated: false,
+            },
+            lines: Vec::new(),
+            line_numbers: Vec::new(),
+        }
+    }
+
+    /// Adds the invalid lines of a chunk of `line_count` lines starting at `first_line`,
+    /// whose line numbers are relative to it. Chunks arrive in file order.
+    pub fn add_chunk(&mut self, lines: Vec<InvalidLine>, first_line: u32, line_count: u32) {
+        let status = &mut self.status;
+        status.lines_checked += line_count as usize;
+        status.invalid_count += lines.len();
+        for mut line in lines {
+            line.line_number += first_line;
+            self.line_numbers.push(line.line_number);
+            *status.problem_counts.entry(line.problem).or_default() += 1;
+            if self.lines.len() < MAX_REPORTED_LINES {
+                self.lines.push(line);
+            } else {
+                status.truncated = true;
+            }
+        }
+    }
+
+    pub fn finish(&mut self, state: ScanState, message: &str) {
+        self.status.state = state;
+        self.status.message = message.to_string();
+    }
+}
+
+/// Returned by `get_invalid_lines`.
+#[derive(Clone, Serialize, Debug)]
+pub struct InvalidLinesPage {
+    pub status: InvalidLinesStatus,
+    pub offset: usize,
+    pub lines: Vec<InvalidLine>,
+}
+
+#[derive(Clone, Copy, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairAction {
+    LossyUtf8,    // Replace invalid UTF-8 with U+FFFD; other problems are left as they are
+    DropLine,
+    WrapAsString, // Replace the line with a JSON string of its (lossily decoded) text
+}
+
+/// Returned by `repair_lines`.
+#[derive(Clone, Serialize, Debug)]
+pub struct RepairSummary {
+    pub lines_repaired: usize,
+    pub lines_dropped: usize,
+    pub target_path: String,
+}
+
+/// Copies the file to `target_path`, applying `action` to the lines in `line_numbers`
+/// (ascending). Other lines are copied through byte for byte. The copy is written next to
+/// the target and renamed into place, so on failure, or when stopped with `REPAIR_CANCELLED`
+/// once `is_cancelled` returns true, whatever was at the target is left as it was.
+pub fn write_repaired(
+    content: &FileContent,
+    line_offsets: &[LineOffset],
+    line_numbers: &[u32],
+    action: RepairAction,
+    target_path: &str,
+    is_cancelled: &dyn Fn() -> bool,
+    on_progress: &dyn Fn(IndexingProgress),
+) -> Result<RepairSummary, String> {
+    let temp_path = format!("{}.dolphin-repair-{}-{}.tmp", target_path, std::process::id(), NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed));
+    let written = copy_repaired(content, line_offsets, line_numbers, action, &temp_path, is_cancelled, on_progress)
+        .and_then(|(lines_repaired, lines_dropped)| {
+            fs::rename(&temp_path, target_path)
+                .map_err(|e| format!("Failed to move repaired file to {}: {}", target_path, e))?;
+            Ok(RepairSummary { lines_repaired, lines_dropped, target_path: target_path.to_string() })
+        });
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+// Writes the repaired copy to `temp_path`; returns the number of lines repaired and dropped.
+fn copy_repaired(
+    content: &FileContent,
+    line_offsets: &[LineOffset],
+    line_numbers: &[u32],
+    action: RepairAction,
+    temp_path: &str,
+    is_cancelled: &dyn Fn() -> bool,
+    on_progress: &dyn Fn(IndexingProgress),
+) -> Result<(usize, usize), String> {
+    let data = content.bytes();
+    let output = File::create(temp_path)
+        .map_err(|e| format!("Failed to create {}: {}", temp_path, e))?;
+    let mut writer = BufWriter::new(output);
+    let (mut lines_repaired, mut lines_dropped) = (0, 0);
+
+    let mut position: u64 = 0;
+    for (done, &line_num) in line_numbers.iter().enumerate() {
+        if is_cancelled() {
+            return Err(REPAIR_CANCELLED.to_string());
+        }
+        if done.is_multiple_of(PROGRESS_INTERVAL_LINES) {
+            on_progress(IndexingProgress { lines_processed: line_num as usize, bytes_processed: position });
+        }
+        let line_info = match line_offsets.get(line_num as usize) {
+            Some(info) => info,
+            None => continue,
+        };
+        let line = content.line(line_info)
+            .ok_or_else(|| "File is shorter than its line offset index; reopen it and retry.".to_string())?;
+        writer.write_all(&data[position as usize..line_info.offset as usize])
+            .map_err(|e| format!("Failed to copy unchanged lines: {}", e))?;
+        position = line_info.offset + line_info.length as u64;
+
+        let (line_content, ending) = split_line_ending(line);
+        let repaired = match action {
+            RepairAction::DropLine => None,
+            RepairAction::LossyUtf8 => Some(String::from_utf8_lossy(line_content).into_owned()),
+            RepairAction::WrapAsString => Some(Value::String(String::from_utf8_lossy(line_content).into_owned()).to_string()),
+        };
+        let write_result = match repaired {
+            Some(repaired) => {
+                if repaired.as_bytes() != line_content {
+                    lines_repaired += 1;
+                }
+                writer.write_all(repaired.as_bytes()).and_then(|_| writer.write_all(ending))
+            }
+            None => {
+                lines_dropped += 1;
+                Ok(())
+            }
+        };
+        write_result.map_err(|e| format!("Failed to write {}: {}", temp_path, e))?;
+    }
+
+    writer.write_all(&data[position as usize..])
+        .map_err(|e| format!("Failed to copy unchanged lines: {}", e))?;
+    let output = writer.into_inner()
+        .map_err(|e| format!("Failed to write {}: {}", temp_path, e.error()))?;
+    output.sync_all()
+        .map_err(|e| format!("Failed to flush {}: {}", temp_path, e))?;
+    on_progress(IndexingProgress { lines_processed: line_offsets.len(), bytes_processed: content.len() });
+    Ok((lines_repaired, lines_dropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problem(content: &[u8]) -> Option<(LineProblem, usize, usize)> {
+        check_line(7, content).map(|invalid| {
+            assert_eq!(invalid.line_number, 7);
+            (invalid.problem, invalid.start, invalid.end)
+        })
+    }
+
+    #[test]
+    fn well_formed_and_empty_lines_pass() {
+        for content in [&b""[..], b"   ", b"{}", br#"{"a": [1, {"b": "}"}], "c": null}"#, b"42", br#""text" "#] {
+            assert!(problem(content).is_none(), "{}", String::from_utf8_lossy(content));
+        }
+    }
+
+    #[test]
+    fn each_problem_is_located() {
+        assert_eq!(problem(b"{\"a\": \"\xff\"}"), Some((LineProblem::InvalidUtf8, 7, 8)));
+        assert_eq!(problem(br#"{"a": [1, 2"#), Some((LineProblem::TruncatedJson, 0, 11)));
+        assert_eq!(problem(br#"{"a": 1} x"#), Some((LineProblem::TrailingGarbage, 9, 10)));
+        assert_eq!(problem(br#"{"a": 1}{"b": 2}"#), Some((LineProblem::TrailingGarbage, 8, 16)));
+        assert_eq!(problem(br#"{"a": tru}"#).map(|p| p.0), Some(LineProblem::InvalidJson));
+        assert_eq!(problem(br#"{"a": 1, "a": 2}"#), Some((LineProblem::DuplicateKey, 9, 12)));
+    }
+
+    #[test]
+    fn duplicate_keys_are_found_per_object() {
+        assert_eq!(duplicate_key(br#"{"a": {"a": 1}, "b": [{"a": 2}, {"a": 3}]}"#), None);
+        assert_eq!(duplicate_key(br#"{"k": "a", "v": ["a", "a"]}"#), None);
+        assert_eq!(duplicate_key(br#"{"a": 1, "x": {"b": 2, "b": 3}}"#), Some(("b".to_string(), 23, 26)));
+        assert_eq!(duplicate_key(br#"{"a\"": 1, "a\"": 2}"#), Some(("a\"".to_string(), 11, 16)));
+    }
+}
@@ -5,6 +5,14 @@ mod indexing_service;
 mod cache_manager;
 mod state;
 mod commands;
+mod search_handler;
+mod utils;
+mod file_content_service;
+mod postings_store;
+mod file_watcher;
+mod schema_inference;
+mod schema_validation;
+mod line_repair;
 
 fn main() {
     let app_state = state::AppState::new();
@@ -13,10 +21,36 @@ fn main() {
         .manage(app_state) // Add AppState to Tauri's managed state
         .invoke_handler(tauri::generate_handler![
             commands::open_file,
+            commands::close_document,
             commands::get_total_lines,
             commands::get_lines,
+            commands::get_lines_at,
             commands::get_line_content,
-            commands::get_indexing_status
+            commands::get_indexing_status,
+            commands::cancel_indexing,
+            commands::set_cache_verification,
+            commands::list_caches,
+            commands::clear_cache,
+            commands::clear_all_caches,
+            commands::set_cache_limit,
+            commands::follow_file,
+            commands::search_file,
+            commands::query_file,
+            commands::infer_schema,
+            commands::export_schema,
+            commands::validate_file,
+            commands::get_validation_results,
+            commands::cancel_validation,
+            commands::scan_invalid_lines,
+            commands::get_invalid_lines,
+            commands::cancel_invalid_lines_scan,
+            commands::repair_lines,
+            commands::cancel_repair,
+            commands::replace_all_in_file,
+            commands::cancel_replace,
+            commands::save_replaced,
+            commands::save_replaced_as,
+            commands::discard_replaced
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
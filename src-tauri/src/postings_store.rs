@@ -0,0 +1,652 @@
+// Compressed, read-only posting lists keyed by byte strings (inverted-index terms or
+// n-grams). Each list is delta + varint encoded, or stored as a bitmap when that is
+// smaller, which is the case for dense n-grams such as `":"`. Lists of the positional
+// index also carry the token positions on each line, and the field value index stores
+// value columns, searched by value without decoding. Keys sit in a sorted
+// dictionary that is binary-searched in place, so a store backed by a memory-mapped cache
+// file answers lookups without deserializing anything up front. Each list carries a checksum
+// that is verified whenever it is read; a mismatch marks the store as damaged.
+//
+// Layout (little-endian):
+//   header   magic, format version, reserved u32, entry count u64, key bytes length u64
+//   entries  one fixed-size entry per key, sorted by key
+//   keys     concatenated key bytes
+//   postings concatenated encoded lists
+use memmap2::Mmap;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Bound;
+use std::sync::atomic::{self, AtomicBool};
+use xxhash_rust::xxh3::xxh3_64;
+
+const MAGIC: &[u8; 8] = b"DOLPOSTS";
+const FORMAT_VERSION: u32 = 3;
+const HEADER_LEN: usize = 32;
+// key start u64, postings start u64, key length u32, postings length u32, count u32, last line u32,
+// checksum u32 (low half of the list's xxh3), encoding u8, padding
+const ENTRY_LEN: usize = 40;
+
+const ENCODING_VARINT: u8 = 0; // Gaps between consecutive line numbers, LEB128
+const ENCODING_BITMAP: u8 = 1; // First line as a varint, then one bit per line from there
+const ENCODING_POSITIONS: u8 = 2; // Per line: line gap, position count, position gaps; all LEB128
+const ENCODING_COLUMN: u8 = 3; // All values as f64, then the line of each as u32; sorted by value
+
+/// Token positions on each line that has the token, ascending by line and by position.
+pub type PositionList = Vec<(u32, Vec<u32>)>;
+
+/// A field's values with the line of each, sorted by value and then line. A line appears
+/// once per value it has in the field.
+pub type ValueColumn = Vec<(f64, u32)>;
+
+/// A posting list as built in memory, before it is encoded into a store.
+pub trait PostingList: Default {
+    fn last_line(&self) -> Option<u32>;
+    /// Number of lines (of values, for a column), stored as the entry's count.
+    fn line_count(&self) -> usize;
+    /// Drops the postings on `line` and later.
+    fn truncate_lines(&mut self, line: u32);
+    /// Appends the postings of `tail`, whose lines all come after the list's own.
+    fn append(&mut self, tail: &Self);
+    fn encode(&self, out: &mut Vec<u8>) -> u8;
+    fn decode(bytes: &[u8], count: usize, encoding: u8) -> Option<Self>;
+}
+
+impl PostingList for Vec<u32> {
+    fn last_line(&self) -> Option<u32> {
+        self.last().copied()
+    }
+
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn truncate_lines(&mut self, line: u32) {
+        self.truncate(self.partition_point(|&l| l < line));
+    }
+
+    fn append(&mut self, tail: &Self) {
+        self.extend_from_slice(tail);
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> u8 {
+        encode_list(self, out)
+    }
+
+    fn decode(bytes: &[u8], count: usize, encoding: u8) -> Option<Self> {
+        match encoding {
+            ENCODING_VARINT => decode_varint_list(bytes, count),
+            ENCODING_BITMAP => decode_bitmap_list(bytes, count),
+            ENCODING_POSITIONS => decode_position_list(bytes, count)
+                .map(|list| list.into_iter().map(|(line, _)| line).collect()),
+            _ => None,
+        }
+    }
+}
+
+impl PostingList for PositionList {
+    fn last_line(&self) -> Option<u32> {
+        self.last().map(|(line, _)| *line)
+    }
+
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn truncate_lines(&mut self, line: u32) {
+        self.truncate(self.partition_point(|(l, _)| *l < line));
+    }
+
+    fn append(&mut self, tail: &Self) {
+        self.extend_from_slice(tail);
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> u8 {
+        out.clear();
+        let mut previous_line = 0;
+        for (line, positions) in self {
+            write_varint(line - previous_line, out);
+            previous_line = *line;
+            write_varint(positions.len() as u32, out);
+            let mut previous_position = 0;
+            for &position in positions {
+                write_varint(position - previous_position, out);
+                previous_position = position;
+            }
+        }
+        ENCODING_POSITIONS
+    }
+
+    fn decode(bytes: &[u8], count: usize, encoding: u8) -> Option<Self> {
+        match encoding {
+            ENCODING_POSITIONS => decode_position_list(bytes, count),
+            _ => None, // Plain line lists have no positions to offer
+        }
+    }
+}
+
+impl PostingList for ValueColumn {
+    fn last_line(&self) -> Option<u32> {
+        self.iter().map(|(_, line)| *line).max()
+    }
+
+    fn line_count(&self) -> usize {
+        self.len()
+    }
+
+    fn truncate_lines(&mut self, line: u32) {
+        self.retain(|(_, l)| *l < line);
+    }
+
+    fn append(&mut self, tail: &Self) {
+        self.extend_from_slice(tail);
+        sort_column(self);
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) -> u8 {
+        out.clear();
+        out.reserve(self.len() * 12);
+        for (value, _) in self {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        for (_, line) in self {
+            out.extend_from_slice(&line.to_le_bytes());
+        }
+        ENCODING_COLUMN
+    }
+
+    fn decode(bytes: &[u8], count: usize, encoding: u8) -> Option<Self> {
+        if encoding != ENCODING_COLUMN || bytes.len() != count.checked_mul(12)? {
+            return None;
+        }
+        let (values, lines) = bytes.split_at(count * 8);
+        Some((0..count).map(|i| (read_f64(values, i * 8), read_u32(lines, i * 4))).collect())
+    }
+}
+
+/// Puts a column built in line order into the order it is stored in.
+pub fn sort_column(column: &mut ValueColumn) {
+    column.sort_unstable_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+}
+
+enum Backing {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+pub struct PostingsStore {
+    backing: Backing,
+    start: usize, // Where the store begins within the backing bytes
+    entry_count: usize,
+    keys_start: usize,
+    postings_start: usize,
+    damaged: AtomicBool, // A list failed its checksum or lies outside the store
+}
+
+struct Entry {
+    key_start: usize,
+    postings_start: usize,
+    key_len: usize,
+    postings_len: usize,
+    count: usize,
+    last_line: u32, // Highest line in the list, 0 if it is empty
+    checksum: u32,
+    encoding: u8,
+}
+
+impl PostingsStore {
+    /// Encodes a freshly built index. Keys are sorted so lookups can binary-search.
+    pub fn from_index<K: AsRef<[u8]> + Eq + Hash, L: PostingList>(index: &HashMap<K, L>) -> Self {
+        let mut writer = PostingsWriter::default();
+        for (key, lines) in sorted_entries(index) {
+            writer.push(key, lines);
+        }
+        writer.finish()
+    }
+
+    /// Returns a copy of the store with the postings of lines indexed again from `from_line`
+    /// on. Stored postings of those lines are replaced, so a last line that was only partly
+    /// written when it was indexed is indexed in full; tail lines must not precede `from_line`.
+    pub fn extend<K: AsRef<[u8]> + Eq + Hash, L: PostingList>(&self, tail: &HashMap<K, L>, from_line: u32) -> Result<Self, String> {
+        let mut writer = PostingsWriter::default();
+        let mut tail = sorted_entries(tail).into_iter().peekable();
+        for position in 0..self.entry_count {
+            let entry = self.entry(position);
+            let key = self.key(&entry)?;
+            while let Some((tail_key, tail_lines)) = tail.next_if(|(tail_key, _)| *tail_key < key) {
+                writer.push(tail_key, tail_lines);
+            }
+            let tail_lines = tail.next_if(|(tail_key, _)| *tail_key == key).map(|(_, tail_lines)| tail_lines);
+            if tail_lines.is_none() && (entry.count == 0 || entry.last_line < from_line) {
+                // Untouched lists are copied without decoding.
+                writer.push_encoded(key, self.encoded(&entry)?, entry.count, entry.last_line, entry.encoding);
+                continue;
+            }
+            let mut lines: L = self.decode(&entry)?;
+            lines.truncate_lines(from_line);
+            if let Some(tail_lines) = tail_lines {
+                lines.append(tail_lines);
+            }
+            if lines.line_count() > 0 {
+                writer.push(key, &lines);
+            }
+        }
+        for (tail_key, tail_lines) in tail {
+            writer.push(tail_key, tail_lines);
+        }
+        Ok(writer.finish())
+    }
+
+    /// Opens a store that starts at byte `start` of a mapped cache file.
+    pub fn from_mmap(mmap: Mmap, start: usize) -> Result<Self, String> {
+        PostingsStore::new(Backing::Mapped(mmap), start)
+    }
+
+    fn new(backing: Backing, start: usize) -> Result<Self, String> {
+        let mut store = PostingsStore { backing, start, entry_count: 0, keys_start: 0, postings_start: 0, damaged: AtomicBool::new(false) };
+        let data = store.as_bytes();
+        if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+            return Err("Not a postings store".to_string());
+        }
+        let version = read_u32(data, 8);
+        if version != FORMAT_VERSION {
+            return Err(format!("Unsupported postings format version {}", version));
+        }
+        let entry_count = usize::try_from(read_u64(data, 16)).map_err(|_| "Postings store is too large".to_string())?;
+        let keys_len = usize::try_from(read_u64(data, 24)).map_err(|_| "Postings store is too large".to_string())?;
+        let keys_start = entry_count.checked_mul(ENTRY_LEN)
+            .and_then(|len| len.checked_add(HEADER_LEN))
+            .ok_or_else(|| "Postings store is corrupt".to_string())?;
+        let postings_start = keys_start.checked_add(keys_len)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| "Postings store is truncated".to_string())?;
+        store.entry_count = entry_count;
+        store.keys_start = keys_start;
+        store.postings_start = postings_start;
+        Ok(store)
+    }
+
+    /// The encoded store, as written to the cache.
+    pub fn as_bytes(&self) -> &[u8] {
+        let backing: &[u8] = match &self.backing {
+            Backing::Owned(bytes) => bytes,
+            Backing::Mapped(mmap) => mmap,
+        };
+        backing.get(self.start..).unwrap_or(&[])
+    }
+
+    /// Length of the header, entry table and keys at the start of the store, which every
+    /// lookup reads; the posting lists follow.
+    pub fn head_len(&self) -> usize {
+        self.postings_start
+    }
+
+    /// Whether a lookup has found a posting list that fails its checksum. The store's cache
+    /// is then of no use and the index has to be built again.
+    pub fn is_damaged(&self) -> bool {
+        self.damaged.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Checks every posting list against its checksum, reading the whole store.
+    pub fn is_intact(&self) -> bool {
+        (0..self.entry_count).all(|position| self.encoded(&self.entry(position)).is_ok())
+    }
+
+    /// Decodes the posting list of `key`; unknown keys have no lines.
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u32>, String> {
+        self.lookup(key)
+    }
+
+    /// Decodes the lines and token positions of `key` in a positional index.
+    pub fn get_positions(&self, key: &[u8]) -> Result<PositionList, String> {
+        self.lookup(key)
+    }
+
+    /// The lines with a value within the bounds in the column `key` of a field value index,
+    /// ascending. Binary-searches the stored column rather than decoding it.
+    pub fn lines_in_range(&self, key: &[u8], lower: Bound<f64>, upper: Bound<f64>) -> Result<Vec<u32>, String> {
+        let entry = match self.find(key)? {
+            Some(entry) => entry,
+            None => return Ok(Vec::new()),
+        };
+        let bytes = self.encoded(&entry)?;
+        if entry.encoding != ENCODING_COLUMN || Some(bytes.len()) != entry.count.checked_mul(12) {
+            return Err("Postings store is corrupt: not a value column".to_string());
+        }
+        let (values, lines) = bytes.split_at(entry.count * 8);
+        let value = |i: usize| read_f64(values, i * 8);
+        let start = match lower {
+            Bound::Included(low) => partition_point(entry.count, |i| value(i) < low),
+            Bound::Excluded(low) => partition_point(entry.count, |i| value(i) <= low),
+            Bound::Unbounded => 0,
+        };
+        let end = match upper {
+            Bound::Included(high) => partition_point(entry.count, |i| value(i) <= high),
+            Bound::Excluded(high) => partition_point(entry.count, |i| value(i) < high),
+            Bound::Unbounded => entry.count,
+        };
+        let mut matching: Vec<u32> = (start..end.max(start)).map(|i| read_u32(lines, i * 4)).collect();
+        matching.sort_unstable();
+        matching.dedup();
+        Ok(matching)
+    }
+
+    fn lookup<L: PostingList>(&self, key: &[u8]) -> Result<L, String> {
+        match self.find(key)? {
+            Some(entry) => self.decode(&entry),
+            None => Ok(L::default()),
+        }
+    }
+
+    fn find(&self, key: &[u8]) -> Result<Option<Entry>, String> {
+        let (mut low, mut high) = (0, self.entry_count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let entry = self.entry(mid);
+            match self.key(&entry)?.cmp(key) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Ok(Some(entry)),
+            }
+        }
+        Ok(None)
+    }
+
+    fn entry(&self, position: usize) -> Entry {
+        let data = self.as_bytes();
+        let at = HEADER_LEN + position * ENTRY_LEN;
+        Entry {
+            key_start: read_u64(data, at) as usize,
+            postings_start: read_u64(data, at + 8) as usize,
+            key_len: read_u32(data, at + 16) as usize,
+            postings_len: read_u32(data, at + 20) as usize,
+            count: read_u32(data, at + 24) as usize,
+            last_line: read_u32(data, at + 28),
+            checksum: read_u32(data, at + 32),
+            encoding: data[at + 36],
+        }
+    }
+
+    fn key(&self, entry: &Entry) -> Result<&[u8], String> {
+        let start = self.keys_start.saturating_add(entry.key_start);
+        self.as_bytes()[..self.postings_start]
+            .get(start..start.saturating_add(entry.key_len))
+            .ok_or_else(|| "Postings store is corrupt: key out of bounds".to_string())
+    }
+
+    fn encoded(&self, entry: &Entry) -> Result<&[u8], String> {
+        let start = self.postings_start.saturating_add(entry.postings_start);
+        match self.as_bytes().get(start..start.saturating_add(entry.postings_len)) {
+            Some(bytes) if xxh3_64(bytes) as u32 == entry.checksum => Ok(bytes),
+            Some(_) => Err(self.mark_damaged("list checksum mismatch")),
+            None => Err(self.mark_damaged("list out of bounds")),
+        }
+    }
+
+    fn mark_damaged(&self, reason: &str) -> String {
+        self.damaged.store(true, atomic::Ordering::Relaxed);
+        format!("Postings store is corrupt: {}", reason)
+    }
+
+    fn decode<L: PostingList>(&self, entry: &Entry) -> Result<L, String> {
+        let bytes = self.encoded(entry)?;
+        L::decode(bytes, entry.count, entry.encoding)
+            .filter(|list| list.line_count() == entry.count)
+            .ok_or_else(|| "Postings store is corrupt: undecodable list".to_string())
+    }
+}
+
+// Accumulates entries in key order and lays them out in the store format.
+#[derive(Default)]
+struct PostingsWriter {
+    entry_count: usize,
+    entries: Vec<u8>,
+    key_bytes: Vec<u8>,
+    postings: Vec<u8>,
+    encoded: Vec<u8>,
+}
+
+impl PostingsWriter {
+    fn push<L: PostingList>(&mut self, key: &[u8], list: &L) {
+        let mut encoded = std::mem::take(&mut self.encoded);
+        let encoding = list.encode(&mut encoded);
+        self.push_encoded(key, &encoded, list.line_count(), list.last_line().unwrap_or(0), encoding);
+        self.encoded = encoded;
+    }
+
+    fn push_encoded(&mut self, key: &[u8], encoded: &[u8], count: usize, last_line: u32, encoding: u8) {
+        self.entries.extend_from_slice(&(self.key_bytes.len() as u64).to_le_bytes());
+        self.entries.extend_from_slice(&(self.postings.len() as u64).to_le_bytes());
+        self.entries.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.entries.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+        self.entries.extend_from_slice(&(count as u32).to_le_bytes());
+        self.entries.extend_from_slice(&last_line.to_le_bytes());
+        self.entries.extend_from_slice(&(xxh3_64(encoded) as u32).to_le_bytes());
+        self.entries.extend_from_slice(&[encoding, 0, 0, 0]);
+        self.key_bytes.extend_from_slice(key);
+        self.postings.extend_from_slice(encoded);
+        self.entry_count += 1;
+    }
+
+    fn finish(self) -> PostingsStore {
+        let mut data = Vec::with_capacity(HEADER_LEN + self.entries.len() + self.key_bytes.len() + self.postings.len());
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&(self.entry_count as u64).to_le_bytes());
+        data.extend_from_slice(&(self.key_bytes.len() as u64).to_le_bytes());
+        data.extend_from_slice(&self.entries);
+        data.extend_from_slice(&self.key_bytes);
+        data.extend_from_slice(&self.postings);
+        PostingsStore::new(Backing::Owned(data), 0).expect("freshly encoded postings store is well-formed")
+    }
+}
+
+fn sorted_entries<K: AsRef<[u8]> + Eq + Hash, L>(index: &HashMap<K, L>) -> Vec<(&[u8], &L)> {
+    let mut entries: Vec<(&[u8], &L)> = index.iter().map(|(key, list)| (key.as_ref(), list)).collect();
+    entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+// Encodes sorted, deduplicated line numbers into `out` and returns the encoding used.
+fn encode_list(lines: &[u32], out: &mut Vec<u8>) -> u8 {
+    out.clear();
+    let mut previous = 0;
+    for &line in lines {
+        write_varint(line - previous, out);
+        previous = line;
+    }
+    if let (Some(&first), Some(&last)) = (lines.first(), lines.last()) {
+        let bitmap_len = ((last - first) / 8 + 1) as usize;
+        if bitmap_len + 5 < out.len() {
+            out.clear();
+            write_varint(first, out);
+            let bitmap_start = out.len();
+            out.resize(bitmap_start + bitmap_len, 0);
+            for &line in lines {
+                let bit = (line - first) as usize;
+                out[bitmap_start + bit / 8] |= 1 << (bit % 8);
+            }
+            return ENCODING_BITMAP;
+        }
+    }
+    ENCODING_VARINT
+}
+
+fn decode_varint_list(bytes: &[u8], count: usize) -> Option<Vec<u32>> {
+    let mut lines = Vec::with_capacity(count.min(bytes.len()));
+    let mut position = 0;
+    let mut previous: u32 = 0;
+    while position < bytes.len() {
+        previous = previous.checked_add(read_varint(bytes, &mut position)?)?;
+        lines.push(previous);
+    }
+    Some(lines)
+}
+
+fn decode_bitmap_list(bytes: &[u8], count: usize) -> Option<Vec<u32>> {
+    let mut position = 0;
+    let first = read_varint(bytes, &mut position)?;
+    let mut lines = Vec::with_capacity(count.min(bytes.len() * 8));
+    for (byte_index, &byte) in bytes[position..].iter().enumerate() {
+        let byte_base = u32::try_from(byte_index).ok()?.checked_mul(8)?;
+        let mut bits = byte;
+        while bits != 0 {
+            lines.push(first.checked_add(byte_base + bits.trailing_zeros())?);
+            bits &= bits - 1;
+        }
+    }
+    Some(lines)
+}
+
+fn decode_position_list(bytes: &[u8], count: usize) -> Option<PositionList> {
+    let mut list = Vec::with_capacity(count.min(bytes.len() / 3));
+    let mut position = 0;
+    let mut line: u32 = 0;
+    while position < bytes.len() {
+        line = line.checked_add(read_varint(bytes, &mut position)?)?;
+        let position_count = read_varint(bytes, &mut position)? as usize;
+        let mut positions = Vec::with_capacity(position_count.min(bytes.len() - position));
+        let mut token_position: u32 = 0;
+        for _ in 0..position_count {
+            token_position = token_position.checked_add(read_varint(bytes, &mut position)?)?;
+            positions.push(token_position);
+        }
+        list.push((line, positions));
+    }
+    Some(list)
+}
+
+fn write_varint(mut value: u32, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(bytes: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*position)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+fn read_f64(data: &[u8], at: usize) -> f64 {
+    f64::from_le_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+// The number of leading indexes in `0..len` for which `below` holds; it must hold for a
+// prefix of them only.
+fn partition_point(len: usize, below: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if below(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip<L: PostingList>(list: &L) -> (u8, L) {
+        let mut out = Vec::new();
+        let encoding = list.encode(&mut out);
+        (encoding, L::decode(&out, list.line_count(), encoding).unwrap())
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        let mut out = Vec::new();
+        for value in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX] {
+            out.clear();
+            write_varint(value, &mut out);
+            let mut position = 0;
+            assert_eq!(read_varint(&out, &mut position), Some(value));
+            assert_eq!(position, out.len());
+        }
+        assert_eq!(read_varint(&[0x80], &mut 0), None);
+        assert_eq!(read_varint(&[0xff; 6], &mut 0), None);
+    }
+
+    #[test]
+    fn sparse_lists_use_varints() {
+        let lines = vec![3, 1_000, 70_000, 4_000_000];
+        assert_eq!(round_trip(&lines), (ENCODING_VARINT, lines));
+    }
+
+    #[test]
+    fn dense_lists_use_bitmaps() {
+        let lines: Vec<u32> = (500..600).filter(|line| line % 3 != 0).collect();
+        assert_eq!(round_trip(&lines), (ENCODING_BITMAP, lines));
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let list: PositionList = vec![(0, vec![0, 4]), (7, vec![2]), (300, vec![1, 200, 100_000])];
+        assert_eq!(round_trip(&list), (ENCODING_POSITIONS, list.clone()));
+        // Plain lookups on a positional index see just the lines.
+        let mut out = Vec::new();
+        list.encode(&mut out);
+        assert_eq!(Vec::<u32>::decode(&out, list.len(), ENCODING_POSITIONS), Some(vec![0, 7, 300]));
+    }
+
+    #[test]
+    fn extend_replaces_lines_from_the_resume_line() {
+        let mut index = HashMap::new();
+        index.insert("kept", vec![1, 2]);
+        index.insert("partial", vec![0, 5]); // Line 5 was only partly written
+        index.insert("gone", vec![5]);
+        let store = PostingsStore::from_index(&index);
+        let mut tail = HashMap::new();
+        tail.insert("partial", vec![6]);
+        tail.insert("new", vec![5, 6]);
+        let extended = store.extend(&tail, 5).unwrap();
+        assert_eq!(extended.get(b"kept").unwrap(), vec![1, 2]);
+        assert_eq!(extended.get(b"partial").unwrap(), vec![0, 6]);
+        assert_eq!(extended.get(b"gone").unwrap(), Vec::<u32>::new());
+        assert_eq!(extended.get(b"new").unwrap(), vec![5, 6]);
+        assert_eq!(extended.entry_count, 3);
+    }
+
+    #[test]
+    fn corrupt_posting_lists_fail_their_checksum() {
+        let mut index = HashMap::new();
+        index.insert("first", vec![1, 2, 3]);
+        index.insert("second", vec![10, 20_000]);
+        let store = PostingsStore::from_index(&index);
+        assert!(store.is_intact());
+
+        let mut bytes = store.as_bytes().to_vec();
+        let second = store.find(b"second").unwrap().unwrap();
+        bytes[store.head_len() + second.postings_start] ^= 0x01;
+        let corrupted = PostingsStore::new(Backing::Owned(bytes), 0).unwrap();
+        // The header and keys are intact, so the store opens and other lists still read.
+        assert_eq!(corrupted.get(b"first").unwrap(), vec![1, 2, 3]);
+        assert!(!corrupted.is_damaged());
+        assert!(corrupted.get(b"second").is_err());
+        assert!(corrupted.is_damaged());
+        assert!(!corrupted.is_intact());
+        assert!(corrupted.extend(&HashMap::<&str, Vec<u32>>::new(), 0).is_err());
+    }
+}
@@ -0,0 +1,427 @@
+// Infers the shape of a JSONL file: for every JSON path, which types occur there, on how
+// many lines, and what the values look like. Statistics are gathered per chunk by the
+// indexing pass (see `build_indexes`) and merged, and can be exported as a JSON Schema.
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use super::utils::token_utils::{self, PathStep};
+
+const JSON_SCHEMA_DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+const MAX_EXAMPLES: usize = 3;
+const MAX_EXAMPLE_CHARS: usize = 80;
+// Files with data-dependent keys (IDs used as keys) would otherwise grow a path per value.
+const MAX_PATHS: usize = 10_000;
+
+// Indexes into `PathStats::type_counts`, named as in JSON Schema.
+const TYPE_NAMES: [&str; 7] = ["object", "array", "string", "integer", "number", "boolean", "null"];
+const OBJECT: usize = 0;
+const ARRAY: usize = 1;
+const STRING: usize = 2;
+const INTEGER: usize = 3;
+const NUMBER: usize = 4;
+const BOOLEAN: usize = 5;
+const NULL: usize = 6;
+
+/// Statistics of the JSON lines seen so far.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct SchemaStats {
+    json_lines: u64,
+    invalid_lines: u64, // Non-empty lines that are not JSON
+    paths: HashMap<String, PathStats>,
+    paths_truncated: bool,
+}
+
+// Paths keep the keys' case here, unlike field terms; a schema should name them as written.
+#[derive(Clone, Serialize, Deserialize)]
+struct PathStats {
+    parent: Option<String>, // None for the root
+    key: Option<String>,    // None for the root and array elements
+    type_counts: [u64; 7],
+    lines: u64,             // Lines the path occurs on
+    #[serde(skip)]
+    last_line: Option<u32>, // To count each line once; chunk-local while gathering
+    string_lengths: Option<(u64, u64)>,
+    array_lengths: BTreeMap<u32, u64>, // Power-of-two bucket, see `length_bucket`
+    examples: Vec<Value>,
+}
+
+impl PathStats {
+    fn new(path: &str, step: PathStep) -> Self {
+        let (parent, key) = match step {
+            PathStep::Root => (None, None),
+            PathStep::Key(key) => {
+                let parent = &path[..path.len() - key.len()];
+                (Some(parent.strip_suffix('.').unwrap_or(parent).to_string()), Some(key.to_string()))
+            }
+            PathStep::Element => (Some(path[..path.len() - 2].to_string()), None),
+        };
+        PathStats {
+            parent,
+            key,
+            type_counts: [0; 7],
+            lines: 0,
+            last_line: None,
+            string_lengths: None,
+            array_lengths: BTreeMap::new(),
+            examples: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, line_num: u32, value: &Value) {
+        if self.last_line != Some(line_num) {
+            self.last_line = Some(line_num);
+            self.lines += 1;
+        }
+        let type_index = match value {
+            Value::Object(_) => OBJECT,
+            Value::Array(items) => {
+                *self.array_lengths.entry(length_bucket(items.len())).or_default() += 1;
+                ARRAY
+            }
+            Value::String(s) => {
+                let length = s.chars().count() as u64;
+                self.string_lengths = Some(match self.string_lengths {
+                    Some((min, max)) => (min.min(length), max.max(length)),
+                    None => (length, length),
+                });
+                STRING
+            }
+            Value::Number(n) if n.is_i64() || n.is_u64() => INTEGER,
+            Value::Number(_) => NUMBER,
+            Value::Bool(_) => BOOLEAN,
+            Value::Null => NULL,
+        };
+        self.type_counts[type_index] += 1;
+        if type_index != OBJECT && type_index != ARRAY && type_index != NULL {
+            self.add_example(value);
+        }
+    }
+
+    fn add_example(&mut self, value: &Value) {
+        if self.examples.len() >= MAX_EXAMPLES || self.examples.contains(value) {
+            return;
+        }
+        let example = match value {
+            Value::String(s) if s.chars().count() > MAX_EXAMPLE_CHARS => {
+                Value::String(format!("{}…", s.chars().take(MAX_EXAMPLE_CHARS).collect::<String>()))
+            }
+            _ => value.clone(),
+        };
+        if !self.examples.contains(&example) {
+            self.examples.push(example);
+        }
+    }
+
+    fn merge(&mut self, other: PathStats) {
+        for (count, other_count) in self.type_counts.iter_mut().zip(other.type_counts) {
+            *count += other_count;
+        }
+        self.lines += other.lines;
+        self.string_lengths = match (self.string_lengths, other.string_lengths) {
+            (Some((min, max)), Some((other_min, other_max))) => Some((min.min(other_min), max.max(other_max))),
+            (lengths, other_lengths) => lengths.or(other_lengths),
+        };
+        for (bucket, count) in other.array_lengths {
+            *self.array_lengths.entry(bucket).or_default() += count;
+        }
+        for example in &other.examples {
+            self.add_example(example);
+        }
+    }
+
+    fn values(&self) -> u64 {
+        self.type_counts.iter().sum()
+    }
+}
+
+// Array lengths are bucketed as 0, 1, 2-3, 4-7, 8-15 and so on.
+fn length_bucket(length: usize) -> u32 {
+    match length {
+        0 => 0,
+        _ => 1 + length.ilog2(),
+    }
+}
+
+fn bucket_range(bucket: u32) -> (u64, u64) {
+    match bucket {
+        0 => (0, 0),
+        _ => (1 << (bucket - 1), (1 << bucket) - 1),
+    }
+}
+
+impl SchemaStats {
+    /// Adds one non-empty line, `None` if it is not JSON.
+    pub fn add_line(&mut self, line_num: u32, json_value: Option<&Value>) {
+        let json_value = match json_value {
+            Some(json_value) => json_value,
+            None => {
+                self.invalid_lines += 1;
+                return;
+            }
+        };
+        self.json_lines += 1;
+        let (paths, paths_truncated) = (&mut self.paths, &mut self.paths_truncated);
+        token_utils::walk_values(json_value, false, &mut |path, step, value| {
+            if !paths.contains_key(path) {
+                if paths.len() >= MAX_PATHS {
+                    *paths_truncated = true;
+                    return;
+                }
+                paths.insert(path.to_string(), PathStats::new(path, step));
+            }
+            if let Some(stats) = paths.get_mut(path) {
+                stats.add(line_num, value);
+            }
+        });
+    }
+
+    /// Adds the statistics of a later part of the file.
+    pub fn merge(&mut self, other: SchemaStats) {
+        self.json_lines += other.json_lines;
+        self.invalid_lines += other.invalid_lines;
+        self.paths_truncated |= other.paths_truncated;
+        for (path, stats) in other.paths {
+            if let Some(existing) = self.paths.get_mut(&path) {
+                existing.merge(stats);
+            } else if self.paths.len() < MAX_PATHS {
+                self.paths.insert(path, stats);
+            } else {
+                self.paths_truncated = true;
+            }
+        }
+    }
+
+    pub fn report(&self, total_lines: usize) -> SchemaReport {
+        let mut paths: Vec<PathReport> = self.paths.iter()
+            .map(|(path, stats)| PathReport {
+                path: path.clone(),
+                types: TYPE_NAMES.iter().zip(stats.type_counts)
+                    .filter(|(_, count)| *count > 0)
+                    .map(|(name, count)| (*name, count))
+                    .collect(),
+                occurrences: stats.values(),
+                presence: if self.json_lines == 0 { 0.0 } else { 100.0 * stats.lines as f64 / self.json_lines as f64 },
+                null_count: stats.type_counts[NULL],
+                examples: stats.examples.clone(),
+                min_length: stats.string_lengths.map(|(min, _)| min),
+                max_length: stats.string_lengths.map(|(_, max)| max),
+                array_lengths: stats.array_lengths.iter()
+                    .map(|(&bucket, &count)| {
+                        let (min, max) = bucket_range(bucket);
+                        LengthBucket { min, max, count }
+                    })
+                    .collect(),
+            })
+            .collect();
+        paths.sort_by(|a, b| a.path.cmp(&b.path));
+        SchemaReport {
+            total_lines,
+            json_lines: self.json_lines,
+            invalid_lines: self.invalid_lines,
+            paths_truncated: self.paths_truncated,
+            paths,
+            json_schema: self.json_schema(),
+        }
+    }
+
+    /// The observed structure as a JSON Schema (draft 2020-12). Keys present in every
+    /// object at their path are required; examples are included, lengths are not, so the
+    /// schema describes the data rather than constraining it to what happened to occur.
+    pub fn json_schema(&self) -> Value {
+        let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (path, stats) in &self.paths {
+            if let Some(parent) = &stats.parent {
+                children.entry(parent.as_str()).or_default().push(path.as_str());
+            }
+        }
+        let mut schema = match self.paths.get("") {
+            Some(_) => self.path_schema("", &children),
+            None => Map::new(),
+        };
+        schema.insert("$schema".to_string(), json!(JSON_SCHEMA_DRAFT));
+        Value::Object(schema)
+    }
+
+    fn path_schema(&self, path: &str, children: &HashMap<&str, Vec<&str>>) -> Map<String, Value> {
+        let stats = &self.paths[path];
+        let mut schema = Map::new();
+
+        let mut types: Vec<&str> = TYPE_NAMES.iter().zip(stats.type_counts)
+            .filter(|(_, count)| *count > 0)
+            .map(|(name, _)| *name)
+            .collect();
+        if stats.type_counts[NUMBER] > 0 {
+            types.retain(|name| *name != "integer"); // Integers are numbers too
+        }
+        match types.as_slice() {
+            [single] => { schema.insert("type".to_string(), json!(single)); }
+            _ => { schema.insert("type".to_string(), json!(types)); }
+        }
+
+        let child_paths = children.get(path).map(Vec::as_slice).unwrap_or(&[]);
+        if stats.type_counts[OBJECT] > 0 {
+            let mut properties = Map::new();
+            let mut required = Vec::new();
+            for &child in child_paths {
+                let child_stats = &self.paths[child];
+                if let Some(key) = &child_stats.key {
+                    properties.insert(key.clone(), Value::Object(self.path_schema(child, children)));
+                    // Each object has a key at most once, so it is in all of them if it
+                    // occurred as often as they did.
+                    if child_stats.values() == stats.type_counts[OBJECT] {
+                        required.push(key.clone());
+                    }
+                }
+            }
+            schema.insert("properties".to_string(), Value::Object(properties));
+            if !required.is_empty() {
+                required.sort();
+                schema.insert("required".to_string(), json!(required));
+            }
+        }
+        if stats.type_counts[ARRAY] > 0 {
+            if let Some(&items) = child_paths.iter().find(|&&child| self.paths[child].key.is_none()) {
+                schema.insert("items".to_string(), Value::Object(self.path_schema(items, children)));
+            }
+        }
+        if !stats.examples.is_empty() {
+            schema.insert("examples".to_string(), json!(stats.examples));
+        }
+        schema
+    }
+}
+
+/// The schema statistics of an open file. Those of its complete lines are extended as lines
+/// are appended; an unterminated last line may still grow, so it is kept apart until it is
+/// complete.
+pub struct FileSchemaStats {
+    pub covered: u64, // Bytes of complete lines in `complete`
+    pub complete: SchemaStats,
+    pub unterminated: SchemaStats,
+}
+
+impl FileSchemaStats {
+    pub fn report(&self, total_lines: usize) -> SchemaReport {
+        if self.unterminated.json_lines == 0 && self.unterminated.invalid_lines == 0 {
+            return self.complete.report(total_lines);
+        }
+        let mut stats = self.complete.clone();
+        stats.merge(self.unterminated.clone());
+        stats.report(total_lines)
+    }
+}
+
+/// Returned by `infer_schema`.
+#[derive(Clone, Serialize, Debug)]
+pub struct SchemaReport {
+    pub total_lines: usize,
+    pub json_lines: u64,
+    pub invalid_lines: u64,     // Non-empty lines that are not JSON
+    pub paths_truncated: bool,  // More distinct paths than are tracked; the rest are left out
+    pub paths: Vec<PathReport>, // Sorted by path
+    pub json_schema: Value,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct PathReport {
+    pub path: String, // As in field queries, with the keys' case kept; the root is ""
+    pub types: BTreeMap<&'static str, u64>, // Occurrences per JSON Schema type name
+    pub occurrences: u64,
+    pub presence: f64, // Percentage of JSON lines the path occurs on
+    pub null_count: u64,
+    pub examples: Vec<Value>, // A few distinct scalar values, long strings cut short
+    pub min_length: Option<u64>, // Of string values, in characters
+    pub max_length: Option<u64>,
+    pub array_lengths: Vec<LengthBucket>, // Distribution of array lengths, ascending
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct LengthBucket {
+    pub min: u64,
+    pub max: u64,
+    pub count: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats_of(lines: &[&str]) -> SchemaStats {
+        let mut stats = SchemaStats::default();
+        for (line_num, line) in lines.iter().enumerate() {
+            stats.add_line(line_num as u32, serde_json::from_str::<Value>(line).ok().as_ref());
+        }
+        stats
+    }
+
+    const LINES: [&str; 4] = [
+        r#"{"id": 1, "name": "ab", "tags": ["x", "y"]}"#,
+        r#"{"id": 2.5, "name": null}"#,
+        "not json",
+        r#"{"id": 3, "tags": []}"#,
+    ];
+
+    #[test]
+    fn merged_chunks_report_like_one_pass() {
+        let whole = stats_of(&LINES);
+        for split in 0..=LINES.len() {
+            let mut merged = stats_of(&LINES[..split]);
+            merged.merge(stats_of(&LINES[split..]));
+            assert_eq!(
+                serde_json::to_value(merged.report(4)).unwrap(),
+                serde_json::to_value(whole.report(4)).unwrap(),
+                "split at {}", split,
+            );
+        }
+    }
+
+    #[test]
+    fn report_describes_each_path() {
+        let report = stats_of(&LINES).report(4);
+        assert_eq!((report.total_lines, report.json_lines, report.invalid_lines), (4, 3, 1));
+        assert!(!report.paths_truncated);
+        let paths: Vec<&str> = report.paths.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, vec!["", "id", "name", "tags", "tags[]"]);
+
+        let id = &report.paths[1];
+        assert_eq!(id.types, BTreeMap::from([("integer", 2), ("number", 1)]));
+        assert_eq!(id.presence, 100.0);
+        assert_eq!(id.examples, vec![json!(1), json!(2.5), json!(3)]);
+
+        let name = &report.paths[2];
+        assert_eq!(name.types, BTreeMap::from([("null", 1), ("string", 1)]));
+        assert_eq!(name.null_count, 1);
+        assert_eq!((name.min_length, name.max_length), (Some(2), Some(2)));
+        assert!((name.presence - 200.0 / 3.0).abs() < 1e-9);
+
+        let tags = &report.paths[3];
+        let buckets: Vec<(u64, u64, u64)> = tags.array_lengths.iter().map(|b| (b.min, b.max, b.count)).collect();
+        assert_eq!(buckets, vec![(0, 0, 1), (2, 3, 1)]);
+        assert_eq!(report.paths[4].occurrences, 2);
+
+        assert_eq!(report.json_schema, json!({
+            "$schema": JSON_SCHEMA_DRAFT,
+            "type": "object",
+            "required": ["id"],
+            "properties": {
+                "id": {"type": "number", "examples": [1, 2.5, 3]},
+                "name": {"type": ["string", "null"], "examples": ["ab"]},
+                "tags": {"type": "array", "items": {"type": "string", "examples": ["x", "y"]}},
+            },
+        }));
+    }
+
+    #[test]
+    fn merging_past_the_path_limit_truncates() {
+        let keys: Map<String, Value> = (0..MAX_PATHS - 1).map(|i| (format!("k{}", i), json!(i))).collect();
+        let mut stats = SchemaStats::default();
+        stats.add_line(0, Some(&Value::Object(keys)));
+        stats.merge(stats_of(&[r#"{"k0": 1}"#]));
+        assert!(!stats.report(2).paths_truncated);
+        stats.merge(stats_of(&[r#"{"other": 1}"#]));
+        let report = stats.report(3);
+        assert!(report.paths_truncated);
+        assert_eq!(report.paths.len(), MAX_PATHS);
+    }
+}
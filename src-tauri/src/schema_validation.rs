@@ -0,0 +1,164 @@
+// Validates the lines of a document against a JSON Schema. Every violation is recorded with
+// the line, a JSON pointer to the offending value and the schema keyword that failed, so the
+// UI can page through them and jump to each; lines that are not JSON at all are reported as
+// parse errors at the byte where parsing failed.
+use jsonschema::Validator;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+
+use super::state::DocId;
+
+/// Violations kept for paging; later ones are only counted.
+pub const MAX_VALIDATION_VIOLATIONS: usize = 100_000;
+const MAX_MESSAGE_CHARS: usize = 500;
+/// Rule reported for lines that are not valid JSON.
+pub const PARSE_ERROR_RULE: &str = "parse";
+
+pub fn compile_schema(schema_path: &str) -> Result<Validator, String> {
+    let text = fs::read_to_string(schema_path)
+        .map_err(|e| format!("Failed to read schema {}: {}", schema_path, e))?;
+    let schema: Value = serde_json::from_str(&text)
+        .map_err(|e| format!("Schema {} is not valid JSON: {}", schema_path, e))?;
+    jsonschema::validator_for(&schema)
+        .map_err(|e| format!("Schema {} is invalid: {}", schema_path, e))
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct Violation {
+    pub line_number: u32,
+    pub pointer: String, // JSON pointer into the line, "" for the line as a whole
+    pub rule: String,    // Schema keyword that failed, or PARSE_ERROR_RULE
+    pub message: String,
+    pub byte_offset: Option<usize>, // Where parsing stopped, for parse errors
+}
+
+/// Checks one line (content without line ending, numbered `line_number`), adding its
+/// violations to `violations`. Empty lines are skipped.
+pub fn check_line(validator: &Validator, line_number: u32, content: &[u8], violations: &mut Vec<Violation>) {
+    if content.is_empty() {
+        return;
+    }
+    let instance: Value = match serde_json::from_slice(content) {
+        Ok(instance) => instance,
+        Err(e) => {
+            // The position is already in `byte_offset`; keep the message short.
+            let message = e.to_string();
+            let message = message.rsplit_once(" at line ").map_or(message.as_str(), |(message, _)| message);
+            violations.push(Violation {
+                line_number,
+                pointer: String::new(),
+                rule: PARSE_ERROR_RULE.to_string(),
+                message: message.to_string(),
+                byte_offset: Some(e.column().saturating_sub(1)), // Lines are single-line JSON, columns count bytes
+            });
+            return;
+        }
+    };
+    for error in validator.iter_errors(&instance) {
+        let rule = error.schema_path.as_str().rsplit('/').next().filter(|rule| !rule.is_empty()).unwrap_or("schema");
+        let mut message = error.to_string();
+        if let Some((cut, _)) = message.char_indices().nth(MAX_MESSAGE_CHARS) {
+            message.truncate(cut);
+            message.push('…');
+        }
+        violations.push(Violation {
+            line_number,
+            pointer: error.instance_path.as_str().to_string(),
+            rule: rule.to_string(),
+            message,
+            byte_offset: None,
+        });
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationState {
+    Running,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+/// Payload of the `validation_status_update` event; also part of every results page.
+#[derive(Clone, Serialize, Debug)]
+pub struct ValidationStatus {
+    pub doc_id: DocId,
+    pub schema_path: String,
+    pub state: ValidationState,
+    pub message: String,
+    pub lines_checked: usize,
+    pub total_lines: usize,
+    pub total_violations: usize,
+    pub invalid_lines: usize, // Lines with at least one violation
+    pub parse_errors: usize,
+    pub rule_counts: BTreeMap<String, usize>, // Violations per schema keyword
+    pub truncated: bool, // More than MAX_VALIDATION_VIOLATIONS; only the first are listed
+}
+
+/// Validation results of a document so far.
+pub struct ValidationResults {
+    pub status: ValidationStatus,
+    pub violations: Vec<Violation>, // Ascending by line
+}
+
+impl ValidationResults {
+    pub fn new(doc_id: DocId, schema_path: &str, total_lines: usize) -> Self {
+        ValidationResults {
+            status: ValidationStatus {
+                doc_id,
+                schema_path: schema_path.to_string(),
+                state: ValidationState::Running,
+                message: "Validating...".to_string(),
+                lines_checked: 0,
+                total_lines,
+                total_violations: 0,
+                invalid_lines: 0,
+                parse_errors: 0,
+                rule_counts: BTreeMap::new(),
+                truncated: false,
+            },
+            violations: Vec::new(),
+        }
+    }
+
+    /// Adds the violations of a chunk of `line_count` lines starting at `first_line`, whose
+    /// line numbers are relative to it. Chunks arrive in file order.
+    pub fn add_chunk(&mut self, violations: Vec<Violation>, first_line: u32, line_count: u32) {
+        let status = &mut self.status;
+        status.lines_checked += line_count as usize;
+        status.total_violations += violations.len();
+        let mut previous_line = None;
+        for mut violation in violations {
+            violation.line_number += first_line;
+            if previous_line != Some(violation.line_number) {
+                previous_line = Some(violation.line_number);
+                status.invalid_lines += 1;
+            }
+            if violation.rule == PARSE_ERROR_RULE {
+                status.parse_errors += 1;
+            }
+            *status.rule_counts.entry(violation.rule.clone()).or_default() += 1;
+            if self.violations.len() < MAX_VALIDATION_VIOLATIONS {
+                self.violations.push(violation);
+            } else {
+                status.truncated = true;
+            }
+        }
+    }
+
+    pub fn finish(&mut self, state: ValidationState, message: &str) {
+        self.status.state = state;
+        self.status.message = message.to_string();
+    }
+}
+
+/// Returned by `get_validation_results`.
+#[derive(Clone, Serialize, Debug)]
+pub struct ValidationPage {
+    pub status: ValidationStatus,
+    pub offset: usize,
+    pub violations: Vec<Violation>,
+}
@@ -0,0 +1,801 @@
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use regex::bytes::{Captures, Regex, RegexBuilder};
+use regex_syntax::hir::{Class, Hir, HirKind};
+use regex_syntax::ParserBuilder;
+
+use super::file_content_service::FileContent;
+use super::indexing_service::{split_line_ending, IndexSettings, IndexingProgress, LineOffset, PROGRESS_INTERVAL_LINES};
+use super::postings_store::{PositionList, PostingsStore};
+use super::utils::ngram_query::{self, NGramQuery};
+use super::utils::field_values;
+use super::utils::query_parser::Query;
+use super::utils::token_utils::{self, UNPARSED_LINE_TERM};
+
+/// Upper bound on the number of matches returned to the frontend for one search.
+pub const MAX_SEARCH_RESULTS: usize = 100_000;
+
+pub const REPLACE_CANCELLED: &str = "Replace cancelled";
+
+/// A run of consecutive bytes that every match must contain.
+/// Each position holds the byte values allowed there (one, or an ASCII case pair).
+type LiteralRun = Vec<Vec<u8>>;
+
+#[derive(Clone, Serialize, Debug)]
+pub struct SearchMatch {
+    pub line_number: usize,
+    pub start: usize, // Byte offset within the line
+    pub end: usize,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct SearchResults {
+    pub matches: Vec<SearchMatch>,
+    pub total_matches: usize,
+    pub lines_scanned: usize,
+    pub truncated: bool,
+    pub strategy: String,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct ReplaceSummary {
+    pub replacements: usize,
+    pub lines_changed: usize,
+    pub lines_scanned: usize,
+    pub strategy: String,
+}
+
+#[derive(Clone, Serialize, Debug)]
+pub struct QueryResults {
+    pub line_numbers: Vec<u32>, // Ascending, at most MAX_SEARCH_RESULTS
+    pub total_lines: usize,     // Number of matching lines
+    pub lines_scanned: usize,   // Lines read: not covered by the inverted index, or phrase candidates to check
+    pub truncated: bool,
+    pub strategy: String,
+}
+
+pub struct ReplaceOutput {
+    pub summary: ReplaceSummary,
+    pub changed_line_lengths: Vec<(u32, usize)>, // Ascending by line number
+    pub line_count_preserved: bool,
+}
+
+pub struct SearchQuery {
+    pub pattern: String, // Regex source; plain queries are escaped
+    pub case_sensitive: bool,
+}
+
+impl SearchQuery {
+    pub fn new(query: &str, is_regex: bool, case_sensitive: bool) -> Self {
+        let pattern = if is_regex { query.to_string() } else { regex::escape(query) };
+        SearchQuery { pattern, case_sensitive }
+    }
+
+    pub fn compile(&self) -> Result<Regex, String> {
+        RegexBuilder::new(&self.pattern)
+            .case_insensitive(!self.case_sensitive)
+            .build()
+            .map_err(|e| format!("Invalid search pattern: {}", e))
+    }
+
+    fn parse_hir(&self) -> Result<Hir, String> {
+        ParserBuilder::new()
+            .case_insensitive(!self.case_sensitive)
+            .build()
+            .parse(&self.pattern)
+            .map_err(|e| format!("Invalid search pattern: {}", e))
+    }
+}
+
+pub enum SearchPlan {
+    /// Only these lines (sorted, deduplicated) can contain a match.
+    Candidates { lines: Vec<u32>, strategy: String },
+    /// No index constrains the pattern; every line has to be checked.
+    FullScan,
+}
+
+impl SearchPlan {
+    /// Adds the lines from `indexed_lines` on, which were appended after the search indexes
+    /// were built and are unknown to them.
+    pub fn including_unindexed(self, indexed_lines: usize, total_lines: usize) -> SearchPlan {
+        match self {
+            SearchPlan::Candidates { mut lines, strategy } if indexed_lines < total_lines => {
+                lines.retain(|&line| (line as usize) < indexed_lines);
+                lines.extend(indexed_lines as u32..total_lines as u32);
+                SearchPlan::Candidates { lines, strategy }
+            }
+            plan => plan,
+        }
+    }
+}
+
+pub struct SearchPlanner<'a> {
+    pub inverted_index: Option<&'a PostingsStore>,
+    pub ngram_index: Option<&'a PostingsStore>,
+    pub settings: &'a IndexSettings, // What the indexes were built with
+}
+
+impl<'a> SearchPlanner<'a> {
+    pub fn plan(&self, query: &SearchQuery) -> Result<SearchPlan, String> {
+        let hir = query.parse_hir()?;
+        let mut runs = Vec::new();
+        let mut current = LiteralRun::new();
+        collect_required_runs(&hir, &mut current, &mut runs);
+        flush_run(&mut current, &mut runs);
+
+        let mut posting_lists: Vec<Vec<u32>> = Vec::new();
+        let mut used_ngrams = false;
+        let mut used_terms = false;
+
+        if let Some(ngram_index) = self.ngram_index {
+            let mut ngram_query = ngram_query::compile(&hir, self.settings.ngram_size);
+            if self.settings.case_fold_ngrams {
+                ngram_query = ngram_query.case_folded();
+            }
+            if let Some(lines) = evaluate_ngram_query(&ngram_query, ngram_index)? {
+                posting_lists.push(lines);
+                used_ngrams = true;
+            }
+        }
+
+        // An index of some fields only lacks the tokens of text elsewhere, so it cannot rule lines out.
+        if let Some(inverted_index) = self.inverted_index.filter(|_| self.settings.fields.is_none()) {
+            // Lines that failed to parse as JSON have no terms of their own, so they stay candidates.
+            let unparsed = inverted_index.get(UNPARSED_LINE_TERM.as_bytes())?;
+            for run in &runs {
+                for term in quoted_terms(run) {
+                    let postings = inverted_index.get(term.as_bytes())?;
+                    posting_lists.push(union_sorted(&postings, &unparsed));
+                    used_terms = true;
+                }
+            }
+        }
+
+        if posting_lists.is_empty() {
+            return Ok(SearchPlan::FullScan);
+        }
+
+        // Intersect smallest lists first so the working set shrinks as fast as possible.
+        posting_lists.sort_by_key(|list| list.len());
+        let mut lines = posting_lists[0].clone();
+        for list in &posting_lists[1..] {
+            if lines.is_empty() {
+                break;
+            }
+            lines = intersect_sorted(&lines, list);
+        }
+
+        let strategy = match (used_terms, used_ngrams) {
+            (true, true) => "inverted+ngram",
+            (true, false) => "inverted",
+            _ => "ngram",
+        };
+        Ok(SearchPlan::Candidates { lines, strategy: strategy.to_string() })
+    }
+}
+
+pub struct SearchExecutor<'a> {
+    pub content: &'a FileContent,
+    pub line_offsets: &'a [LineOffset],
+}
+
+impl<'a> SearchExecutor<'a> {
+    pub fn execute(&self, plan: &SearchPlan, regex: &Regex) -> Result<SearchResults, String> {
+        let mut results = SearchResults {
+            matches: Vec::new(),
+            total_matches: 0,
+            lines_scanned: 0,
+            truncated: false,
+            strategy: String::new(),
+        };
+
+        match plan {
+            SearchPlan::Candidates { lines, strategy } => {
+                results.strategy = strategy.clone();
+                for &line_num_u32 in lines {
+                    let line_number = line_num_u32 as usize;
+                    // Skip lines of an index built for a different revision of the file.
+                    let line = match self.line_offsets.get(line_number).and_then(|info| self.content.line(info)) {
+                        Some(line) => line,
+                        None => continue,
+                    };
+                    collect_line_matches(line_number, line, regex, &mut results);
+                }
+            }
+            SearchPlan::FullScan => {
+                results.strategy = "full_scan".to_string();
+                for (line_number, line) in self.content.bytes().split_inclusive(|&b| b == b'\n').enumerate() {
+                    collect_line_matches(line_number, line, regex, &mut results);
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// What Replace All writes in place of each match.
+pub struct Replacement<'a> {
+    pub regex: &'a Regex,
+    pub with: &'a [u8],
+    pub literal: bool, // Insert `with` as-is rather than expanding `$1`/`${name}`
+}
+
+impl<'a> SearchExecutor<'a> {
+    /// Streams the file into `output_path`, rewriting candidate lines the regex matches.
+    /// Untouched byte ranges between candidates are copied through without being parsed.
+    /// Stops with `REPLACE_CANCELLED` once `is_cancelled` returns true, leaving the output partial.
+    pub fn replace_into(
+        &self,
+        plan: &SearchPlan,
+        replacement: &Replacement,
+        output_path: &str,
+        is_cancelled: &dyn Fn() -> bool,
+        on_progress: &dyn Fn(IndexingProgress),
+    ) -> Result<ReplaceOutput, String> {
+        let data = self.content.bytes();
+        let output = File::create(output_path)
+            .map_err(|e| format!("Failed to create {}: {}", output_path, e))?;
+        let mut writer = BufWriter::new(output);
+
+        let mut summary = ReplaceSummary { replacements: 0, lines_changed: 0, lines_scanned: 0, strategy: String::new() };
+        let mut changed_line_lengths = Vec::new();
+        let mut line_count_preserved = true;
+
+        let all_lines: Vec<u32>;
+        let candidates: &[u32] = match plan {
+            SearchPlan::Candidates { lines, strategy } => {
+                summary.strategy = strategy.clone();
+                lines
+            }
+            SearchPlan::FullScan => {
+                summary.strategy = "full_scan".to_string();
+                all_lines = (0..self.line_offsets.len() as u32).collect();
+                &all_lines
+            }
+        };
+
+        let mut position: u64 = 0;
+        for &line_num_u32 in candidates {
+            if is_cancelled() {
+                return Err(REPLACE_CANCELLED.to_string());
+            }
+            let line_info = match self.line_offsets.get(line_num_u32 as usize) {
+                Some(info) => info,
+                None => continue,
+            };
+            let line = self.content.line(line_info)
+                .ok_or_else(|| "File is shorter than its line offset index; reopen it and retry.".to_string())?;
+
+            writer.write_all(&data[position as usize..line_info.offset as usize])
+                .map_err(|e| format!("Failed to copy unchanged lines: {}", e))?;
+            position = line_info.offset + line_info.length as u64;
+            summary.lines_scanned += 1;
+
+            let (content, ending) = split_line_ending(line);
+            let mut line_replacements = 0;
+            let replaced = replacement.regex.replace_all(content, |caps: &Captures| {
+                line_replacements += 1;
+                if replacement.literal {
+                    replacement.with.to_vec()
+                } else {
+                    let mut expanded = Vec::new();
+                    caps.expand(replacement.with, &mut expanded);
+                    expanded
+                }
+            });
+
+            let write_result = match replaced {
+                Cow::Borrowed(_) => writer.write_all(line),
+                Cow::Owned(new_content) => {
+                    summary.replacements += line_replacements;
+                    summary.lines_changed += 1;
+                    if new_content.contains(&b'\n') {
+                        line_count_preserved = false;
+                    }
+                    changed_line_lengths.push((line_num_u32, new_content.len() + ending.len()));
+                    writer.write_all(&new_content).and_then(|_| writer.write_all(ending))
+                }
+            };
+            write_result.map_err(|e| format!("Failed to write {}: {}", output_path, e))?;
+
+            if summary.lines_scanned.is_multiple_of(PROGRESS_INTERVAL_LINES) {
+                on_progress(IndexingProgress { lines_processed: line_num_u32 as usize, bytes_processed: position });
+            }
+        }
+
+        writer.write_all(&data[position as usize..])
+            .map_err(|e| format!("Failed to copy unchanged lines: {}", e))?;
+        let output = writer.into_inner()
+            .map_err(|e| format!("Failed to write {}: {}", output_path, e.error()))?;
+        output.sync_all()
+            .map_err(|e| format!("Failed to flush {}: {}", output_path, e))?;
+        on_progress(IndexingProgress { lines_processed: self.line_offsets.len(), bytes_processed: position });
+
+        Ok(ReplaceOutput { summary, changed_line_lengths, line_count_preserved })
+    }
+}
+
+/// Evaluates structured queries exactly: lines covered by the inverted index are answered
+/// from its postings, the rest (appended since, or all of them while it is being built)
+/// are tokenized the same way the indexer does. Phrases are answered from the positional
+/// index where there is one; otherwise the lines containing all their words are read and
+/// checked. Ranges are looked up in the field value index, or checked on the lines that
+/// have the field's key without one. An inverted index of some fields only is used for
+/// queries that stay within them; others are answered by scanning.
+pub struct QueryEvaluator<'a> {
+    pub inverted_index: Option<&'a PostingsStore>,
+    pub position_index: Option<&'a PostingsStore>,
+    pub value_index: Option<&'a PostingsStore>,
+    pub settings: &'a IndexSettings, // What the indexes were built with
+    pub indexed_lines: usize, // Leading lines covered by the indexes
+    pub content: &'a FileContent,
+    pub line_offsets: &'a [LineOffset],
+}
+
+impl<'a> QueryEvaluator<'a> {
+    pub fn evaluate(&self, query: &Query) -> Result<QueryResults, String> {
+        let mut lines_scanned = 0;
+        let inverted_index = self.inverted_index.filter(|_| self.index_covers(query));
+        let (mut lines, indexed_lines) = match inverted_index {
+            Some(inverted_index) => {
+                let indexed_lines = self.indexed_lines.min(self.line_offsets.len());
+                let mut lines = self.lines_from_index(query, inverted_index, indexed_lines as u32, &mut lines_scanned)?;
+                lines.retain(|&line| (line as usize) < indexed_lines);
+                (lines, indexed_lines)
+            }
+            None => (Vec::new(), 0),
+        };
+
+        let mut lines_tokenized = 0;
+        for (line_number, line_info) in self.line_offsets.iter().enumerate().skip(indexed_lines) {
+            let line = match self.content.line(line_info) {
+                Some(line) => line,
+                None => break,
+            };
+            lines_tokenized += 1;
+            let json_value = parse_json_line(line);
+            let terms = match &json_value {
+                Some(json_value) => token_utils::extract_terms(json_value, None),
+                None => std::iter::once(UNPARSED_LINE_TERM.to_string()).collect(),
+            };
+            if line_matches(query, &terms, json_value.as_ref()) {
+                lines.push(line_number as u32);
+            }
+        }
+        lines_scanned += lines_tokenized;
+
+        let strategy = match (inverted_index.is_some(), lines_tokenized > 0) {
+            (true, true) => "inverted+scan",
+            (true, false) => "inverted",
+            _ => "scan",
+        };
+        let total_lines = lines.len();
+        lines.truncate(MAX_SEARCH_RESULTS);
+        Ok(QueryResults {
+            line_numbers: lines,
+            total_lines,
+            lines_scanned,
+            truncated: total_lines > MAX_SEARCH_RESULTS,
+            strategy: strategy.to_string(),
+        })
+    }
+
+    // Whether the inverted index has every term `query` could look up. With all fields
+    // indexed it does; otherwise terms and phrases must be scoped to an indexed field. Keys
+    // are indexed everywhere, so ranges always qualify, and phrases anywhere do when the
+    // positional index answers them.
+    fn index_covers(&self, query: &Query) -> bool {
+        let fields = match &self.settings.fields {
+            Some(fields) => fields,
+            None => return true,
+        };
+        let field_indexed = |field: &Option<String>| {
+            field.as_ref().is_some_and(|field| token_utils::path_in_fields(&field.to_lowercase(), fields))
+        };
+        match query {
+            Query::Term { field, .. } => field_indexed(field),
+            Query::Phrase { field: None, .. } => self.position_index.is_some(),
+            Query::Phrase { field, .. } => field_indexed(field),
+            Query::Range { .. } => true,
+            Query::And(subs) | Query::Or(subs) => subs.iter().all(|sub| self.index_covers(sub)),
+            Query::Not(inner) => self.index_covers(inner),
+        }
+    }
+
+    // The indexed lines matching the query, by set operations on sorted posting lists.
+    // Negations are taken relative to the first `indexed_lines` lines. Lines read to check
+    // phrases are counted in `lines_read`.
+    fn lines_from_index(&self, query: &Query, inverted_index: &PostingsStore, indexed_lines: u32, lines_read: &mut usize) -> Result<Vec<u32>, String> {
+        match query {
+            Query::Term { field, value } => {
+                let mut lines = Vec::new();
+                for alternative in token_utils::query_terms(field.as_deref(), value) {
+                    let mut lists = Vec::with_capacity(alternative.len());
+                    for term in &alternative {
+                        lists.push(inverted_index.get(term.as_bytes())?);
+                    }
+                    lines = union_sorted(&lines, &intersect_all(lists));
+                }
+                Ok(lines)
+            }
+            Query::Phrase { field, tokens, slop } => {
+                // The positional index answers phrases anywhere in the line exactly.
+                let position_lines = match self.position_index {
+                    Some(position_index) => {
+                        let lines = phrase_lines(position_index, tokens, *slop)?;
+                        if field.is_none() {
+                            return Ok(lines);
+                        }
+                        Some(lines)
+                    }
+                    None => None,
+                };
+                // Otherwise check the lines that have every word (in the field) on the line itself.
+                let mut lists = Vec::with_capacity(tokens.len() + 1);
+                for token in tokens {
+                    lists.push(inverted_index.get(token_utils::scoped_term(field.as_deref(), token).as_bytes())?);
+                }
+                lists.extend(position_lines);
+                let candidates = intersect_all(lists);
+                Ok(self.check_lines(candidates, indexed_lines, lines_read, |json_value| {
+                    token_utils::contains_phrase(json_value, field.as_deref(), tokens, *slop)
+                }))
+            }
+            Query::Range { field, kind, lower, upper } => {
+                if let Some(value_index) = self.value_index {
+                    return value_index.lines_in_range(field_values::column_key(*kind, field).as_bytes(), *lower, *upper);
+                }
+                // Keys are indexed as terms, so only lines with the field's last key can match.
+                let key = field.rsplit('.').next().unwrap_or(field).trim_end_matches("[]").to_lowercase();
+                let candidates = inverted_index.get(key.as_bytes())?;
+                Ok(self.check_lines(candidates, indexed_lines, lines_read, |json_value| {
+                    field_values::field_in_range(json_value, field, *kind, *lower, *upper)
+                }))
+            }
+            Query::And(subs) => {
+                // `a AND NOT b` subtracts b from a rather than intersecting with its complement.
+                let mut included = Vec::with_capacity(subs.len());
+                let mut excluded = Vec::new();
+                for sub in subs {
+                    match sub {
+                        Query::Not(inner) => excluded.push(self.lines_from_index(inner, inverted_index, indexed_lines, lines_read)?),
+                        _ => included.push(self.lines_from_index(sub, inverted_index, indexed_lines, lines_read)?),
+                    }
+                }
+                let mut lines = if included.is_empty() {
+                    complement_sorted(&[], indexed_lines)
+                } else {
+                    intersect_all(included)
+                };
+                for list in &excluded {
+                    if lines.is_empty() {
+                        break;
+                    }
+                    lines = difference_sorted(&lines, list);
+                }
+                Ok(lines)
+            }
+            Query::Or(subs) => {
+                let mut lines = Vec::new();
+                for sub in subs {
+                    lines = union_sorted(&lines, &self.lines_from_index(sub, inverted_index, indexed_lines, lines_read)?);
+                }
+                Ok(lines)
+            }
+            Query::Not(inner) => Ok(complement_sorted(&self.lines_from_index(inner, inverted_index, indexed_lines, lines_read)?, indexed_lines)),
+        }
+    }
+
+    // Keeps the candidate lines below `indexed_lines` whose parsed content passes `check`.
+    fn check_lines(&self, mut candidates: Vec<u32>, indexed_lines: u32, lines_read: &mut usize, check: impl Fn(&serde_json::Value) -> bool) -> Vec<u32> {
+        candidates.retain(|&line| {
+            if line >= indexed_lines {
+                return false;
+            }
+            *lines_read += 1;
+            self.line_offsets.get(line as usize)
+                .and_then(|line_info| self.content.line(line_info))
+                .and_then(parse_json_line)
+                .is_some_and(|json_value| check(&json_value))
+        });
+        candidates
+    }
+}
+
+// The line's content as JSON, or None if it is not valid JSON (or not UTF-8).
+fn parse_json_line(line: &[u8]) -> Option<serde_json::Value> {
+    let line_content = std::str::from_utf8(split_line_ending(line).0).ok()?;
+    serde_json::from_str(line_content).ok()
+}
+
+// The lines where the phrase's tokens occur in order with at most `slop` others in
+// between, walking the tokens' position lists side by side.
+fn phrase_lines(position_index: &PostingsStore, tokens: &[String], slop: u32) -> Result<Vec<u32>, String> {
+    let mut lists: Vec<PositionList> = Vec::with_capacity(tokens.len());
+    for token in tokens {
+        lists.push(position_index.get_positions(token.as_bytes())?);
+    }
+    let (first, rest) = match lists.split_first() {
+        Some(split) => split,
+        None => return Ok(Vec::new()),
+    };
+    let mut cursors = vec![0; rest.len()];
+    let mut lines = Vec::new();
+    'lines: for (line, first_positions) in first {
+        let mut positions: Vec<&[u32]> = vec![first_positions];
+        for (list, cursor) in rest.iter().zip(cursors.iter_mut()) {
+            *cursor += list[*cursor..].partition_point(|(other, _)| other < line);
+            match list.get(*cursor) {
+                Some((other, other_positions)) if other == line => positions.push(other_positions),
+                _ => continue 'lines,
+            }
+        }
+        if token_utils::positions_form_phrase(&positions, slop) {
+            lines.push(*line);
+        }
+    }
+    Ok(lines)
+}
+
+// Whether a line with the indexed `terms` and parsed content satisfies the query.
+fn line_matches(query: &Query, terms: &HashSet<String>, json_value: Option<&serde_json::Value>) -> bool {
+    match query {
+        Query::Term { field, value } => token_utils::query_terms(field.as_deref(), value)
+            .iter()
+            .any(|alternative| alternative.iter().all(|term| terms.contains(term))),
+        Query::Phrase { field, tokens, slop } => json_value
+            .is_some_and(|json_value| token_utils::contains_phrase(json_value, field.as_deref(), tokens, *slop)),
+        Query::Range { field, kind, lower, upper } => json_value
+            .is_some_and(|json_value| field_values::field_in_range(json_value, field, *kind, *lower, *upper)),
+        Query::And(subs) => subs.iter().all(|sub| line_matches(sub, terms, json_value)),
+        Query::Or(subs) => subs.iter().any(|sub| line_matches(sub, terms, json_value)),
+        Query::Not(inner) => !line_matches(inner, terms, json_value),
+    }
+}
+
+// Intersects posting lists, smallest first so the working set shrinks as fast as possible.
+// An empty set of lists yields no lines.
+fn intersect_all(mut lists: Vec<Vec<u32>>) -> Vec<u32> {
+    if lists.is_empty() {
+        return Vec::new();
+    }
+    lists.sort_by_key(|list| list.len());
+    let mut lines = lists.swap_remove(0);
+    for list in &lists {
+        if lines.is_empty() {
+            break;
+        }
+        lines = intersect_sorted(&lines, list);
+    }
+    lines
+}
+
+fn collect_line_matches(line_number: usize, line: &[u8], regex: &Regex, results: &mut SearchResults) {
+    results.lines_scanned += 1;
+    let (content, _) = split_line_ending(line);
+    for m in regex.find_iter(content) {
+        results.total_matches += 1;
+        if results.matches.len() < MAX_SEARCH_RESULTS {
+            results.matches.push(SearchMatch { line_number, start: m.start(), end: m.end() });
+        } else {
+            results.truncated = true;
+        }
+    }
+}
+
+fn flush_run(current: &mut LiteralRun, runs: &mut Vec<LiteralRun>) {
+    if !current.is_empty() {
+        runs.push(std::mem::take(current));
+    }
+}
+
+// Walks the regex and records the literal runs that every match must contain.
+// Anything that can vary (alternations, optional parts, wide classes) ends the current run.
+fn collect_required_runs(hir: &Hir, current: &mut LiteralRun, runs: &mut Vec<LiteralRun>) {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => {} // Zero-width, the run continues
+        HirKind::Literal(literal) => {
+            current.extend(literal.0.iter().map(|&b| vec![b]));
+        }
+        HirKind::Class(class) => match class_as_case_pair(class) {
+            Some(bytes) => current.push(bytes),
+            None => flush_run(current, runs),
+        },
+        HirKind::Capture(capture) => collect_required_runs(&capture.sub, current, runs),
+        HirKind::Concat(subs) => {
+            for sub in subs {
+                collect_required_runs(sub, current, runs);
+            }
+        }
+        HirKind::Repetition(repetition) => {
+            flush_run(current, runs);
+            if repetition.min >= 1 {
+                let mut inner = LiteralRun::new();
+                collect_required_runs(&repetition.sub, &mut inner, runs);
+                flush_run(&mut inner, runs);
+            }
+        }
+        HirKind::Alternation(_) => flush_run(current, runs),
+    }
+}
+
+// Accepts a single ASCII byte or an ASCII upper/lower pair, which is what case-insensitive
+// letters compile to. Letters with non-ASCII folds (e.g. `k`, `s`) produce wider classes.
+fn class_as_case_pair(class: &Class) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    match class {
+        Class::Unicode(unicode) => {
+            for range in unicode.ranges() {
+                for c in range.start()..=range.end() {
+                    if !c.is_ascii() || bytes.len() == 2 {
+                        return None;
+                    }
+                    bytes.push(c as u8);
+                }
+            }
+        }
+        Class::Bytes(byte_class) => {
+            for range in byte_class.ranges() {
+                for b in range.start()..=range.end() {
+                    if !b.is_ascii() || bytes.len() == 2 {
+                        return None;
+                    }
+                    bytes.push(b);
+                }
+            }
+        }
+    }
+    match bytes.as_slice() {
+        [_] => Some(bytes),
+        [a, b] if a.eq_ignore_ascii_case(b) => Some(bytes),
+        _ => None,
+    }
+}
+
+// Extracts `"token"` sequences from a run. In valid JSON such a token is either a whole key
+// or the whole tail of a string value, so its lowercased form is always an indexed term.
+fn quoted_terms(run: &LiteralRun) -> Vec<String> {
+    let mut terms = Vec::new();
+    let mut i = 0;
+    while i < run.len() {
+        if run[i] != [b'"'] {
+            i += 1;
+            continue;
+        }
+        let mut j = i + 1;
+        while j < run.len() && run[j].iter().all(|b| b.is_ascii_alphanumeric()) {
+            j += 1;
+        }
+        if j < run.len() && j > i + 1 && run[j] == [b'"'] {
+            let term: String = run[i + 1..j].iter().map(|choices| choices[0].to_ascii_lowercase() as char).collect();
+            terms.push(term);
+        }
+        i = j;
+    }
+    terms
+}
+
+// Resolves an n-gram query to its candidate lines. `None` means the query does not
+// constrain the search at all.
+fn evaluate_ngram_query(query: &NGramQuery, ngram_index: &PostingsStore) -> Result<Option<Vec<u32>>, String> {
+    match query {
+        NGramQuery::All => Ok(None),
+        NGramQuery::None => Ok(Some(Vec::new())),
+        NGramQuery::NGram(ngram) => ngram_index.get(ngram).map(Some),
+        NGramQuery::And(subs) => {
+            let mut lists = Vec::new();
+            for sub in subs {
+                if let Some(lines) = evaluate_ngram_query(sub, ngram_index)? {
+                    lists.push(lines);
+                }
+            }
+            if lists.is_empty() {
+                return Ok(None);
+            }
+            lists.sort_by_key(|list| list.len());
+            let mut lines = lists.swap_remove(0);
+            for list in &lists {
+                if lines.is_empty() {
+                    break;
+                }
+                lines = intersect_sorted(&lines, list);
+            }
+            Ok(Some(lines))
+        }
+        NGramQuery::Or(subs) => {
+            let mut lines = Vec::new();
+            for sub in subs {
+                match evaluate_ngram_query(sub, ngram_index)? {
+                    Some(sub_lines) => lines = union_sorted(&lines, &sub_lines),
+                    None => return Ok(None),
+                }
+            }
+            Ok(Some(lines))
+        }
+    }
+}
+
+pub fn intersect_sorted(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(std::cmp::min(a.len(), b.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                result.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    result
+}
+
+pub fn union_sorted(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => { result.push(a[i]); i += 1; }
+            std::cmp::Ordering::Greater => { result.push(b[j]); j += 1; }
+            std::cmp::Ordering::Equal => { result.push(a[i]); i += 1; j += 1; }
+        }
+    }
+    result.extend_from_slice(&a[i..]);
+    result.extend_from_slice(&b[j..]);
+    result
+}
+
+pub fn difference_sorted(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut result = Vec::with_capacity(a.len());
+    let mut j = 0;
+    for &line in a {
+        while j < b.len() && b[j] < line {
+            j += 1;
+        }
+        if j == b.len() || b[j] != line {
+            result.push(line);
+        }
+    }
+    result
+}
+
+// Lines in 0..universe that are not in `lines`.
+fn complement_sorted(lines: &[u32], universe: u32) -> Vec<u32> {
+    let mut result = Vec::with_capacity((universe as usize).saturating_sub(lines.len()));
+    let mut next = 0;
+    for &line in lines.iter().take_while(|&&line| line < universe) {
+        result.extend(next..line);
+        next = line + 1;
+    }
+    result.extend(next..universe);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complement_within_universe() {
+        assert_eq!(complement_sorted(&[], 4), vec![0, 1, 2, 3]);
+        assert_eq!(complement_sorted(&[0, 2, 3], 5), vec![1, 4]);
+        assert_eq!(complement_sorted(&[0, 1, 2], 3), Vec::<u32>::new());
+        // Lines past the universe are ignored.
+        assert_eq!(complement_sorted(&[1, 7, 9], 3), vec![0, 2]);
+        assert_eq!(complement_sorted(&[1], 0), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn difference_of_sorted_lists() {
+        assert_eq!(difference_sorted(&[1, 3, 5, 7], &[3, 4, 7]), vec![1, 5]);
+        assert_eq!(difference_sorted(&[1, 2], &[]), vec![1, 2]);
+        assert_eq!(difference_sorted(&[], &[1, 2]), Vec::<u32>::new());
+        assert_eq!(difference_sorted(&[2, 4], &[0, 1, 2, 3, 4, 5]), Vec::<u32>::new());
+    }
+}
@@ -1,24 +1,175 @@
-use std::sync::Mutex;
-use super::indexing_service::{LineOffset, InvertedIndex, NGramIndex}; // Add InvertedIndex, NGramIndex
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::JoinHandle;
+use super::file_content_service::FileContent;
+use super::indexing_service::{IndexSettings, LineOffset};
+use super::postings_store::PostingsStore;
+use super::schema_inference::FileSchemaStats;
+use super::line_repair::InvalidLinesScan;
+use super::schema_validation::ValidationResults;
+
+/// Identifies an open document. Never reused within a session, so a stale ID from the
+/// frontend fails instead of reaching another document.
+pub type DocId = u64;
+
+#[derive(Clone, Copy, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexingStage {
+    Idle,
+    LineOffsets,
+    SearchIndexes, // Inverted, n-gram, positional and field value indexes, built together in one pass
+    Replacing,
+    Repairing, // Writing a repaired copy of the file, see `repair_lines`
+    Saving,
+    Ready,
+    Cancelled,
+    Failed,
+}
+
+/// Payload of the `indexing_status_update` event, also returned by `get_indexing_status`.
+#[derive(Clone, Serialize, Debug)]
+pub struct IndexingStatus {
+    pub doc_id: DocId,
+    pub stage: IndexingStage,
+    pub message: String,
+    pub progress: f32, // Overall progress across all stages, 0.0..=1.0
+    pub bytes_processed: u64,
+    pub total_bytes: u64,
+    pub lines_processed: usize,
+    pub total_lines: usize,
+    pub eta_seconds: Option<f64>, // Remaining time for the current stage
+}
+
+impl IndexingStatus {
+    pub fn new(doc_id: DocId, stage: IndexingStage, message: &str, progress: f32) -> Self {
+        IndexingStatus {
+            doc_id,
+            stage,
+            message: message.to_string(),
+            progress,
+            bytes_processed: 0,
+            total_bytes: 0,
+            lines_processed: 0,
+            total_lines: 0,
+            eta_seconds: None,
+        }
+    }
+}
+
+/// Output of a Replace All that has not been saved yet.
+pub struct PendingReplace {
+    pub source_path: String,
+    pub temp_path: String,
+    pub changed_line_lengths: Vec<(u32, usize)>, // (line number, new length) for rewritten lines
+    pub line_count_preserved: bool, // False if a replacement added or removed line breaks
+}
+
+/// Background thread working on a document: building its search indexes, validating or
+/// scanning its lines, or writing a Replace All or a repaired copy.
+pub struct IndexingJob {
+    pub cancel_flag: Arc<AtomicBool>,
+    pub handle: JoinHandle<()>,
+}
+
+/// Background thread following appends to a document's file, see `follow_file`.
+pub struct FollowJob {
+    pub file_path: String,
+    pub stop_flag: Arc<AtomicBool>,
+    pub handle: JoinHandle<()>,
+}
+
+/// An open file with everything built for it. Each document is indexed, searched and
+/// followed independently of the others.
+pub struct Document {
+    pub id: DocId,
+    pub file_path: Mutex<String>, // Changes when replaced content is saved under another name
+    pub file_content: Mutex<Option<Arc<FileContent>>>,
+    pub line_offset_index: Mutex<Option<Arc<Vec<LineOffset>>>>,
+    pub inverted_index: Mutex<Option<PostingsStore>>,
+    pub ngram_index: Mutex<Option<PostingsStore>>,
+    pub position_index: Mutex<Option<PostingsStore>>, // Only built when `index_settings` ask for it
+    pub index_settings: IndexSettings, // Which search indexes are built, and how
+    pub value_index: Mutex<Option<PostingsStore>>, // Numeric and date columns for range queries
+    pub search_indexed_lines: Mutex<usize>, // Leading lines covered by the search indexes; later lines are scanned
+    pub schema_stats: Mutex<Option<FileSchemaStats>>, // Gathered while indexing; None until the first pass is done
+    pub indexing_status: Mutex<IndexingStatus>,
+    pub pending_replace: Mutex<Option<PendingReplace>>,
+    pub validation: Mutex<Option<ValidationResults>>, // Of the latest `validate_file` run
+    pub invalid_lines: Mutex<Option<InvalidLinesScan>>, // Of the latest `scan_invalid_lines` run
+    pub indexing_job: Mutex<Option<IndexingJob>>,
+    pub validation_job: Mutex<Option<IndexingJob>>,
+    pub invalid_lines_job: Mutex<Option<IndexingJob>>,
+    pub replace_job: Mutex<Option<IndexingJob>>,
+    pub repair_job: Mutex<Option<IndexingJob>>,
+    pub follow_job: Mutex<Option<FollowJob>>,
+}
+
+impl Document {
+    pub fn new(id: DocId, file_path: &str, index_settings: IndexSettings) -> Self {
+        Document {
+            id,
+            file_path: Mutex::new(file_path.to_string()),
+            file_content: Mutex::new(None),
+            line_offset_index: Mutex::new(None),
+            inverted_index: Mutex::new(None),
+            ngram_index: Mutex::new(None),
+            position_index: Mutex::new(None),
+            index_settings,
+            value_index: Mutex::new(None),
+            search_indexed_lines: Mutex::new(0),
+            schema_stats: Mutex::new(None),
+            indexing_status: Mutex::new(IndexingStatus::new(id, IndexingStage::Idle, "Ready", 0.0)),
+            pending_replace: Mutex::new(None),
+            validation: Mutex::new(None),
+            invalid_lines: Mutex::new(None),
+            indexing_job: Mutex::new(None),
+            validation_job: Mutex::new(None),
+            invalid_lines_job: Mutex::new(None),
+            replace_job: Mutex::new(None),
+            repair_job: Mutex::new(None),
+            follow_job: Mutex::new(None),
+        }
+    }
+}
 
 pub struct AppState {
-    pub current_file_path: Mutex<Option<String>>,
-    pub line_offset_index: Mutex<Option<Vec<LineOffset>>>,
-    pub inverted_index: Mutex<Option<InvertedIndex>>, // New
-    pub ngram_index: Mutex<Option<NGramIndex>>,       // New
-    pub indexing_status_message: Mutex<String>,      // New
-    pub indexing_progress: Mutex<f32>,             // New
+    pub documents: Mutex<HashMap<DocId, Arc<Document>>>,
+    next_doc_id: AtomicU64,
 }
 
 impl AppState {
     pub fn new() -> Self {
         AppState {
-            current_file_path: Mutex::new(None),
-            line_offset_index: Mutex::new(None),
-            inverted_index: Mutex::new(None),    // New
-            ngram_index: Mutex::new(None),       // New
-            indexing_status_message: Mutex::new("Ready".to_string()), // New
-            indexing_progress: Mutex::new(0.0), // New
+            documents: Mutex::new(HashMap::new()),
+            next_doc_id: AtomicU64::new(1),
         }
     }
+
+    /// Registers a new, not yet indexed document for `file_path`.
+    pub fn create_document(&self, file_path: &str, index_settings: IndexSettings) -> Result<Arc<Document>, String> {
+        let id = self.next_doc_id.fetch_add(1, Ordering::Relaxed);
+        let document = Arc::new(Document::new(id, file_path, index_settings));
+        self.documents.lock()
+            .map_err(|e| format!("Failed to lock documents: {}", e))?
+            .insert(id, Arc::clone(&document));
+        Ok(document)
+    }
+
+    pub fn document(&self, doc_id: DocId) -> Result<Arc<Document>, String> {
+        self.documents.lock()
+            .map_err(|e| format!("Failed to lock documents: {}", e))?
+            .get(&doc_id)
+            .cloned()
+            .ok_or_else(|| format!("Document {} is not open.", doc_id))
+    }
+
+    /// Unregisters a document. Its memory is freed once running commands and background
+    /// threads let go of it.
+    pub fn remove_document(&self, doc_id: DocId) -> Result<Option<Arc<Document>>, String> {
+        Ok(self.documents.lock()
+            .map_err(|e| format!("Failed to lock documents: {}", e))?
+            .remove(&doc_id))
+    }
 }
@@ -0,0 +1,161 @@
+// Typed values of JSON fields for range queries. Numbers are compared as numbers and
+// strings that read as ISO-8601 dates or date-times as milliseconds since the Unix epoch;
+// each field keeps one column per kind, so `ts > 2024-01-01` never matches a number.
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde_json::Value;
+use std::ops::{Bound, RangeBounds};
+
+use super::token_utils;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValueKind {
+    Number,
+    Timestamp,
+}
+
+impl ValueKind {
+    fn tag(self) -> char {
+        match self {
+            ValueKind::Number => 'n',
+            ValueKind::Timestamp => 't',
+        }
+    }
+}
+
+/// Key of the column holding the `kind` values of the field at `path` (lowercased).
+pub fn column_key(kind: ValueKind, path: &str) -> String {
+    format!("{}{}", kind.tag(), path.to_lowercase())
+}
+
+/// The typed values of a line's fields as (column key, value), for the field value index.
+pub fn field_values(json_value: &Value) -> Vec<(String, f64)> {
+    let mut values = Vec::new();
+    token_utils::for_each_scalar(json_value, &mut |path, value| {
+        if let Some((kind, typed)) = typed_value(value) {
+            if !path.is_empty() {
+                values.push((column_key(kind, path), typed));
+            }
+        }
+    });
+    values
+}
+
+/// Whether the field at `path` has a `kind` value within the bounds.
+pub fn field_in_range(json_value: &Value, path: &str, kind: ValueKind, lower: Bound<f64>, upper: Bound<f64>) -> bool {
+    let path = path.to_lowercase();
+    let mut found = false;
+    token_utils::for_each_scalar(json_value, &mut |value_path, value| {
+        if !found && value_path == path {
+            found = typed_value(value)
+                .is_some_and(|(value_kind, typed)| value_kind == kind && (lower, upper).contains(&typed));
+        }
+    });
+    found
+}
+
+fn typed_value(value: &Value) -> Option<(ValueKind, f64)> {
+    match value {
+        Value::Number(n) => n.as_f64().map(|n| (ValueKind::Number, n)),
+        Value::String(s) => parse_timestamp(s).map(|ms| (ValueKind::Timestamp, ms)),
+        _ => None,
+    }
+}
+
+/// Reads a range bound typed in a query: a number, or else an ISO-8601 date or date-time.
+pub fn parse_query_value(text: &str) -> Option<(ValueKind, f64)> {
+    match text.trim().parse::<f64>() {
+        Ok(n) if n.is_finite() => Some((ValueKind::Number, n)),
+        _ => parse_timestamp(text).map(|ms| (ValueKind::Timestamp, ms)),
+    }
+}
+
+/// Milliseconds since the Unix epoch of an ISO-8601 date (`2024-05-01`) or date-time
+/// (`2024-05-01T12:30:00Z`, with or without seconds, fractional seconds and offset; `Z`,
+/// `+02:00` or `+0200`). Date-times without an offset, and plain dates, are taken as UTC.
+pub fn parse_timestamp(s: &str) -> Option<f64> {
+    let s = s.trim();
+    // Most strings are not dates; reject them before trying the formats.
+    let bytes = s.as_bytes();
+    if bytes.len() < 10 || !bytes[..4].iter().all(u8::is_ascii_digit) || bytes[4] != b'-' {
+        return None;
+    }
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(s) {
+        return Some(date_time.timestamp_millis() as f64);
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f%#z", "%Y-%m-%dT%H:%M%#z"] {
+        if let Ok(date_time) = DateTime::parse_from_str(s, format) {
+            return Some(date_time.timestamp_millis() as f64);
+        }
+    }
+    for format in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%MZ"] {
+        if let Ok(date_time) = NaiveDateTime::parse_from_str(s, format) {
+            return Some(date_time.and_utc().timestamp_millis() as f64);
+        }
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date_time| date_time.and_utc().timestamp_millis() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const MAY_FIRST: f64 = 1_714_521_600_000.0; // 2024-05-01T00:00:00Z
+    const HOUR: f64 = 3_600_000.0;
+
+    #[test]
+    fn timestamps_in_every_documented_form() {
+        let noon = MAY_FIRST + 12.0 * HOUR;
+        let cases = [
+            ("2024-05-01", MAY_FIRST),
+            ("2024-05-01T12:00:00Z", noon),
+            ("2024-05-01T12:00:00.250Z", noon + 250.0),
+            ("2024-05-01T12:00:00+02:00", noon - 2.0 * HOUR),
+            ("2024-05-01T12:00:00+0200", noon - 2.0 * HOUR),
+            ("2024-05-01T12:00:00.5-01:00", noon + HOUR + 500.0),
+            ("2024-05-01T12:00:00", noon),
+            ("2024-05-01 12:00:00.125", noon + 125.0),
+            ("2024-05-01T12:00", noon),
+            ("2024-05-01T12:00Z", noon),
+            ("2024-05-01T12:00+02:00", noon - 2.0 * HOUR),
+            ("2024-05-01T12:00-0530", noon + 5.5 * HOUR),
+            (" 2024-05-01T12:00Z ", noon),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_timestamp(text), Some(expected), "{}", text);
+        }
+    }
+
+    #[test]
+    fn other_strings_are_not_timestamps() {
+        for text in ["", "2024", "2024-13-01", "2024-05-01T25:00Z", "05/01/2024", "2024-05-01Tnoon", "hello world"] {
+            assert_eq!(parse_timestamp(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn query_values_are_numbers_before_dates() {
+        assert_eq!(parse_query_value(" 0.8 "), Some((ValueKind::Number, 0.8)));
+        assert_eq!(parse_query_value("2024-05-01"), Some((ValueKind::Timestamp, MAY_FIRST)));
+        assert_eq!(parse_query_value("inf"), None);
+        assert_eq!(parse_query_value("soon"), None);
+    }
+
+    #[test]
+    fn field_values_keep_numbers_and_timestamps_apart() {
+        let line = json!({"Score": 3, "ts": "2024-05-01", "name": "x", "nested": {"n": 1.5}, "flag": true});
+        let mut values = field_values(&line);
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(values, vec![
+            ("nnested.n".to_string(), 1.5),
+            ("nscore".to_string(), 3.0),
+            ("tts".to_string(), MAY_FIRST),
+        ]);
+        assert!(field_in_range(&line, "SCORE", ValueKind::Number, Bound::Included(3.0), Bound::Unbounded));
+        assert!(!field_in_range(&line, "score", ValueKind::Number, Bound::Excluded(3.0), Bound::Unbounded));
+        assert!(!field_in_range(&line, "ts", ValueKind::Number, Bound::Unbounded, Bound::Unbounded));
+        assert!(field_in_range(&line, "ts", ValueKind::Timestamp, Bound::Unbounded, Bound::Included(MAY_FIRST)));
+    }
+}
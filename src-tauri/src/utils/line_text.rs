@@ -0,0 +1,45 @@
+// Decodes raw lines for display. Files are expected to be UTF-8, but a corrupt record or a
+// stretch of another encoding must not keep the lines around it from being shown.
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LineDecoding {
+    #[default]
+    Lossy,  // UTF-8, invalid bytes shown as U+FFFD
+    Latin1, // UTF-8 if the line is valid, otherwise every byte as its ISO-8859-1 character
+}
+
+/// A line as returned by `get_lines`, `get_lines_at` and `get_line_content`.
+#[derive(Clone, Serialize, Debug)]
+pub struct LineText {
+    pub text: String,
+    pub invalid_utf8: bool, // The line has bytes that are not UTF-8; `text` is not what is on disk
+    pub hex: Option<String>, // Raw bytes as space-separated hex pairs, if requested
+}
+
+pub fn decode_line(bytes: &[u8], decoding: LineDecoding, with_hex: bool) -> LineText {
+    let (text, invalid_utf8) = match std::str::from_utf8(bytes) {
+        Ok(text) => (text.to_string(), false),
+        Err(_) => match decoding {
+            LineDecoding::Lossy => (String::from_utf8_lossy(bytes).into_owned(), true),
+            LineDecoding::Latin1 => (bytes.iter().map(|&b| b as char).collect(), true),
+        },
+    };
+    LineText {
+        text,
+        invalid_utf8,
+        hex: with_hex.then(|| hex_view(bytes)),
+    }
+}
+
+fn hex_view(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 3);
+    for (i, byte) in bytes.iter().enumerate() {
+        if i > 0 {
+            hex.push(' ');
+        }
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    hex
+}
@@ -1,2 +1,6 @@
 pub mod token_utils;
 pub mod ngram_utils;
+pub mod ngram_query;
+pub mod query_parser;
+pub mod field_values;
+pub mod line_text;
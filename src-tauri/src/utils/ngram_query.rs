@@ -0,0 +1,378 @@
+// Compiles a regex into a boolean query over n-grams, following Russ Cox's
+// "Regular Expression Matching with a Trigram Index". Every line the regex can match
+// satisfies the query, so evaluating it against the n-gram index yields a candidate set.
+use regex_syntax::hir::{Class, Hir, HirKind};
+use std::collections::BTreeSet;
+
+const MAX_EXACT_SET: usize = 16; // Above this, exact strings are turned into n-gram clauses
+const MAX_AFFIX_SET: usize = 32;
+const MAX_CLASS_CHARS: usize = 8; // Wider classes are treated like `.`
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NGramQuery {
+    All,  // No constraint, every line is a candidate
+    None, // No line can match
+    NGram(Vec<u8>),
+    And(Vec<NGramQuery>),
+    Or(Vec<NGramQuery>),
+}
+
+impl NGramQuery {
+    pub fn and(self, other: NGramQuery) -> NGramQuery {
+        match (self, other) {
+            (NGramQuery::All, q) | (q, NGramQuery::All) => q,
+            (NGramQuery::None, _) | (_, NGramQuery::None) => NGramQuery::None,
+            (NGramQuery::And(mut a), NGramQuery::And(b)) => {
+                for q in b {
+                    if !a.contains(&q) {
+                        a.push(q);
+                    }
+                }
+                NGramQuery::And(a)
+            }
+            (NGramQuery::And(mut a), q) | (q, NGramQuery::And(mut a)) => {
+                if !a.contains(&q) {
+                    a.push(q);
+                }
+                NGramQuery::And(a)
+            }
+            (a, b) if a == b => a,
+            (a, b) => NGramQuery::And(vec![a, b]),
+        }
+    }
+
+    pub fn or(self, other: NGramQuery) -> NGramQuery {
+        match (self, other) {
+            (NGramQuery::None, q) | (q, NGramQuery::None) => q,
+            (NGramQuery::All, _) | (_, NGramQuery::All) => NGramQuery::All,
+            (NGramQuery::Or(mut a), NGramQuery::Or(b)) => {
+                for q in b {
+                    if !a.contains(&q) {
+                        a.push(q);
+                    }
+                }
+                NGramQuery::Or(a)
+            }
+            (NGramQuery::Or(mut a), q) | (q, NGramQuery::Or(mut a)) => {
+                if !a.contains(&q) {
+                    a.push(q);
+                }
+                NGramQuery::Or(a)
+            }
+            (a, b) if a == b => a,
+            (a, b) => NGramQuery::Or(vec![a, b]),
+        }
+    }
+
+    /// The same query against an index of ASCII-lowercased n-grams.
+    pub fn case_folded(self) -> NGramQuery {
+        match self {
+            NGramQuery::NGram(ngram) => NGramQuery::NGram(ngram.to_ascii_lowercase()),
+            NGramQuery::And(subs) => subs.into_iter().map(NGramQuery::case_folded).fold(NGramQuery::All, NGramQuery::and),
+            NGramQuery::Or(subs) => subs.into_iter().map(NGramQuery::case_folded).fold(NGramQuery::None, NGramQuery::or),
+            query => query,
+        }
+    }
+}
+
+type StringSet = BTreeSet<Vec<u8>>;
+
+// What is known about the strings a sub-expression can match.
+struct RegexInfo {
+    emptyable: bool,
+    exact: Option<StringSet>, // The complete set of matched strings, when small enough
+    prefix: StringSet,        // Every match starts with one of these (used when `exact` is None)
+    suffix: StringSet,        // Every match ends with one of these
+    query: NGramQuery,        // Must hold for any line containing a match
+}
+
+impl RegexInfo {
+    fn exact(set: StringSet) -> Self {
+        RegexInfo {
+            emptyable: set.contains(&Vec::new()),
+            exact: Some(set),
+            prefix: StringSet::new(),
+            suffix: StringSet::new(),
+            query: NGramQuery::All,
+        }
+    }
+
+    fn empty_string() -> Self {
+        RegexInfo::exact(single(Vec::new()))
+    }
+
+    fn any_char() -> Self {
+        RegexInfo {
+            emptyable: false,
+            exact: None,
+            prefix: single(Vec::new()),
+            suffix: single(Vec::new()),
+            query: NGramQuery::All,
+        }
+    }
+
+    fn any_string() -> Self {
+        RegexInfo { emptyable: true, ..RegexInfo::any_char() }
+    }
+
+    // Moves an exact set into the query and the prefix/suffix sets.
+    fn drop_exact(&mut self, n: usize) {
+        if let Some(exact) = self.exact.take() {
+            let query = std::mem::replace(&mut self.query, NGramQuery::All);
+            self.query = query.and(ngrams_of_set(&exact, n));
+            self.prefix = exact.clone();
+            self.suffix = exact;
+        }
+    }
+
+    // Keeps the sets bounded. N-grams of long prefixes/suffixes are folded into the query
+    // first, after which only the last N-1 bytes matter for n-grams spanning a boundary.
+    fn simplify(mut self, n: usize) -> Self {
+        if self.exact.as_ref().is_some_and(|exact| exact.len() > MAX_EXACT_SET) {
+            self.drop_exact(n);
+        }
+        if self.exact.is_none() {
+            let query = std::mem::replace(&mut self.query, NGramQuery::All);
+            self.query = query.and(ngrams_of_set(&self.prefix, n)).and(ngrams_of_set(&self.suffix, n));
+            self.prefix = trim_set(&self.prefix, n, |s, len| s[..len].to_vec());
+            self.suffix = trim_set(&self.suffix, n, |s, len| s[s.len() - len..].to_vec());
+        }
+        self
+    }
+
+    fn into_query(mut self, n: usize) -> NGramQuery {
+        self.drop_exact(n);
+        self.simplify(n).query
+    }
+}
+
+/// Compiles `hir` into a query over an index of `n`-byte n-grams.
+pub fn compile(hir: &Hir, n: usize) -> NGramQuery {
+    analyze(hir, n).into_query(n)
+}
+
+fn analyze(hir: &Hir, n: usize) -> RegexInfo {
+    match hir.kind() {
+        HirKind::Empty | HirKind::Look(_) => RegexInfo::empty_string(),
+        HirKind::Literal(literal) => RegexInfo::exact(single(literal.0.to_vec())),
+        HirKind::Class(class) => match class_strings(class) {
+            Some(set) => RegexInfo::exact(set),
+            None => RegexInfo::any_char(),
+        },
+        HirKind::Capture(capture) => analyze(&capture.sub, n),
+        HirKind::Repetition(repetition) => {
+            let mut info = analyze(&repetition.sub, n);
+            match (repetition.min, repetition.max) {
+                (0, Some(1)) => match info.exact.take() {
+                    Some(mut exact) => {
+                        exact.insert(Vec::new());
+                        RegexInfo::exact(exact)
+                    }
+                    None => RegexInfo::any_string(),
+                },
+                (0, _) => RegexInfo::any_string(),
+                _ => {
+                    // x{n,} behaves like x+: at least one copy with unknown neighbours.
+                    info.drop_exact(n);
+                    info.simplify(n)
+                }
+            }
+        }
+        HirKind::Concat(subs) => subs.iter()
+            .map(|sub| analyze(sub, n))
+            .fold(RegexInfo::empty_string(), |x, y| concat(x, y, n)),
+        HirKind::Alternation(subs) => {
+            let mut infos = subs.iter().map(|sub| analyze(sub, n));
+            let first = infos.next().unwrap_or_else(RegexInfo::empty_string);
+            infos.fold(first, |x, y| alternate(x, y, n))
+        }
+    }
+}
+
+fn concat(mut x: RegexInfo, mut y: RegexInfo, n: usize) -> RegexInfo {
+    let query = std::mem::replace(&mut x.query, NGramQuery::All)
+        .and(std::mem::replace(&mut y.query, NGramQuery::All));
+    let emptyable = x.emptyable && y.emptyable;
+
+    if let (Some(xe), Some(ye)) = (&x.exact, &y.exact) {
+        let exact = cross(xe, ye);
+        return RegexInfo { emptyable, exact: Some(exact), prefix: StringSet::new(), suffix: StringSet::new(), query }.simplify(n);
+    }
+
+    let mut prefix = match &x.exact {
+        Some(xe) => cross(xe, &y.prefix),
+        None => x.prefix.clone(),
+    };
+    if x.emptyable {
+        prefix.extend(y.prefix.iter().cloned());
+    }
+    let mut suffix = match &y.exact {
+        Some(ye) => cross(&x.suffix, ye),
+        None => y.suffix.clone(),
+    };
+    if y.emptyable {
+        suffix.extend(x.suffix.iter().cloned());
+    }
+
+    let mut query = query;
+    if x.exact.is_none() && y.exact.is_none() {
+        // N-grams spanning the boundary between the two halves.
+        query = query.and(ngrams_of_set(&cross(&x.suffix, &y.prefix), n));
+    }
+
+    RegexInfo { emptyable, exact: None, prefix, suffix, query }.simplify(n)
+}
+
+fn alternate(mut x: RegexInfo, mut y: RegexInfo, n: usize) -> RegexInfo {
+    let emptyable = x.emptyable || y.emptyable;
+    if let (Some(xe), Some(ye)) = (&x.exact, &y.exact) {
+        let exact = xe.union(ye).cloned().collect();
+        let query = x.query.or(y.query);
+        return RegexInfo { emptyable, exact: Some(exact), prefix: StringSet::new(), suffix: StringSet::new(), query }.simplify(n);
+    }
+    x.drop_exact(n);
+    y.drop_exact(n);
+    let prefix = x.prefix.union(&y.prefix).cloned().collect();
+    let suffix = x.suffix.union(&y.suffix).cloned().collect();
+    RegexInfo { emptyable, exact: None, prefix, suffix, query: x.query.or(y.query) }.simplify(n)
+}
+
+// The UTF-8 (or raw byte) encodings of every member of a small class.
+fn class_strings(class: &Class) -> Option<StringSet> {
+    let mut set = StringSet::new();
+    match class {
+        Class::Unicode(unicode) => {
+            for range in unicode.ranges() {
+                for c in range.start()..=range.end() {
+                    if set.len() == MAX_CLASS_CHARS {
+                        return None;
+                    }
+                    let mut buf = [0; 4];
+                    set.insert(c.encode_utf8(&mut buf).as_bytes().to_vec());
+                }
+            }
+        }
+        Class::Bytes(bytes) => {
+            for range in bytes.ranges() {
+                for b in range.start()..=range.end() {
+                    if set.len() == MAX_CLASS_CHARS {
+                        return None;
+                    }
+                    set.insert(vec![b]);
+                }
+            }
+        }
+    }
+    Some(set)
+}
+
+fn single(s: Vec<u8>) -> StringSet {
+    let mut set = StringSet::new();
+    set.insert(s);
+    set
+}
+
+fn cross(a: &StringSet, b: &StringSet) -> StringSet {
+    let mut set = StringSet::new();
+    for x in a {
+        for y in b {
+            let mut s = x.clone();
+            s.extend_from_slice(y);
+            set.insert(s);
+        }
+    }
+    set
+}
+
+// Shortens every string to at most N-1 bytes, then further until the set is small enough.
+fn trim_set(set: &StringSet, n: usize, keep: impl Fn(&[u8], usize) -> Vec<u8>) -> StringSet {
+    let mut max_len = n - 1;
+    loop {
+        let trimmed: StringSet = set.iter()
+            .map(|s| if s.len() > max_len { keep(s, max_len) } else { s.clone() })
+            .collect();
+        if trimmed.len() <= MAX_AFFIX_SET || max_len == 0 {
+            return trimmed;
+        }
+        max_len -= 1;
+    }
+}
+
+// A line containing any string of the set contains all n-grams of that string.
+fn ngrams_of_set(set: &StringSet, n: usize) -> NGramQuery {
+    let mut query = NGramQuery::None;
+    for s in set {
+        if s.len() < n {
+            return NGramQuery::All;
+        }
+        let ngrams = s.windows(n)
+            .map(|w| NGramQuery::NGram(w.to_vec()))
+            .fold(NGramQuery::All, NGramQuery::and);
+        query = query.or(ngrams);
+    }
+    query
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex_syntax::ParserBuilder;
+
+    fn compile_pattern(pattern: &str, n: usize) -> NGramQuery {
+        compile(&ParserBuilder::new().build().parse(pattern).unwrap(), n)
+    }
+
+    fn ngrams(text: &str, n: usize) -> NGramQuery {
+        text.as_bytes().windows(n)
+            .map(|w| NGramQuery::NGram(w.to_vec()))
+            .fold(NGramQuery::All, NGramQuery::and)
+    }
+
+    #[test]
+    fn literal_needs_all_its_ngrams() {
+        for n in 2..=8 {
+            assert_eq!(compile_pattern("errorcode", n), ngrams("errorcode", n), "n={}", n);
+        }
+    }
+
+    #[test]
+    fn literal_shorter_than_n_matches_everything() {
+        for n in 2..=8 {
+            let literal = &"abcdefgh"[..n - 1];
+            assert_eq!(compile_pattern(literal, n), NGramQuery::All, "n={}", n);
+        }
+    }
+
+    #[test]
+    fn alternation_becomes_or() {
+        for n in 2..=8 {
+            let query = compile_pattern("timeout_error|connection_refused", n);
+            assert_eq!(query, ngrams("connection_refused", n).or(ngrams("timeout_error", n)), "n={}", n);
+        }
+    }
+
+    #[test]
+    fn wildcards_split_the_literals() {
+        for n in 2..=8 {
+            let query = compile_pattern("request_id.*status_code", n);
+            assert_eq!(query, ngrams("request_id", n).and(ngrams("status_code", n)), "n={}", n);
+        }
+    }
+
+    #[test]
+    fn case_folding_lowercases_ngrams() {
+        for n in 2..=8 {
+            let query = compile_pattern("ErrorCode|WARNING", n).case_folded();
+            assert_eq!(query, ngrams("errorcode", n).or(ngrams("warning", n)), "n={}", n);
+        }
+        // N-grams that coincide once folded are deduplicated.
+        assert_eq!(compile_pattern("ABab", 2).case_folded(), ngrams("abab", 2));
+    }
+
+    #[test]
+    fn impossible_and_unconstrained_patterns() {
+        assert_eq!(compile_pattern(".*", 3), NGramQuery::All);
+        assert_eq!(compile_pattern("a?b?", 3), NGramQuery::All);
+        assert_eq!(compile_pattern("[^\\x00-\\x{10FFFF}]", 3), NGramQuery::None);
+    }
+}
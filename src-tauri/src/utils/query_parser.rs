@@ -0,0 +1,311 @@
+// Parses structured queries over the inverted index:
+//
+//   user                     the token anywhere in the line
+//   role:user                the token in the value of field `role`
+//   messages[].role:user     fields are JSON paths: keys joined by `.`, `[]` for arrays
+//   content:"hello world"    quoted values (and fields) may contain spaces, `:` and parentheses;
+//                            a quoted value of several words is a phrase: the words in order
+//   "hello world"~3          proximity: in order, at most 3 other words in between
+//   score > 0.8              ranges over numbers, or ISO-8601 dates and date-times;
+//   ts <= "2024-05-01T12:00Z"  also `>=` and `<`; values with `:` need quotes
+//   score:1..5               inclusive range, either end may be left open (`score:1..`)
+//   a b, a AND b             both; adjacent terms are implicitly ANDed
+//   a OR b                   either
+//   NOT a                    lines without it
+//   (a OR b) AND NOT c       grouping; NOT binds tightest, then AND, then OR
+//
+// Operators are only recognised in upper case and unquoted, so `and` is an ordinary term.
+use std::ops::Bound;
+
+use super::field_values::{self, ValueKind};
+use super::token_utils::{self, MAX_PHRASE_SLOP, MAX_PHRASE_TOKENS};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Query {
+    Term { field: Option<String>, value: String },
+    Phrase { field: Option<String>, tokens: Vec<String>, slop: u32 }, // Tokens as indexed, at least two
+    Range { field: String, kind: ValueKind, lower: Bound<f64>, upper: Bound<f64> }, // Dates in epoch milliseconds
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word { text: String, quoted: bool },
+    Slop(u32), // `~N` right after a quoted word
+    Compare { greater: bool, inclusive: bool }, // `>`, `>=`, `<` or `<=`
+    Colon,
+    OpenParen,
+    CloseParen,
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word { text, quoted: false } if text == keyword)
+    }
+}
+
+pub fn parse(input: &str) -> Result<Query, String> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Err("Query is empty.".to_string());
+    }
+    let mut parser = Parser { tokens, position: 0 };
+    let query = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(query),
+        Some(Token::CloseParen) => Err("Unbalanced `)` in query.".to_string()),
+        Some(token) => Err(format!("Unexpected {} in query.", describe(token))),
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut chars = input.chars().peekable();
+    let mut tokens = Vec::new();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => { chars.next(); }
+            ':' => { chars.next(); tokens.push(Token::Colon); }
+            '(' => { chars.next(); tokens.push(Token::OpenParen); }
+            ')' => { chars.next(); tokens.push(Token::CloseParen); }
+            '<' | '>' => {
+                chars.next();
+                let inclusive = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Compare { greater: c == '>', inclusive });
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => text.push(escaped),
+                            None => return Err(format!("Unterminated quote in query near \"{}", text)),
+                        },
+                        Some(c) => text.push(c),
+                        None => return Err(format!("Unterminated quote in query near \"{}", text)),
+                    }
+                }
+                tokens.push(Token::Word { text, quoted: true });
+                if chars.next_if_eq(&'~').is_some() {
+                    let mut digits = String::new();
+                    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                        digits.push(digit);
+                    }
+                    let slop = digits.parse::<u32>()
+                        .map_err(|_| "Expected a number of words after `~` in query.".to_string())?;
+                    tokens.push(Token::Slop(slop));
+                }
+            }
+            _ => {
+                let mut text = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace() && !matches!(c, ':' | '(' | ')' | '"' | '<' | '>')) {
+                    text.push(c);
+                }
+                tokens.push(Token::Word { text, quoted: false });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn next_if_keyword(&mut self, keyword: &str) -> bool {
+        let matched = self.peek().is_some_and(|token| token.is_keyword(keyword));
+        if matched {
+            self.position += 1;
+        }
+        matched
+    }
+
+    fn parse_or(&mut self) -> Result<Query, String> {
+        let mut operands = vec![self.parse_and()?];
+        while self.next_if_keyword("OR") {
+            operands.push(self.parse_and()?);
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { Query::Or(operands) })
+    }
+
+    fn parse_and(&mut self) -> Result<Query, String> {
+        let mut operands = vec![self.parse_unary()?];
+        loop {
+            if self.next_if_keyword("AND") {
+                operands.push(self.parse_unary()?);
+                continue;
+            }
+            // Implicit AND: anything that can start an operand.
+            match self.peek() {
+                Some(Token::CloseParen) | None => break,
+                Some(token) if token.is_keyword("OR") => break,
+                Some(_) => operands.push(self.parse_unary()?),
+            }
+        }
+        Ok(if operands.len() == 1 { operands.remove(0) } else { Query::And(operands) })
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, String> {
+        if self.next_if_keyword("NOT") {
+            return Ok(Query::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, String> {
+        match self.next() {
+            Some(Token::OpenParen) => {
+                let query = self.parse_or()?;
+                match self.next() {
+                    Some(Token::CloseParen) => Ok(query),
+                    _ => Err("Missing `)` in query.".to_string()),
+                }
+            }
+            Some(Token::Word { text, quoted }) => {
+                if !quoted && (text == "AND" || text == "OR") {
+                    return Err(format!("Missing term before `{}` in query.", text));
+                }
+                if let Some(&Token::Compare { greater, inclusive }) = self.peek() {
+                    self.position += 1;
+                    let bound = match self.next() {
+                        Some(Token::Word { text: value, .. }) => Some((value, inclusive)),
+                        _ => return Err(format!("Missing value to compare field `{}` with.", text)),
+                    };
+                    return if greater { range_query(text, bound, None) } else { range_query(text, None, bound) };
+                }
+                if self.peek() != Some(&Token::Colon) {
+                    return self.finish_term(None, text, quoted);
+                }
+                self.position += 1;
+                match self.next() {
+                    Some(Token::Word { text: value, quoted }) => self.finish_term(Some(text), value, quoted),
+                    _ => Err(format!("Missing value for field `{}`.", text)),
+                }
+            }
+            Some(token) => Err(format!("Unexpected {} in query.", describe(&token))),
+            None => Err("Query ends where a term was expected.".to_string()),
+        }
+    }
+
+    // Builds the term for a value just read, taking a following `~N`. Quoted values of
+    // several words become phrases; anything else is matched as a plain term.
+    fn finish_term(&mut self, field: Option<String>, value: String, quoted: bool) -> Result<Query, String> {
+        // `field:lo..hi`, as long as the ends read as numbers or dates.
+        if let (false, Some(field), Some((low, high))) = (quoted, &field, value.split_once("..")) {
+            let is_bound = |text: &str| text.is_empty() || field_values::parse_query_value(text).is_some();
+            if !(low.is_empty() && high.is_empty()) && is_bound(low) && is_bound(high) {
+                let bound = |text: &str| (!text.is_empty()).then(|| (text.to_string(), true));
+                return range_query(field.clone(), bound(low), bound(high));
+            }
+        }
+        let slop = match self.peek() {
+            Some(&Token::Slop(slop)) => {
+                self.position += 1;
+                slop
+            }
+            _ => 0,
+        };
+        if slop > MAX_PHRASE_SLOP {
+            return Err(format!("Proximity `~{}` is too large; at most {} is supported.", slop, MAX_PHRASE_SLOP));
+        }
+        let tokens: Vec<String> = token_utils::text_tokens(&value).collect();
+        if !quoted || tokens.len() < 2 {
+            return Ok(Query::Term { field, value });
+        }
+        if tokens.len() > MAX_PHRASE_TOKENS {
+            return Err(format!("Phrase has {} words; at most {} are supported.", tokens.len(), MAX_PHRASE_TOKENS));
+        }
+        Ok(Query::Phrase { field, tokens, slop })
+    }
+}
+
+// Builds a range over `field` from the texts of its bounds, each with whether it is
+// inclusive; None leaves that end open. Both bounds must be of the same kind.
+fn range_query(field: String, lower: Option<(String, bool)>, upper: Option<(String, bool)>) -> Result<Query, String> {
+    let mut kind = None;
+    let mut bound = |bound: Option<(String, bool)>| -> Result<Bound<f64>, String> {
+        let (text, inclusive) = match bound {
+            Some(bound) => bound,
+            None => return Ok(Bound::Unbounded),
+        };
+        let (value_kind, value) = field_values::parse_query_value(&text)
+            .ok_or_else(|| format!("`{}` is neither a number nor an ISO-8601 date.", text))?;
+        if kind.is_some_and(|kind| kind != value_kind) {
+            return Err(format!("Range on `{}` mixes a number and a date.", field));
+        }
+        kind = Some(value_kind);
+        Ok(if inclusive { Bound::Included(value) } else { Bound::Excluded(value) })
+    };
+    let lower = bound(lower)?;
+    let upper = bound(upper)?;
+    Ok(Query::Range { field, kind: kind.unwrap_or(ValueKind::Number), lower, upper })
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Word { text, .. } => format!("`{}`", text),
+        Token::Slop(slop) => format!("`~{}`", slop),
+        Token::Compare { greater, inclusive } => format!("`{}{}`", if *greater { ">" } else { "<" }, if *inclusive { "=" } else { "" }),
+        Token::Colon => "`:`".to_string(),
+        Token::OpenParen => "`(`".to_string(),
+        Token::CloseParen => "`)`".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(value: &str) -> Query {
+        Query::Term { field: None, value: value.to_string() }
+    }
+
+    fn not(query: Query) -> Query {
+        Query::Not(Box::new(query))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parse("a OR b AND c").unwrap(), Query::Or(vec![term("a"), Query::And(vec![term("b"), term("c")])]));
+        assert_eq!(parse("a b OR c").unwrap(), Query::Or(vec![Query::And(vec![term("a"), term("b")]), term("c")]));
+        assert_eq!(parse("(a OR b) c").unwrap(), Query::And(vec![Query::Or(vec![term("a"), term("b")]), term("c")]));
+    }
+
+    #[test]
+    fn not_binds_tightest() {
+        assert_eq!(parse("NOT a b").unwrap(), Query::And(vec![not(term("a")), term("b")]));
+        assert_eq!(parse("a OR NOT b").unwrap(), Query::Or(vec![term("a"), not(term("b"))]));
+        assert_eq!(parse("NOT (a OR b)").unwrap(), not(Query::Or(vec![term("a"), term("b")])));
+        assert_eq!(parse("NOT NOT a").unwrap(), not(not(term("a"))));
+    }
+
+    #[test]
+    fn operators_are_upper_case_and_unquoted() {
+        assert_eq!(parse("a and b").unwrap(), Query::And(vec![term("a"), term("and"), term("b")]));
+        assert_eq!(parse("\"NOT\" a").unwrap(), Query::And(vec![term("NOT"), term("a")]));
+    }
+
+    #[test]
+    fn rejects_dangling_operators() {
+        assert!(parse("NOT").is_err());
+        assert!(parse("a AND").is_err());
+        assert!(parse("OR a").is_err());
+        assert!(parse("(a OR b").is_err());
+        assert!(parse("a)").is_err());
+    }
+}
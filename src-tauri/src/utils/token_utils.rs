@@ -1,46 +1,242 @@
 use serde_json::Value;
+use std::borrow::Cow;
 use std::collections::HashSet;
 
-fn extract_terms_from_value(json_value: &Value, terms: &mut HashSet<String>) {
+/// Term recorded for lines that are not valid JSON, so searches can keep them as candidates.
+/// The leading NUL keeps it out of the way of ordinary tokens.
+pub const UNPARSED_LINE_TERM: &str = "\u{0}unparsed";
+
+/// Starts terms scoped to a JSON path, `<path>=<token>` as in `messages[].role=user`. Keys
+/// are indexed as plain terms and may contain `=`, so the prefix keeps the two kinds apart.
+pub const FIELD_TERM_PREFIX: char = '\u{1}';
+
+/// Bump whenever the terms, positions or values extracted from a line change, so indexes
+/// cached by an earlier build are rebuilt instead of answering queries with stale terms.
+/// 2: lines that are not JSON get UNPARSED_LINE_TERM, which search relies on to keep them.
+/// 3: value tokens are also indexed as field terms, which `field:value` queries take as exact.
+pub const TOKENIZER_VERSION: u32 = 3;
+
+/// Positions of a string value's tokens start this far after the previous value's, so a
+/// phrase within the limits below never matches across two values.
+const VALUE_POSITION_GAP: u32 = 1024;
+pub const MAX_PHRASE_TOKENS: usize = 256;
+pub const MAX_PHRASE_SLOP: u32 = 256;
+
+/// The term recorded for `token` appearing in a value at `path`.
+pub fn field_term(path: &str, token: &str) -> String {
+    format!("{}{}={}", FIELD_TERM_PREFIX, path, token)
+}
+
+/// `token` scoped to `path` (lowercased), or the plain token without one.
+pub fn scoped_term(path: Option<&str>, token: &str) -> String {
+    match path {
+        Some(path) => field_term(&path.to_lowercase(), token),
+        None => token.to_string(),
+    }
+}
+
+/// Splits text into the lowercased tokens indexed for string values.
+pub fn text_tokens(s: &str) -> impl Iterator<Item = String> + '_ {
+    // Simple tokenization: split by whitespace and common punctuation.
+    // More sophisticated tokenization can be added later.
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// How `walk_values` reached a value from the one containing it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PathStep<'a> {
+    Root,
+    Key(&'a str),
+    Element,
+}
+
+/// Calls `f` with the path, the last step of the path and the value for every value in
+/// `json_value`, containers before their contents, in document order. Paths are keys joined
+/// by `.` with `[]` for array elements, the root being the empty path; keys in paths and
+/// steps are lowercased if `lowercase_keys` is set, as they are for field terms.
+pub fn walk_values(json_value: &Value, lowercase_keys: bool, f: &mut impl FnMut(&str, PathStep, &Value)) {
+    f("", PathStep::Root, json_value);
+    walk_children(json_value, &mut String::new(), lowercase_keys, f);
+}
+
+fn walk_children(json_value: &Value, path: &mut String, lowercase_keys: bool, f: &mut impl FnMut(&str, PathStep, &Value)) {
     match json_value {
         Value::Object(map) => {
             for (key, value) in map {
-                terms.insert(key.to_lowercase()); // Index keys
-                extract_terms_from_value(value, terms);
+                let key = if lowercase_keys { Cow::Owned(key.to_lowercase()) } else { Cow::Borrowed(key.as_str()) };
+                let parent_len = path.len();
+                if !path.is_empty() {
+                    path.push('.');
+                }
+                path.push_str(&key);
+                f(path, PathStep::Key(&key), value);
+                walk_children(value, path, lowercase_keys, f);
+                path.truncate(parent_len);
             }
         }
         Value::Array(arr) => {
+            let parent_len = path.len();
+            path.push_str("[]");
             for value in arr {
-                extract_terms_from_value(value, terms);
+                f(path, PathStep::Element, value);
+                walk_children(value, path, lowercase_keys, f);
             }
+            path.truncate(parent_len);
+        }
+        _ => {}
+    }
+}
+
+/// Calls `f` with the lowercased path and value of every string, number, boolean and null
+/// in `json_value`, in document order.
+pub fn for_each_scalar(json_value: &Value, f: &mut impl FnMut(&str, &Value)) {
+    walk_values(json_value, true, &mut |path, _, value| {
+        if !value.is_object() && !value.is_array() {
+            f(path, value);
+        }
+    });
+}
+
+/// The inverted-index terms of a parsed line. Keys are indexed as terms, and every value
+/// token both on its own and scoped to the value's path. With `fields`, only the values at
+/// or below those paths are tokenized; keys are still indexed everywhere.
+pub fn extract_terms(json_value: &Value, fields: Option<&[String]>) -> HashSet<String> {
+    let mut terms = HashSet::new();
+    walk_values(json_value, true, &mut |path, step, value| {
+        if let PathStep::Key(key) = step {
+            terms.insert(key.to_string()); // Index keys
         }
-        Value::String(s) => {
-            // Simple tokenization: split by whitespace and common punctuation.
-            // More sophisticated tokenization can be added later.
-            s.split_whitespace()
-             .flat_map(|word| word.split(|c: char| !c.is_alphanumeric()))
-             .filter(|word| !word.is_empty())
-             .for_each(|term_part| {
-                 terms.insert(term_part.to_lowercase()); // Index string parts
-             });
+        if fields.is_some_and(|fields| !path_in_fields(path, fields)) {
+            return;
         }
-        Value::Number(n) => {
-            terms.insert(n.to_string()); // Index numbers as strings
+        match value {
+            Value::String(s) => {
+                for token in text_tokens(s) {
+                    insert_field_term(path, &token, &mut terms);
+                    terms.insert(token); // Index string parts
+                }
+            }
+            Value::Number(n) => {
+                let token = n.to_string(); // Index numbers as strings
+                insert_field_term(path, &token, &mut terms);
+                terms.insert(token);
+            }
+            Value::Bool(b) => {
+                let token = b.to_string(); // Index booleans as strings ("true", "false")
+                insert_field_term(path, &token, &mut terms);
+                terms.insert(token);
+            }
+            Value::Null => {
+                // Only scoped: `field:null` is precise, a bare "null" term would not be.
+                insert_field_term(path, "null", &mut terms);
+            }
+            Value::Object(_) | Value::Array(_) => {}
         }
-        Value::Bool(b) => {
-            terms.insert(b.to_string()); // Index booleans as strings ("true", "false")
+    });
+    terms
+}
+
+/// Whether `path` (lowercased) is one of `fields` or inside one, e.g. `messages[].role`
+/// is inside `messages`.
+pub fn path_in_fields(path: &str, fields: &[String]) -> bool {
+    fields.iter().any(|field| {
+        path.strip_prefix(field.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.') || rest.starts_with('['))
+    })
+}
+
+fn insert_field_term(path: &str, token: &str, terms: &mut HashSet<String>) {
+    if !path.is_empty() {
+        terms.insert(field_term(path, token));
+    }
+}
+
+/// The tokens of a line's string values with their positions, for the positional index.
+/// Positions count tokens across the line, with a gap between values.
+pub fn token_positions(json_value: &Value) -> Vec<(String, u32)> {
+    let mut positions = Vec::new();
+    let mut next_position: u32 = 0;
+    for_each_scalar(json_value, &mut |_, value| {
+        let s = match value {
+            Value::String(s) => s,
+            _ => return,
+        };
+        for token in text_tokens(s) {
+            positions.push((token, next_position));
+            next_position = next_position.saturating_add(1);
         }
-        Value::Null => {
-            // Optionally index "null" or ignore
+        next_position = next_position.saturating_add(VALUE_POSITION_GAP);
+    });
+    positions
+}
+
+/// Whether one string value (at `path`, if given) contains `tokens` in order, with at most
+/// `slop` other tokens in between them in total.
+pub fn contains_phrase(json_value: &Value, path: Option<&str>, tokens: &[String], slop: u32) -> bool {
+    let path = path.map(str::to_lowercase);
+    let mut found = false;
+    for_each_scalar(json_value, &mut |value_path, value| {
+        let s = match value {
+            Value::String(s) if !found && path.as_deref().is_none_or(|path| path == value_path) => s,
+            _ => return,
+        };
+        let value_tokens: Vec<String> = text_tokens(s).collect();
+        let positions: Vec<Vec<u32>> = tokens.iter()
+            .map(|token| (0..value_tokens.len() as u32).filter(|&i| value_tokens[i as usize] == *token).collect())
+            .collect();
+        let positions: Vec<&[u32]> = positions.iter().map(Vec::as_slice).collect();
+        found = positions_form_phrase(&positions, slop);
+    });
+    found
+}
+
+/// Whether one position can be picked from each list (all sorted) so that they ascend with
+/// at most `slop` unpicked positions in between.
+pub fn positions_form_phrase(positions: &[&[u32]], slop: u32) -> bool {
+    let (first, rest) = match positions.split_first() {
+        Some(split) => split,
+        None => return false,
+    };
+    for &start in first.iter() {
+        // Taking the nearest following position each time gives the tightest match from here.
+        let mut previous = start;
+        for list in rest {
+            match list.get(list.partition_point(|&position| position <= previous)) {
+                Some(&position) => previous = position,
+                None => return false, // Later starts cannot do better
+            }
+        }
+        if previous - start - rest.len() as u32 <= slop {
+            return true;
         }
     }
+    false
 }
 
-pub fn tokenize_json_line(line_content: &str) -> HashSet<String> {
-    let mut terms = HashSet::new();
-    if let Ok(value) = serde_json::from_str::<Value>(line_content) {
-        extract_terms_from_value(&value, &mut terms);
+/// The indexed terms that make a line match `value`, anywhere or (with `path`) in that
+/// field. A line matches if it has all terms of any one of the returned alternatives: the
+/// value's text tokens, or the value itself when it reads as a JSON number, boolean or null.
+pub fn query_terms(path: Option<&str>, value: &str) -> Vec<Vec<String>> {
+    let scoped = |token: &str| scoped_term(path, token);
+
+    let mut alternatives = Vec::new();
+    let tokens: Vec<String> = text_tokens(value).map(|token| scoped(&token)).collect();
+    if !tokens.is_empty() {
+        alternatives.push(tokens);
     }
-    // Else, if not valid JSON, no terms are extracted for this line.
-    terms
+    let literal = match serde_json::from_str::<Value>(value.trim()) {
+        Ok(Value::Number(n)) => Some(n.to_string()),
+        Ok(Value::Bool(b)) => Some(b.to_string()),
+        Ok(Value::Null) if path.is_some() => Some("null".to_string()),
+        _ => None,
+    };
+    if let Some(literal) = literal {
+        let literal = vec![scoped(&literal)];
+        if !alternatives.contains(&literal) {
+            alternatives.push(literal);
+        }
+    }
+    alternatives
 }
@@ -2,6 +2,7 @@
 console.log("Dolphin Editor Main JS Loaded");
 
 const { invoke } = window.__TAURI__.tauri;
+const { listen } = window.__TAURI__.event;
 const { appWindow } = window.__TAURI__.window; // For listening to resize events
 
 // DOM Elements
@@ -13,11 +14,12 @@ const prettyJsonContentPre = document.querySelector('#pretty-json-content pre');
 const statusFilePath = document.getElementById('status-file-path');
 const statusTotalLines = document.getElementById('status-total-lines');
 const statusIndexing = document.getElementById('status-indexing');
+const statusFollow = document.getElementById('status-follow');
 
 
 // --- Global State (Simplified) ---
+let currentDocId = null; // Backend ID of the open document
 let totalLines = 0;
-let indexingStatusInterval = null; // For polling indexing status
 let activeLineIndex = -1;
 let activeLineElement = null; // To style the active line
 let linesCache = {}; // Cache for lines already fetched
@@ -25,6 +27,7 @@ let lineHeight = 0; // To be calculated
 let visibleLinesCount = 0; // Lines visible in viewport
 let currentlyDisplayedLines = []; // Array of DOM nodes
 let currentScrollTop = 0;
+let following = false; // Whether appends to the open file are followed
 const OVERSCAN_COUNT = 10; // Number of lines to render above/below viewport
 
 // --- Utility Functions ---
@@ -51,36 +54,83 @@ function updateVisibleLinesCount() {
 }
 
 // --- Function to Update Indexing Status Display ---
-async function updateIndexingStatusDisplay() {
+function formatEta(seconds) {
+    if (seconds < 60) return `${Math.ceil(seconds)}s`;
+    const minutes = Math.floor(seconds / 60);
+    if (minutes < 60) return `${minutes}m ${Math.ceil(seconds % 60)}s`;
+    return `${Math.floor(minutes / 60)}h ${minutes % 60}m`;
+}
+
+function renderIndexingStatus(status) {
+    if (!status || typeof status.message !== 'string' || typeof status.progress !== 'number') {
+        return;
+    }
+    if (status.stage === 'ready' || status.stage === 'idle') {
+        statusIndexing.textContent = status.message;
+        return;
+    }
+    let text = `${status.message} (${Math.round(status.progress * 100)}%)`;
+    if (status.lines_processed > 0) {
+        text += ` ${status.lines_processed.toLocaleString()} lines`;
+    }
+    if (status.eta_seconds !== null && status.eta_seconds !== undefined) {
+        text += `, ~${formatEta(status.eta_seconds)} left`;
+    }
+    statusIndexing.textContent = text;
+}
+
+// The backend pushes status updates while indexing, replacing or saving.
+listen('indexing_status_update', (event) => {
+    if (event.payload.doc_id === currentDocId) {
+        renderIndexingStatus(event.payload);
+    }
+});
+
+async function setFollowing(enabled) {
     try {
-        const status = await invoke('get_indexing_status');
-        if (status && typeof status.message === 'string' && typeof status.progress === 'number') {
-            const progressPercent = Math.round(status.progress * 100);
-            statusIndexing.textContent = `${status.message} (${progressPercent}%)`;
-
-            // If indexing is complete (progress is 1.0 and message is "Ready" or similar)
-            // or if there was an error message that implies completion.
-            if (status.progress >= 1.0 || status.message.toLowerCase().includes("error") || status.message.toLowerCase() === "ready") {
-                if (indexingStatusInterval) {
-                    clearInterval(indexingStatusInterval);
-                    indexingStatusInterval = null;
-                    // Ensure final status is "Ready" if progress is 1.0 and no error in message
-                    if (status.progress >= 1.0 && !status.message.toLowerCase().includes("error")) {
-                        statusIndexing.textContent = "Ready";
-                    }
-                }
-            }
-        }
+        await invoke('follow_file', { docId: currentDocId, enabled });
+        following = enabled;
     } catch (error) {
-        console.error("Error fetching indexing status:", error);
-        statusIndexing.textContent = "Error fetching status.";
-        if (indexingStatusInterval) {
-            clearInterval(indexingStatusInterval);
-            indexingStatusInterval = null;
-        }
+        console.error("Error toggling follow mode:", error);
+        following = false;
     }
+    statusFollow.textContent = `Follow: ${following ? 'On' : 'Off'}`;
 }
 
+statusFollow.addEventListener('click', () => {
+    if (currentDocId !== null) {
+        setFollowing(!following);
+    }
+});
+
+// Lines appended to a followed file; `reset` means it was truncated or rotated and reopened.
+listen('lines_appended', async (event) => {
+    const { doc_id, total_lines, first_changed_line, reset } = event.payload;
+    if (doc_id !== currentDocId) {
+        return;
+    }
+    const wasAtBottom = rawViewContent.scrollTop + rawViewContent.clientHeight >= rawViewContent.scrollHeight - lineHeight;
+    if (reset) {
+        linesCache = {};
+    } else {
+        // The previous last line may have been incomplete and grown since it was fetched.
+        for (const lineNumber of Object.keys(linesCache)) {
+            if (Number(lineNumber) >= first_changed_line) {
+                delete linesCache[lineNumber];
+            }
+        }
+    }
+    totalLines = total_lines;
+    statusTotalLines.textContent = `Total Lines: ${totalLines}`;
+    if (lineHeight === 0) {
+        return;
+    }
+    await renderVisibleLines();
+    if (wasAtBottom) {
+        rawViewContent.scrollTop = rawViewContent.scrollHeight;
+    }
+});
+
 // --- Function to Update Pretty JSON View ---
 async function updatePrettyJsonView(lineNumber) {
     if (lineNumber < 0 || lineNumber >= totalLines) {
@@ -90,16 +140,22 @@ async function updatePrettyJsonView(lineNumber) {
 
     try {
         statusIndexing.textContent = `Fetching line ${lineNumber + 1} for pretty view...`;
-        const lineContent = await invoke('get_line_content', { lineNumber });
+        const line = await invoke('get_line_content', { docId: currentDocId, lineNumber, hex: true });
         statusIndexing.textContent = 'Parsing JSON...';
         try {
-            const parsedJson = JSON.parse(lineContent);
+            if (line.invalid_utf8) {
+                throw new Error('the line is not valid UTF-8');
+            }
+            const parsedJson = JSON.parse(line.text);
             prettyJsonContentPre.textContent = JSON.stringify(parsedJson, null, 2);
         } catch (e) {
             prettyJsonContentPre.textContent = `Invalid JSON on this line: ${e.message}
 
 Raw content:
-${lineContent}`;
+${line.text}
+
+Bytes:
+${line.hex}`;
         }
     } catch (error) {
         console.error(`Error fetching line ${lineNumber} for pretty view:`, error);
@@ -158,9 +214,9 @@ async function renderVisibleLines() {
         statusIndexing.textContent = 'Fetching lines...';
         try {
              for (const range of fetchRanges) {
-                 const fetchedLines = await invoke('get_lines', { startLine: range.start, count: range.count });
-                 fetchedLines.forEach((lineContent, idx) => {
-                     linesCache[range.start + idx] = lineContent;
+                 const fetchedLines = await invoke('get_lines', { docId: currentDocId, startLine: range.start, count: range.count });
+                 fetchedLines.forEach((line, idx) => {
+                     linesCache[range.start + idx] = line;
                  });
              }
         } catch (error) {
@@ -176,7 +232,11 @@ async function renderVisibleLines() {
 
     for (let i = startIndex; i < endIndex; i++) {
         const lineDiv = document.createElement('div');
-        lineDiv.textContent = linesCache[i] || ''; // Use cached or fetched line
+        const line = linesCache[i]; // Use cached or fetched line
+        lineDiv.textContent = line ? line.text : '';
+        if (line && line.invalid_utf8) {
+            lineDiv.classList.add('invalid-line');
+        }
         lineDiv.style.height = `${lineHeight}px`;
         lineDiv.style.position = 'absolute';
         lineDiv.style.top = `${i * lineHeight}px`;
@@ -295,6 +355,8 @@ async function handleFileOpen(filePath) {
     statusFilePath.textContent = filePath;
     // statusIndexing.textContent = 'Indexing...'; // Replaced by polling logic
     linesCache = {}; // Clear cache for new file
+    following = false; // Opening a file stops following the previous one
+    statusFollow.textContent = 'Follow: Off';
 
     // Clear Pretty JSON View and active line state
     activeLineIndex = -1;
@@ -308,25 +370,21 @@ async function handleFileOpen(filePath) {
        statusCursorPos.textContent = `Ln 0, Col 0`;
     }
 
-    // Start polling for indexing status
-    if (indexingStatusInterval) {
-        clearInterval(indexingStatusInterval); // Clear any existing interval
-    }
     statusIndexing.textContent = 'Opening file... (0%)'; // Initial message
-    indexingStatusInterval = setInterval(updateIndexingStatusDisplay, 500); // Poll every 500ms
-
 
     try {
-        totalLines = await invoke('open_file', { filePath }); // This is the main call
-        statusTotalLines.textContent = `Total Lines: ${totalLines}`;
-        // The polling should handle intermediate statuses.
-        // Explicitly call once more to get final status if indexing was super quick
-        await updateIndexingStatusDisplay(); 
-        // If polling didn't stop it, and status is Ready, stop it.
-        if (statusIndexing.textContent === "Ready" && indexingStatusInterval){
-           clearInterval(indexingStatusInterval);
-           indexingStatusInterval = null;
+        // Returns once line offsets are available; search indexes keep building and report via events.
+        // One document at a time for now; close the previous one to free its indexes.
+        if (currentDocId !== null) {
+            const previousDocId = currentDocId;
+            currentDocId = null;
+            await invoke('close_document', { docId: previousDocId });
         }
+        const opened = await invoke('open_file', { filePath }); // This is the main call
+        currentDocId = opened.doc_id;
+        totalLines = opened.total_lines;
+        statusTotalLines.textContent = `Total Lines: ${totalLines}`;
+        renderIndexingStatus(await invoke('get_indexing_status', { docId: currentDocId }));
 
 
         currentScrollTop = 0;
@@ -349,10 +407,6 @@ async function handleFileOpen(filePath) {
         rawViewContent.innerHTML = '';
         rawViewLineNumbers.innerHTML = '';
         statusIndexing.textContent = `Error opening: ${error}`; // Show error
-        if (indexingStatusInterval) {
-            clearInterval(indexingStatusInterval);
-            indexingStatusInterval = null;
-        }
     }
 }
 